@@ -0,0 +1,104 @@
+#!/usr/bin/env bash
+# Redirects the TCP connections of a network namespace to Privaxy's transparent proxy, to try it
+# out without redirecting the host's own connections.
+#
+# Privaxy must be running with its transparent proxy enabled, listening on 10.200.0.1 or 0.0.0.0
+# and using NAT rather than TPROXY:
+#
+#   [transparent_proxy]
+#   enabled = true
+#   address = "0.0.0.0"
+#   port = 8102
+#
+# Usage, as root:
+#   transparent_netns.sh up               Creates the namespace and the redirection.
+#   transparent_netns.sh test CA_CERT     Checks HTTP, HTTPS and tunneled connections.
+#   transparent_netns.sh down             Removes them.
+#
+# Commands can be run from the namespace with `ip netns exec privaxy-test <command>`. The
+# namespace has no DNS resolution of its own, hosts are resolved by the test on the host side.
+set -euo pipefail
+
+NAMESPACE=privaxy-test
+HOST_INTERFACE=privaxy-host
+CLIENT_INTERFACE=privaxy-client
+HOST_ADDRESS=10.200.0.1
+CLIENT_ADDRESS=10.200.0.2
+PORT=${PRIVAXY_TRANSPARENT_PORT:-8102}
+
+up() {
+    ip netns add "$NAMESPACE"
+    ip link add "$HOST_INTERFACE" type veth peer name "$CLIENT_INTERFACE"
+    ip link set "$CLIENT_INTERFACE" netns "$NAMESPACE"
+
+    ip addr add "$HOST_ADDRESS/24" dev "$HOST_INTERFACE"
+    ip link set "$HOST_INTERFACE" up
+
+    ip netns exec "$NAMESPACE" ip addr add "$CLIENT_ADDRESS/24" dev "$CLIENT_INTERFACE"
+    ip netns exec "$NAMESPACE" ip link set "$CLIENT_INTERFACE" up
+    ip netns exec "$NAMESPACE" ip link set lo up
+    ip netns exec "$NAMESPACE" ip route add default via "$HOST_ADDRESS"
+
+    # Every TCP connection leaving the namespace ends up at the transparent proxy, which connects
+    # to the original destination from the host.
+    iptables -t nat -A PREROUTING -i "$HOST_INTERFACE" -p tcp -j REDIRECT --to-ports "$PORT"
+}
+
+down() {
+    iptables -t nat -D PREROUTING -i "$HOST_INTERFACE" -p tcp -j REDIRECT --to-ports "$PORT" || true
+    ip link del "$HOST_INTERFACE" 2>/dev/null || true
+    ip netns del "$NAMESPACE" 2>/dev/null || true
+}
+
+resolve() {
+    getent ahostsv4 "$1" | awk 'NR == 1 { print $1 }'
+}
+
+check() {
+    local description=$1
+    shift
+
+    if "$@"; then
+        echo "ok: $description"
+    else
+        echo "failed: $description"
+        failures=$((failures + 1))
+    fi
+}
+
+run_test() {
+    local ca_certificate=${1:?usage: transparent_netns.sh test CA_CERT}
+    local example_address github_address
+    failures=0
+
+    example_address=$(resolve example.com)
+    github_address=$(resolve github.com)
+
+    check "plain HTTP is served" \
+        ip netns exec "$NAMESPACE" curl --silent --fail --output /dev/null --max-time 10 \
+        --resolve "example.com:80:$example_address" http://example.com/
+
+    # Only succeeds when the certificate is issued by Privaxy's authority for the ClientHello's
+    # server name.
+    check "TLS connections are intercepted" \
+        ip netns exec "$NAMESPACE" curl --silent --fail --output /dev/null --max-time 10 \
+        --cacert "$ca_certificate" --resolve "example.com:443:$example_address" \
+        https://example.com/
+
+    # SSH servers speak first, their banner must arrive without the client sending anything.
+    check "other connections are tunneled" \
+        ip netns exec "$NAMESPACE" timeout 5 bash -c \
+        "exec 3<>/dev/tcp/$github_address/22 && head -c 7 <&3 | grep -q SSH-2.0"
+
+    return "$failures"
+}
+
+case "${1:-}" in
+up) up ;;
+down) down ;;
+test) run_test "${2:-}" ;;
+*)
+    echo "usage: transparent_netns.sh up|test CA_CERT|down" >&2
+    exit 1
+    ;;
+esac
@@ -0,0 +1,118 @@
+(function () {
+    "use strict";
+
+    var endpoint = "#{endpoint}#";
+    var token = "#{token}#";
+
+    var seenIds = new Set();
+    var seenClasses = new Set();
+    var pendingIds = new Set();
+    var pendingClasses = new Set();
+    var flushTimeout = null;
+
+    function collect(element, isPending) {
+        if (element.id && !seenIds.has(element.id)) {
+            seenIds.add(element.id);
+
+            if (isPending) {
+                pendingIds.add(element.id);
+            }
+        }
+
+        if (element.classList) {
+            element.classList.forEach(function (className) {
+                if (!seenClasses.has(className)) {
+                    seenClasses.add(className);
+
+                    if (isPending) {
+                        pendingClasses.add(className);
+                    }
+                }
+            });
+        }
+    }
+
+    function collectTree(root, isPending) {
+        if (root.nodeType !== Node.ELEMENT_NODE) {
+            return;
+        }
+
+        collect(root, isPending);
+
+        var elements = root.querySelectorAll("[id], [class]");
+        for (var i = 0; i < elements.length; i++) {
+            collect(elements[i], isPending);
+        }
+    }
+
+    function hide(hiddenSelectors) {
+        if (hiddenSelectors.length === 0) {
+            return;
+        }
+
+        var style = document.createElement("style");
+        // One rule per selector, a single malformed selector would otherwise break hiding.
+        style.textContent = hiddenSelectors
+            .map(function (selector) {
+                return selector + " { display: none !important; }";
+            })
+            .join("\n");
+
+        (document.head || document.documentElement).appendChild(style);
+    }
+
+    function flush() {
+        flushTimeout = null;
+
+        if (pendingIds.size === 0 && pendingClasses.size === 0) {
+            return;
+        }
+
+        var body = JSON.stringify({
+            token: token,
+            ids: Array.from(pendingIds),
+            classes: Array.from(pendingClasses),
+        });
+
+        pendingIds.clear();
+        pendingClasses.clear();
+
+        fetch(endpoint, {
+            method: "POST",
+            headers: { "Content-Type": "application/json" },
+            credentials: "omit",
+            body: body,
+        })
+            .then(function (response) {
+                return response.ok ? response.json() : { hidden_selectors: [] };
+            })
+            .then(function (response) {
+                hide(response.hidden_selectors);
+            })
+            .catch(function () {});
+    }
+
+    // Classes and ids present in the initial document have already been handled by the proxy.
+    collectTree(document.documentElement, false);
+
+    new MutationObserver(function (mutations) {
+        mutations.forEach(function (mutation) {
+            if (mutation.type === "attributes") {
+                collect(mutation.target, true);
+            } else {
+                mutation.addedNodes.forEach(function (node) {
+                    collectTree(node, true);
+                });
+            }
+        });
+
+        if (flushTimeout === null) {
+            flushTimeout = setTimeout(flush, 100);
+        }
+    }).observe(document.documentElement, {
+        childList: true,
+        subtree: true,
+        attributes: true,
+        attributeFilter: ["id", "class"],
+    });
+})();
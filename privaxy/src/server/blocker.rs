@@ -1,6 +1,9 @@
 use crate::blocker_utils::{
     build_resource_from_file_contents, read_redirectable_resource_mapping, read_template_resources,
 };
+use crate::domain_filters::DomainFilterSet;
+use crate::html_filters::{self, HtmlFilter, HtmlFilterSet};
+use crate::response_rewrite_rules::{ResponseRewrite, ResponseRewriteRule, ResponseRewriteRuleSet};
 use adblock::blocker::BlockerResult as AdblockerBlockerResult;
 use adblock::engine::Engine;
 use adblock::lists::FilterSet;
@@ -40,17 +43,33 @@ pub struct NetworkUrl {
     referer: String,
 }
 
+#[derive(Debug)]
+pub struct ResponseRewritesRequest {
+    url: String,
+    content_type: String,
+}
+
 #[derive(Debug)]
 pub enum RequestKind {
     Url(NetworkUrl),
     Cosmetic(CosmeticRequest),
+    HtmlFilters(String),
+    InjectsScript(String),
+    ResponseRewrites(ResponseRewritesRequest),
+    Domain(String),
+    Connection(String),
     ReplaceEngine(Vec<String>),
+    ReplaceResponseRewriteRules(Vec<ResponseRewriteRule>),
 }
 
 #[derive(Debug)]
 pub enum BlockerResult {
     Network(adblock::blocker::BlockerResult),
     Cosmetic(CosmeticBlockerResult),
+    HtmlFilters(Vec<HtmlFilter>),
+    InjectsScript(bool),
+    ResponseRewrites(Vec<ResponseRewrite>),
+    Domain(bool),
 }
 
 #[derive(Debug)]
@@ -69,6 +88,9 @@ pub struct Blocker {
     pub sender: Sender<BlockerRequest>,
     receiver: Receiver<BlockerRequest>,
     engine: Engine,
+    html_filter_set: HtmlFilterSet,
+    response_rewrite_rule_set: ResponseRewriteRuleSet,
+    domain_filter_set: DomainFilterSet,
     blocking_disabled: BlockingDisabledStore,
 }
 
@@ -110,6 +132,9 @@ impl Blocker {
             sender,
             receiver,
             engine: Engine::new(true),
+            html_filter_set: HtmlFilterSet::default(),
+            response_rewrite_rule_set: ResponseRewriteRuleSet::default(),
+            domain_filter_set: DomainFilterSet::default(),
             blocking_disabled,
         }
     }
@@ -190,12 +215,100 @@ impl Blocker {
                         .respond_to
                         .send(BlockerResult::Network(blocker_result));
                 }
+                RequestKind::HtmlFilters(url) => {
+                    let html_filters = if self.blocking_disabled.is_enabled() {
+                        match url::Url::parse(&url) {
+                            Ok(url) => match url.host_str() {
+                                Some(host) => self.html_filter_set.filters_for_hostname(host),
+                                None => Vec::new(),
+                            },
+                            Err(_err) => Vec::new(),
+                        }
+                    } else {
+                        Vec::new()
+                    };
+
+                    let _result = request
+                        .respond_to
+                        .send(BlockerResult::HtmlFilters(html_filters));
+                }
+                RequestKind::InjectsScript(url) => {
+                    let injects_script = self.blocking_disabled.is_enabled()
+                        && !self
+                            .engine
+                            .url_cosmetic_resources(&url)
+                            .injected_script
+                            .is_empty();
+
+                    let _result = request
+                        .respond_to
+                        .send(BlockerResult::InjectsScript(injects_script));
+                }
+                RequestKind::ResponseRewrites(response_rewrites_request) => {
+                    let response_rewrites = if self.blocking_disabled.is_enabled() {
+                        match url::Url::parse(&response_rewrites_request.url) {
+                            Ok(url) => self
+                                .response_rewrite_rule_set
+                                .rewrites_for(&url, &response_rewrites_request.content_type),
+                            Err(_err) => Vec::new(),
+                        }
+                    } else {
+                        Vec::new()
+                    };
+
+                    let _result = request
+                        .respond_to
+                        .send(BlockerResult::ResponseRewrites(response_rewrites));
+                }
+                RequestKind::Domain(domain) => {
+                    let is_domain_blocked = self.blocking_disabled.is_enabled()
+                        && self.domain_filter_set.is_blocked(&domain);
+
+                    let _result = request
+                        .respond_to
+                        .send(BlockerResult::Domain(is_domain_blocked));
+                }
+                RequestKind::Connection(host) => {
+                    // Refused only when the host's own pages are blocked, and when no filter lets
+                    // any of its requests through.
+                    let is_connection_blocked = self.blocking_disabled.is_enabled()
+                        && !self.domain_filter_set.has_exception(&host)
+                        && {
+                            let url = format!("https://{}/", host);
+                            let blocker_result =
+                                self.engine.check_network_urls(&url, &url, "document");
+
+                            blocker_result.matched && blocker_result.exception.is_none()
+                        };
+
+                    let _result = request
+                        .respond_to
+                        .send(BlockerResult::Domain(is_connection_blocked));
+                }
+                RequestKind::ReplaceResponseRewriteRules(rules) => {
+                    self.response_rewrite_rule_set
+                        .replace_configured_rules(&rules);
+                }
                 RequestKind::ReplaceEngine(filters) => {
                     log::debug!("Configuring blocking engine.");
 
+                    // `adblock-rust` doesn't support html filters nor `$replace` filters,
+                    // we handle them ourselves.
+                    self.html_filter_set = HtmlFilterSet::new(&filters);
+                    self.domain_filter_set = DomainFilterSet::new(&filters);
+                    self.response_rewrite_rule_set
+                        .replace_filter_rules(&filters);
+
                     let mut filter_set = FilterSet::new(true);
 
                     for filter in filters {
+                        // Otherwise, `adblock-rust` would mistake html filters for cosmetic ones.
+                        let filter = filter
+                            .lines()
+                            .filter(|line| !html_filters::is_html_filter(line))
+                            .collect::<Vec<_>>()
+                            .join("\n");
+
                         filter_set
                             .add_filter_list(&filter, adblock::lists::ParseOptions::default());
                     }
@@ -233,6 +346,17 @@ impl AdblockRequester {
             .unwrap();
     }
 
+    pub(crate) async fn replace_response_rewrite_rules(&self, rules: Vec<ResponseRewriteRule>) {
+        let (sender, _receiver) = oneshot::channel();
+
+        self.adblock_request_channel
+            .send(BlockerRequest {
+                respond_to: sender,
+                kind: RequestKind::ReplaceResponseRewriteRules(rules),
+            })
+            .unwrap();
+    }
+
     pub(crate) async fn get_cosmetic_response(
         &self,
         url: String,
@@ -251,7 +375,112 @@ impl AdblockRequester {
         match receiver.await {
             Ok(blocker_result) => match blocker_result {
                 crate::blocker::BlockerResult::Cosmetic(blocker_result) => blocker_result,
-                crate::blocker::BlockerResult::Network(_) => unreachable!(),
+                _ => unreachable!(),
+            },
+            Err(_err) => unreachable!(),
+        }
+    }
+
+    pub(crate) async fn get_html_filters(&self, url: String) -> Vec<HtmlFilter> {
+        let (sender, receiver) = oneshot::channel();
+
+        self.adblock_request_channel
+            .send(BlockerRequest {
+                respond_to: sender,
+                kind: RequestKind::HtmlFilters(url),
+            })
+            .unwrap();
+
+        match receiver.await {
+            Ok(blocker_result) => match blocker_result {
+                crate::blocker::BlockerResult::HtmlFilters(html_filters) => html_filters,
+                _ => unreachable!(),
+            },
+            Err(_err) => unreachable!(),
+        }
+    }
+
+    /// Returns whether scriptlets are to be injected into the document at `url`.
+    pub(crate) async fn injects_script(&self, url: String) -> bool {
+        let (sender, receiver) = oneshot::channel();
+
+        self.adblock_request_channel
+            .send(BlockerRequest {
+                respond_to: sender,
+                kind: RequestKind::InjectsScript(url),
+            })
+            .unwrap();
+
+        match receiver.await {
+            Ok(blocker_result) => match blocker_result {
+                crate::blocker::BlockerResult::InjectsScript(injects_script) => injects_script,
+                _ => unreachable!(),
+            },
+            Err(_err) => unreachable!(),
+        }
+    }
+
+    pub(crate) async fn get_response_rewrites(
+        &self,
+        url: String,
+        content_type: String,
+    ) -> Vec<ResponseRewrite> {
+        let (sender, receiver) = oneshot::channel();
+
+        self.adblock_request_channel
+            .send(BlockerRequest {
+                respond_to: sender,
+                kind: RequestKind::ResponseRewrites(ResponseRewritesRequest { url, content_type }),
+            })
+            .unwrap();
+
+        match receiver.await {
+            Ok(blocker_result) => match blocker_result {
+                crate::blocker::BlockerResult::ResponseRewrites(response_rewrites) => {
+                    response_rewrites
+                }
+                _ => unreachable!(),
+            },
+            Err(_err) => unreachable!(),
+        }
+    }
+
+    pub(crate) async fn is_domain_blocked(&self, domain: String) -> bool {
+        let (sender, receiver) = oneshot::channel();
+
+        self.adblock_request_channel
+            .send(BlockerRequest {
+                respond_to: sender,
+                kind: RequestKind::Domain(domain),
+            })
+            .unwrap();
+
+        match receiver.await {
+            Ok(blocker_result) => match blocker_result {
+                crate::blocker::BlockerResult::Domain(is_domain_blocked) => is_domain_blocked,
+                _ => unreachable!(),
+            },
+            Err(_err) => unreachable!(),
+        }
+    }
+
+    /// Whether tunnels towards `host` are to be refused as a whole.
+    pub(crate) async fn is_connection_blocked(&self, host: String) -> bool {
+        let (sender, receiver) = oneshot::channel();
+
+        self.adblock_request_channel
+            .send(BlockerRequest {
+                respond_to: sender,
+                kind: RequestKind::Connection(host),
+            })
+            .unwrap();
+
+        match receiver.await {
+            Ok(blocker_result) => match blocker_result {
+                crate::blocker::BlockerResult::Domain(is_connection_blocked) => {
+                    is_connection_blocked
+                }
+                _ => unreachable!(),
             },
             Err(_err) => unreachable!(),
         }
@@ -279,9 +508,96 @@ impl AdblockRequester {
                 crate::blocker::BlockerResult::Network(blocker_result) => {
                     (blocker_result.matched, blocker_result)
                 }
-                crate::blocker::BlockerResult::Cosmetic(_) => unreachable!(),
+                _ => unreachable!(),
             },
             Err(_err) => unreachable!(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn adblock_requester(filters: &[&str]) -> AdblockRequester {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let blocker = Blocker::new(
+            sender.clone(),
+            receiver,
+            BlockingDisabledStore(Arc::new(RwLock::new(false))),
+        );
+
+        std::thread::spawn(move || blocker.handle_requests());
+
+        let adblock_requester = AdblockRequester::new(sender);
+        let filters = Vec::from_iter(filters.iter().map(|filter| filter.to_string()));
+
+        adblock_requester.replace_engine(filters).await;
+
+        adblock_requester
+    }
+
+    #[tokio::test]
+    async fn test_connection_to_blocked_host_is_refused() {
+        let adblock_requester = adblock_requester(&["||ads.example.com^"]).await;
+
+        assert!(
+            adblock_requester
+                .is_connection_blocked("ads.example.com".to_string())
+                .await
+        );
+        assert!(
+            adblock_requester
+                .is_connection_blocked("cdn.ads.example.com".to_string())
+                .await
+        );
+        assert!(
+            !adblock_requester
+                .is_connection_blocked("example.com".to_string())
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connection_to_excepted_subdomain_is_allowed() {
+        let adblock_requester = adblock_requester(&[
+            "||example.com^",
+            "@@||cdn.example.com/library.js$domain=site.com",
+        ])
+        .await;
+
+        assert!(
+            adblock_requester
+                .is_connection_blocked("www.example.com".to_string())
+                .await
+        );
+        assert!(
+            !adblock_requester
+                .is_connection_blocked("cdn.example.com".to_string())
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connection_to_badfiltered_host_is_allowed() {
+        let adblock_requester =
+            adblock_requester(&["||tracker.com^", "||tracker.com^$badfilter"]).await;
+
+        assert!(
+            !adblock_requester
+                .is_connection_blocked("tracker.com".to_string())
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connection_blocked_for_some_requests_only_is_allowed() {
+        let adblock_requester = adblock_requester(&["||cdn.com^$third-party"]).await;
+
+        assert!(
+            !adblock_requester
+                .is_connection_blocked("cdn.com".to_string())
+                .await
+        );
+    }
+}
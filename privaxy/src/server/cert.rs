@@ -1,28 +1,118 @@
+use crate::{
+    cert_store::CertStore,
+    configuration::CertificatesConfiguration,
+    leaf_keys::LeafKeys,
+    trust_store::{format_ip_address, TrustStore},
+    upstream_proxies::UpstreamProxies,
+};
 use http::uri::Authority;
 use openssl::{
     asn1::Asn1Time,
     bn::{BigNum, MsbOption},
     hash::MessageDigest,
-    pkey::{PKey, PKeyRef, Private},
-    rsa::Rsa,
+    pkey::{Id, PKey, PKeyRef, Private},
     x509::{
         extension::{
             AuthorityKeyIdentifier, BasicConstraints, KeyUsage, SubjectAlternativeName,
             SubjectKeyIdentifier,
         },
-        X509NameBuilder, X509Ref, X509Req, X509ReqBuilder, X509,
+        X509Name, X509NameBuilder, X509Ref, X509Req, X509ReqBuilder, X509,
     },
 };
-use rustls::{Certificate, PrivateKey, ServerConfig};
-use std::{str::FromStr, sync::Arc};
-use tokio::sync::Mutex;
+use rustls::{Certificate, ClientConfig, PrivateKey, ServerConfig, ServerName};
+use std::{collections::HashMap, io, str::FromStr, sync::Arc, time::Duration};
+use tokio::sync::{Mutex, OnceCell};
+use tokio_rustls::TlsConnector;
 use uluru::LRUCache;
 
 const MAX_CACHED_CERTIFICATES: usize = 1_000;
 
+const UPSTREAM_CERTIFICATE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Subject fields which only matter to extended validation certificates, which certificates issued
+// by Privaxy can't be.
+const EXTENDED_VALIDATION_FIELDS: [&str; 5] = [
+    "serialNumber",
+    "businessCategory",
+    "jurisdictionC",
+    "jurisdictionST",
+    "jurisdictionL",
+];
+
+/// Retrieves the certificates of upstream servers, for minted certificates to mirror them.
+#[derive(Clone)]
+pub struct UpstreamCertificateFetcher {
+    upstream_proxies: UpstreamProxies,
+    tls_configuration: Arc<ClientConfig>,
+}
+
+impl UpstreamCertificateFetcher {
+    pub fn new(upstream_proxies: UpstreamProxies, trust_store: &TrustStore) -> Self {
+        Self {
+            upstream_proxies,
+            tls_configuration: Arc::new(trust_store.client_configuration()),
+        }
+    }
+
+    async fn fetch_certificate(&self, authority: &Authority) -> io::Result<Option<X509>> {
+        let host = authority
+            .host()
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+
+        let server_name = ServerName::try_from(host)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+        let stream = self
+            .upstream_proxies
+            .connect(host, authority.port_u16().unwrap_or(443))
+            .await?;
+
+        let tls_stream = TlsConnector::from(self.tls_configuration.clone())
+            .connect(server_name, stream)
+            .await?;
+
+        let certificate = tls_stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certificates| certificates.first())
+            .and_then(|certificate| X509::from_der(&certificate.0).ok());
+
+        Ok(certificate)
+    }
+
+    /// Returns the certificate presented by the server behind `authority`, provided it could be
+    /// verified.
+    async fn get(&self, authority: &Authority) -> Option<X509> {
+        match tokio::time::timeout(
+            UPSTREAM_CERTIFICATE_TIMEOUT,
+            self.fetch_certificate(authority),
+        )
+        .await
+        {
+            Ok(Ok(certificate)) => certificate,
+            Ok(Err(err)) => {
+                log::debug!(
+                    "Unable to retrieve upstream certificate of {}: {}",
+                    authority,
+                    err
+                );
+                None
+            }
+            Err(_elapsed) => {
+                log::debug!("Timed out retrieving upstream certificate of {}", authority);
+                None
+            }
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct SignedWithCaCert {
     authority: Authority,
+    certificate: X509,
+    private_key: PKey<Private>,
     pub server_configuration: ServerConfig,
 }
 
@@ -32,16 +122,31 @@ impl SignedWithCaCert {
         private_key: PKey<Private>,
         ca_certificate: X509,
         ca_private_key: PKey<Private>,
+        upstream_certificate: Option<X509>,
     ) -> Self {
-        let x509 =
-            Self::build_ca_signed_cert(&ca_certificate, &ca_private_key, &authority, &private_key);
+        let x509 = Self::build_ca_signed_cert(
+            &ca_certificate,
+            &ca_private_key,
+            &authority,
+            &private_key,
+            upstream_certificate.as_deref(),
+        );
+
+        Self::from_certificate(authority, x509, private_key, &ca_certificate)
+    }
 
+    fn from_certificate(
+        authority: Authority,
+        certificate: X509,
+        private_key: PKey<Private>,
+        ca_certificate: &X509Ref,
+    ) -> Self {
         let certs = vec![
-            Certificate(x509.to_der().unwrap()),
+            Certificate(certificate.to_der().unwrap()),
             Certificate(ca_certificate.to_der().unwrap()),
         ];
 
-        let server_configuration = ServerConfig::builder()
+        let mut server_configuration = ServerConfig::builder()
             .with_safe_default_cipher_suites()
             .with_safe_default_kx_groups()
             .with_safe_default_protocol_versions()
@@ -50,16 +155,60 @@ impl SignedWithCaCert {
             .with_single_cert(certs, PrivateKey(private_key.private_key_to_der().unwrap()))
             .unwrap();
 
+        // Clients supporting it get to talk HTTP/2 to us, regardless of what the upstream server supports.
+        server_configuration.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
         Self {
             authority,
+            certificate,
+            private_key,
             server_configuration,
         }
     }
 
-    fn build_certificate_request(key_pair: &PKey<Private>, authority: &Authority) -> X509Req {
+    fn build_mirrored_subject_name(upstream_certificate: &X509Ref) -> Option<X509Name> {
+        let mut x509_name = X509NameBuilder::new().unwrap();
+        let mut is_empty = true;
+
+        for entry in upstream_certificate.subject_name().entries() {
+            let nid = entry.object().nid();
+
+            if EXTENDED_VALIDATION_FIELDS.contains(&nid.short_name().unwrap_or_default()) {
+                continue;
+            }
+
+            if let Ok(value) = entry.data().as_utf8() {
+                if x509_name.append_entry_by_nid(nid, &value).is_ok() {
+                    is_empty = false;
+                }
+            }
+        }
+
+        if is_empty {
+            None
+        } else {
+            Some(x509_name.build())
+        }
+    }
+
+    fn build_certificate_request(
+        key_pair: &PKey<Private>,
+        authority: &Authority,
+        upstream_certificate: Option<&X509Ref>,
+    ) -> X509Req {
         let mut request_builder = X509ReqBuilder::new().unwrap();
         request_builder.set_pubkey(key_pair).unwrap();
 
+        if let Some(x509_name) = upstream_certificate.and_then(Self::build_mirrored_subject_name) {
+            request_builder.set_subject_name(&x509_name).unwrap();
+
+            request_builder
+                .sign(key_pair, MessageDigest::sha256())
+                .unwrap();
+
+            return request_builder.build();
+        }
+
         let mut x509_name = X509NameBuilder::new().unwrap();
 
         // Only 64 characters are allowed in the CN field.
@@ -89,8 +238,9 @@ impl SignedWithCaCert {
         ca_key_pair: &PKeyRef<Private>,
         authority: &Authority,
         private_key: &PKey<Private>,
+        upstream_certificate: Option<&X509Ref>,
     ) -> X509 {
-        let req = Self::build_certificate_request(private_key, authority);
+        let req = Self::build_certificate_request(private_key, authority, upstream_certificate);
 
         let mut cert_builder = X509::builder().unwrap();
         cert_builder.set_version(2).unwrap();
@@ -108,45 +258,52 @@ impl SignedWithCaCert {
             .unwrap();
         cert_builder.set_pubkey(private_key).unwrap();
 
-        let not_before = Asn1Time::days_from_now(0).unwrap();
-        cert_builder.set_not_before(&not_before).unwrap();
+        match upstream_certificate {
+            // Minted certificates can't be valid for longer than the CA is.
+            Some(upstream_certificate) => {
+                let not_before = if upstream_certificate.not_before() > ca_cert.not_before() {
+                    upstream_certificate.not_before()
+                } else {
+                    ca_cert.not_before()
+                };
+                cert_builder.set_not_before(not_before).unwrap();
+
+                let not_after = if upstream_certificate.not_after() < ca_cert.not_after() {
+                    upstream_certificate.not_after()
+                } else {
+                    ca_cert.not_after()
+                };
+                cert_builder.set_not_after(not_after).unwrap();
+            }
+            None => {
+                let not_before = Asn1Time::days_from_now(0).unwrap();
+                cert_builder.set_not_before(&not_before).unwrap();
 
-        let not_after = Asn1Time::days_from_now(365).unwrap();
-        cert_builder.set_not_after(&not_after).unwrap();
+                let not_after = Asn1Time::days_from_now(365).unwrap();
+                cert_builder.set_not_after(&not_after).unwrap();
+            }
+        }
 
         cert_builder
             .append_extension(BasicConstraints::new().build().unwrap())
             .unwrap();
 
+        let mut key_usage = KeyUsage::new();
+        key_usage.critical().non_repudiation().digital_signature();
+
+        // Only RSA keys may be used to exchange keys by encrypting them.
+        if private_key.id() == Id::RSA {
+            key_usage.key_encipherment();
+        }
+
         cert_builder
-            .append_extension(
-                KeyUsage::new()
-                    .critical()
-                    .non_repudiation()
-                    .digital_signature()
-                    .key_encipherment()
-                    .build()
-                    .unwrap(),
-            )
+            .append_extension(key_usage.build().unwrap())
             .unwrap();
 
-        let subject_alternative_name = match std::net::IpAddr::from_str(authority.host()) {
-            // If we are able to parse the authority as an ip address, let's build an "IP" field instead
-            // of a "DNS" one.
-            Ok(_ip_addr) => {
-                let mut san = SubjectAlternativeName::new();
-                san.ip(authority.host());
-
-                san
-            }
-            Err(_err) => {
-                let mut san = SubjectAlternativeName::new();
-                san.dns(authority.host());
-                san
-            }
-        }
-        .build(&cert_builder.x509v3_context(Some(ca_cert), None))
-        .unwrap();
+        let subject_alternative_name =
+            Self::subject_alternative_name(authority, upstream_certificate)
+                .build(&cert_builder.x509v3_context(Some(ca_cert), None))
+                .unwrap();
 
         cert_builder
             .append_extension(subject_alternative_name)
@@ -172,27 +329,81 @@ impl SignedWithCaCert {
 
         cert_builder.build()
     }
+
+    fn subject_alternative_name(
+        authority: &Authority,
+        upstream_certificate: Option<&X509Ref>,
+    ) -> SubjectAlternativeName {
+        let mut san = SubjectAlternativeName::new();
+        let mut is_authority_host_covered = false;
+
+        // IPv6 addresses are bracketed in authorities only.
+        let host = authority
+            .host()
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+
+        // Upstream names are copied as is, wildcards included.
+        if let Some(names) =
+            upstream_certificate.and_then(|certificate| certificate.subject_alt_names())
+        {
+            for name in names.iter() {
+                // Names are comma separated once built, such names couldn't be valid anyway.
+                if let Some(dns_name) = name.dnsname().filter(|name| !name.contains(',')) {
+                    is_authority_host_covered |= dns_name.eq_ignore_ascii_case(host);
+                    san.dns(dns_name);
+                } else if let Some(ip_address) = name.ipaddress().and_then(format_ip_address) {
+                    is_authority_host_covered |= ip_address == host;
+                    san.ip(&ip_address);
+                }
+            }
+        }
+
+        if !is_authority_host_covered {
+            // If we are able to parse the authority as an ip address, let's build an "IP" field
+            // instead of a "DNS" one.
+            match std::net::IpAddr::from_str(host) {
+                Ok(_ip_addr) => san.ip(host),
+                Err(_err) => san.dns(host),
+            };
+        }
+
+        san
+    }
 }
 
+// Certificates being minted, which concurrent requests for the same authority wait for.
+type PendingCertificates = HashMap<Authority, Arc<OnceCell<SignedWithCaCert>>>;
+
 #[derive(Clone)]
 pub struct CertCache {
     cache: Arc<Mutex<LRUCache<SignedWithCaCert, MAX_CACHED_CERTIFICATES>>>,
-    // We use a single RSA key for all certificates.
-    private_key: PKey<Private>,
+    pending_certificates: Arc<Mutex<PendingCertificates>>,
+    leaf_keys: LeafKeys,
     ca_certificate: X509,
     ca_private_key: PKey<Private>,
+    // Set when minted certificates mirror upstream ones.
+    upstream_certificate_fetcher: Option<UpstreamCertificateFetcher>,
+    // Set when minted certificates are persisted.
+    cert_store: Option<CertStore>,
 }
 
 impl CertCache {
-    pub fn new(ca_certificate: X509, ca_private_key: PKey<Private>) -> Self {
+    pub fn new(
+        ca_certificate: X509,
+        ca_private_key: PKey<Private>,
+        certificates_configuration: &CertificatesConfiguration,
+        upstream_certificate_fetcher: Option<UpstreamCertificateFetcher>,
+        cert_store: Option<CertStore>,
+    ) -> Self {
         Self {
             cache: Arc::new(Mutex::new(LRUCache::default())),
-            private_key: {
-                let rsa = Rsa::generate(2048).unwrap();
-                PKey::from_rsa(rsa).unwrap()
-            },
+            pending_certificates: Arc::new(Mutex::new(HashMap::new())),
+            leaf_keys: LeafKeys::new(certificates_configuration),
             ca_certificate,
             ca_private_key,
+            upstream_certificate_fetcher,
+            cert_store,
         }
     }
 
@@ -201,32 +412,373 @@ impl CertCache {
         cache.insert(certificate);
     }
 
-    pub async fn get(&self, authority: Authority) -> SignedWithCaCert {
-        let mut cache = self.cache.lock().await;
+    async fn mint(&self, authority: Authority) -> SignedWithCaCert {
+        let leaf_keys = self.leaf_keys.clone();
+
+        let ca_certificate = self.ca_certificate.clone();
+        let ca_private_key = self.ca_private_key.clone();
+
+        if let Some(cert_store) = self.cert_store.clone() {
+            let ca_certificate = ca_certificate.clone();
+            let authority = authority.clone();
+
+            let stored_certificate = tokio::task::spawn_blocking(move || {
+                cert_store
+                    .load(&authority)
+                    .map(|(certificate, private_key)| {
+                        SignedWithCaCert::from_certificate(
+                            authority,
+                            certificate,
+                            private_key,
+                            &ca_certificate,
+                        )
+                    })
+            })
+            .await
+            .unwrap();
 
-        match cache.find(|cert| cert.authority == authority) {
-            Some(certificate) => certificate.clone(),
-            None => {
-                // We release the previously acquired lock early as `insert`, which we will call just
-                // afterwards also waits to acquire a lock.
-                std::mem::drop(cache);
+            if let Some(stored_certificate) = stored_certificate {
+                return stored_certificate;
+            }
+        }
 
-                let private_key = self.private_key.clone();
+        let upstream_certificate = match &self.upstream_certificate_fetcher {
+            Some(upstream_certificate_fetcher) => {
+                upstream_certificate_fetcher.get(&authority).await
+            }
+            None => None,
+        };
+
+        let cert_store = self.cert_store.clone();
+
+        // This operation is somewhat CPU intensive and on some lower powered machines,
+        // not running it inside of a thread pool may cause it to block the executor for too long.
+        tokio::task::spawn_blocking(move || {
+            let certificate = SignedWithCaCert::new(
+                authority,
+                leaf_keys.next_key(),
+                ca_certificate,
+                ca_private_key,
+                upstream_certificate,
+            );
+
+            if let Some(cert_store) = cert_store {
+                if let Err(err) = cert_store.save(
+                    &certificate.authority,
+                    &certificate.certificate,
+                    &certificate.private_key,
+                ) {
+                    log::warn!(
+                        "Unable to store certificate of {}: {}",
+                        certificate.authority,
+                        err
+                    );
+                }
+            }
+
+            certificate
+        })
+        .await
+        .unwrap()
+    }
 
-                let ca_certificate = self.ca_certificate.clone();
-                let ca_private_key = self.ca_private_key.clone();
+    pub async fn get(&self, authority: Authority) -> SignedWithCaCert {
+        let pending_certificate = {
+            let mut cache = self.cache.lock().await;
 
-                // This operation is somewhat CPU intensive and on some lower powered machines,
-                // not running it inside of a thread pool may cause it to block the executor for too long.
-                let certificate = tokio::task::spawn_blocking(move || {
-                    SignedWithCaCert::new(authority, private_key, ca_certificate, ca_private_key)
-                })
+            if let Some(certificate) = cache.find(|cert| cert.authority == authority) {
+                return certificate.clone();
+            }
+
+            // The cache lock is held so that a certificate can't be inserted in the cache
+            // in between.
+            self.pending_certificates
+                .lock()
                 .await
-                .unwrap();
+                .entry(authority.clone())
+                .or_default()
+                .clone()
+        };
+
+        // Only the first caller mints the certificate, others wait for it.
+        pending_certificate
+            .get_or_init(|| async {
+                let certificate = self.mint(authority.clone()).await;
 
                 self.insert(certificate.clone()).await;
+                self.pending_certificates.lock().await.remove(&authority);
+
                 certificate
+            })
+            .await
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ca::make_ca_certificate,
+        configuration::{LeafKeyAlgorithm, LeafKeyReuse},
+    };
+    use openssl::{
+        asn1::Asn1TimeRef,
+        ec::{EcGroup, EcKey},
+        nid::Nid,
+    };
+
+    fn cert_cache(algorithm: LeafKeyAlgorithm, reuse: LeafKeyReuse) -> CertCache {
+        let (ca_certificate, ca_private_key) = make_ca_certificate();
+
+        CertCache::new(
+            ca_certificate,
+            ca_private_key,
+            &CertificatesConfiguration {
+                leaf_key_algorithm: algorithm,
+                leaf_key_reuse: reuse,
+                persist: false,
+                ..Default::default()
+            },
+            None,
+            None,
+        )
+    }
+
+    fn serial_number(certificate: &SignedWithCaCert) -> String {
+        certificate
+            .certificate
+            .serial_number()
+            .to_bn()
+            .unwrap()
+            .to_hex_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_requests_mint_once() {
+        let cert_cache = cert_cache(LeafKeyAlgorithm::EcdsaP256, LeafKeyReuse::PerHost);
+
+        let tasks = Vec::from_iter((0..8).map(|_| {
+            let cert_cache = cert_cache.clone();
+
+            tokio::spawn(async move {
+                cert_cache
+                    .get(Authority::from_static("example.com:443"))
+                    .await
+            })
+        }));
+
+        let certificates = Vec::from_iter(
+            futures::future::join_all(tasks)
+                .await
+                .into_iter()
+                .map(|certificate| certificate.unwrap()),
+        );
+
+        for certificate in &certificates[1..] {
+            assert_eq!(serial_number(certificate), serial_number(&certificates[0]));
+            assert!(certificate
+                .private_key
+                .public_eq(&certificates[0].private_key));
+        }
+
+        assert!(cert_cache.pending_certificates.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_per_host_keys() {
+        let cert_cache = cert_cache(LeafKeyAlgorithm::EcdsaP256, LeafKeyReuse::PerHost);
+
+        let certificate = cert_cache
+            .get(Authority::from_static("example.com:443"))
+            .await;
+        let other_certificate = cert_cache
+            .get(Authority::from_static("example.org:443"))
+            .await;
+
+        assert!(!certificate
+            .private_key
+            .public_eq(&other_certificate.private_key));
+    }
+
+    #[tokio::test]
+    async fn test_key_encipherment_usage() {
+        let text = |certificate: &SignedWithCaCert| {
+            String::from_utf8(certificate.certificate.to_text().unwrap()).unwrap()
+        };
+
+        let ec_cert_cache = cert_cache(LeafKeyAlgorithm::EcdsaP256, LeafKeyReuse::Shared);
+        let certificate = ec_cert_cache
+            .get(Authority::from_static("example.com:443"))
+            .await;
+
+        assert!(text(&certificate).contains("Digital Signature"));
+        assert!(!text(&certificate).contains("Key Encipherment"));
+
+        let rsa_cert_cache = cert_cache(LeafKeyAlgorithm::Rsa, LeafKeyReuse::Shared);
+        let certificate = rsa_cert_cache
+            .get(Authority::from_static("example.com:443"))
+            .await;
+
+        assert!(text(&certificate).contains("Key Encipherment"));
+    }
+
+    fn ec_key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    /// A self-signed certificate such as an upstream server with an extended validation
+    /// certificate would present.
+    fn upstream_fixture(not_before: &Asn1TimeRef, not_after: &Asn1TimeRef) -> X509 {
+        let key = ec_key();
+
+        let mut x509_name = X509NameBuilder::new().unwrap();
+        x509_name.append_entry_by_text("CN", "example.com").unwrap();
+        x509_name.append_entry_by_text("O", "Example").unwrap();
+        x509_name
+            .append_entry_by_text("serialNumber", "1234")
+            .unwrap();
+        x509_name
+            .append_entry_by_text("businessCategory", "Private Organization")
+            .unwrap();
+        x509_name
+            .append_entry_by_text("jurisdictionC", "US")
+            .unwrap();
+        let x509_name = x509_name.build();
+
+        let mut cert_builder = X509::builder().unwrap();
+        cert_builder.set_version(2).unwrap();
+        cert_builder.set_subject_name(&x509_name).unwrap();
+        cert_builder.set_issuer_name(&x509_name).unwrap();
+        cert_builder.set_pubkey(&key).unwrap();
+        cert_builder.set_not_before(not_before).unwrap();
+        cert_builder.set_not_after(not_after).unwrap();
+
+        let subject_alternative_name = SubjectAlternativeName::new()
+            .dns("example.com")
+            .dns("*.example.com")
+            .ip("192.0.2.1")
+            .ip("2001:db8::1")
+            .build(&cert_builder.x509v3_context(None, None))
+            .unwrap();
+        cert_builder
+            .append_extension(subject_alternative_name)
+            .unwrap();
+
+        cert_builder.sign(&key, MessageDigest::sha256()).unwrap();
+        cert_builder.build()
+    }
+
+    fn mint(
+        authority: &str,
+        (ca_certificate, ca_private_key): &(X509, PKey<Private>),
+        upstream_certificate: &X509,
+    ) -> X509 {
+        SignedWithCaCert::build_ca_signed_cert(
+            ca_certificate,
+            ca_private_key,
+            &Authority::from_str(authority).unwrap(),
+            &ec_key(),
+            Some(upstream_certificate),
+        )
+    }
+
+    fn alternative_names(certificate: &X509) -> Vec<String> {
+        Vec::from_iter(certificate.subject_alt_names().unwrap().iter().map(|name| {
+            match name.dnsname() {
+                Some(dns_name) => dns_name.to_string(),
+                None => format_ip_address(name.ipaddress().unwrap()).unwrap(),
             }
+        }))
+    }
+
+    #[test]
+    fn test_upstream_alternative_names_are_copied() {
+        let ca = make_ca_certificate();
+        let upstream_certificate = upstream_fixture(
+            &Asn1Time::days_from_now(1).unwrap(),
+            &Asn1Time::days_from_now(90).unwrap(),
+        );
+
+        let upstream_names = ["example.com", "*.example.com", "192.0.2.1", "2001:db8::1"];
+
+        // Authorities the upstream certificate names aren't added again.
+        for authority in [
+            "example.com:443",
+            "EXAMPLE.com:443",
+            "192.0.2.1:443",
+            "[2001:db8::1]:443",
+        ] {
+            assert_eq!(
+                alternative_names(&mint(authority, &ca, &upstream_certificate)),
+                upstream_names
+            );
         }
+
+        // Wildcards don't count as covering an authority, as clients might not accept them
+        // for it.
+        assert_eq!(
+            alternative_names(&mint("www.example.com:443", &ca, &upstream_certificate)),
+            [&upstream_names[..], &["www.example.com"]].concat()
+        );
+        assert_eq!(
+            alternative_names(&mint("192.0.2.2:443", &ca, &upstream_certificate)),
+            [&upstream_names[..], &["192.0.2.2"]].concat()
+        );
+        assert_eq!(
+            alternative_names(&mint("[2001:db8::2]:443", &ca, &upstream_certificate)),
+            [&upstream_names[..], &["2001:db8::2"]].concat()
+        );
+    }
+
+    #[test]
+    fn test_upstream_validity_is_clamped_to_the_ca() {
+        let ca = make_ca_certificate();
+
+        let upstream_certificate = upstream_fixture(
+            &Asn1Time::from_unix(0).unwrap(),
+            &Asn1Time::days_from_now(2 * 3650).unwrap(),
+        );
+        let certificate = mint("example.com:443", &ca, &upstream_certificate);
+
+        assert!(certificate.not_before() == ca.0.not_before());
+        assert!(certificate.not_after() == ca.0.not_after());
+
+        let upstream_certificate = upstream_fixture(
+            &Asn1Time::days_from_now(1).unwrap(),
+            &Asn1Time::days_from_now(90).unwrap(),
+        );
+        let certificate = mint("example.com:443", &ca, &upstream_certificate);
+
+        assert!(certificate.not_before() == upstream_certificate.not_before());
+        assert!(certificate.not_after() == upstream_certificate.not_after());
+    }
+
+    #[test]
+    fn test_extended_validation_fields_are_dropped() {
+        let ca = make_ca_certificate();
+        let upstream_certificate = upstream_fixture(
+            &Asn1Time::days_from_now(1).unwrap(),
+            &Asn1Time::days_from_now(90).unwrap(),
+        );
+        let certificate = mint("example.com:443", &ca, &upstream_certificate);
+
+        let subject_fields = Vec::from_iter(certificate.subject_name().entries().map(|entry| {
+            (
+                entry.object().nid().short_name().unwrap(),
+                entry.data().as_utf8().unwrap().to_string(),
+            )
+        }));
+
+        assert_eq!(
+            subject_fields,
+            [
+                ("CN", String::from("example.com")),
+                ("O", String::from("Example"))
+            ]
+        );
     }
 }
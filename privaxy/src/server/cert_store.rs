@@ -0,0 +1,535 @@
+//! Minted certificates persisted on disk, so that they don't have to be minted again after a
+//! restart.
+//!
+//! Every certificate is stored along with its key in its own file, named after a hash of its
+//! authority and encrypted with AES-256-GCM using a key derived from the CA's private key.
+//! Certificates minted for another CA or with other certificate settings are discarded.
+use crate::configuration::{
+    get_certificates_directory_path, CertificatesConfiguration, LeafKeyAlgorithm, LeafKeyReuse,
+};
+use http::uri::Authority;
+use openssl::{
+    asn1::Asn1Time,
+    pkey::{PKey, PKeyRef, Private},
+    rand::rand_bytes,
+    sha::{sha256, Sha256},
+    symm::{decrypt_aead, encrypt_aead, Cipher},
+    x509::{X509Ref, X509},
+};
+use serde::{Deserialize, Serialize};
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+const FINGERPRINT_FILE_NAME: &str = "fingerprint";
+const CERTIFICATE_FILE_EXTENSION: &str = "bin";
+const TEMPORARY_FILE_EXTENSION: &str = "tmp";
+// Temporary files older than this were left behind by an interrupted save, younger ones may still
+// be being written.
+const TEMPORARY_FILE_MAX_AGE: Duration = Duration::from_secs(60);
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+// Certificates expiring sooner are minted again.
+const MIN_REMAINING_VALIDITY_DAYS: u32 = 1;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn io_error<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::Other, error)
+}
+
+// Only the user running Privaxy may read stored keys.
+fn write_private_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    options.open(path)?.write_all(contents)
+}
+
+fn create_private_directory(directory: &Path) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+
+    #[cfg(unix)]
+    builder.mode(0o700);
+
+    builder.create(directory)
+}
+
+/// What the stored certificates were minted with, changing any of it empties the store.
+#[derive(Serialize)]
+struct StoreFingerprint {
+    ca_certificate: String,
+    mirror_upstream: bool,
+    leaf_key_algorithm: LeafKeyAlgorithm,
+    leaf_key_reuse: LeafKeyReuse,
+    leaf_key_pool_size: usize,
+    leaf_key_rotation_interval: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredCertificate {
+    authority: String,
+    // Base64 encoded DER.
+    certificate: String,
+    private_key: String,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct CertStore {
+    directory: PathBuf,
+    encryption_key: [u8; 32],
+}
+
+impl CertStore {
+    /// Opens the store, emptying it if it was filled for another CA or with other settings.
+    pub(crate) fn open(
+        ca_certificate: &X509Ref,
+        ca_private_key: &PKeyRef<Private>,
+        configuration: &CertificatesConfiguration,
+    ) -> io::Result<Self> {
+        let directory = get_certificates_directory_path().map_err(io_error)?;
+
+        Self::open_directory(directory, ca_certificate, ca_private_key, configuration)
+    }
+
+    fn open_directory(
+        directory: PathBuf,
+        ca_certificate: &X509Ref,
+        ca_private_key: &PKeyRef<Private>,
+        configuration: &CertificatesConfiguration,
+    ) -> io::Result<Self> {
+        create_private_directory(&directory)?;
+
+        let fingerprint = StoreFingerprint {
+            ca_certificate: hex(&sha256(&ca_certificate.to_der().map_err(io_error)?)),
+            mirror_upstream: configuration.mirror_upstream,
+            leaf_key_algorithm: configuration.leaf_key_algorithm,
+            leaf_key_reuse: configuration.leaf_key_reuse,
+            leaf_key_pool_size: configuration.leaf_key_pool_size,
+            leaf_key_rotation_interval: configuration.leaf_key_rotation_interval,
+        };
+        let fingerprint = hex(&sha256(&serde_json::to_vec(&fingerprint)?));
+        let fingerprint_path = directory.join(FINGERPRINT_FILE_NAME);
+
+        let is_same_fingerprint = match fs::read_to_string(&fingerprint_path) {
+            Ok(stored_fingerprint) => stored_fingerprint.trim() == fingerprint,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => false,
+            Err(err) => return Err(err),
+        };
+
+        if !is_same_fingerprint {
+            log::debug!("Clearing certificates minted for another CA or with other settings");
+
+            for path in Self::paths(&directory, CERTIFICATE_FILE_EXTENSION)?
+                .into_iter()
+                .chain(Self::paths(&directory, TEMPORARY_FILE_EXTENSION)?)
+            {
+                fs::remove_file(path)?;
+            }
+
+            write_private_file(&fingerprint_path, fingerprint.as_bytes())?;
+        }
+
+        let mut hasher = Sha256::new();
+        hasher.update(b"privaxy certificate store");
+        hasher.update(&ca_private_key.private_key_to_der().map_err(io_error)?);
+
+        Ok(Self {
+            directory,
+            encryption_key: hasher.finish(),
+        })
+    }
+
+    fn paths(directory: &Path, extension: &str) -> io::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+
+        for entry in fs::read_dir(directory)? {
+            let path = entry?.path();
+
+            if path.extension().and_then(|extension| extension.to_str()) == Some(extension) {
+                paths.push(path);
+            }
+        }
+
+        Ok(paths)
+    }
+
+    fn certificate_path(&self, authority: &Authority) -> PathBuf {
+        self.directory
+            .join(hex(&sha256(authority.as_str().as_bytes())))
+            .with_extension(CERTIFICATE_FILE_EXTENSION)
+    }
+
+    fn encrypt(&self, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        let mut nonce = [0; NONCE_LENGTH];
+        rand_bytes(&mut nonce).map_err(io_error)?;
+
+        let mut tag = [0; TAG_LENGTH];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.encryption_key,
+            Some(&nonce[..]),
+            &[],
+            plaintext,
+            &mut tag,
+        )
+        .map_err(io_error)?;
+
+        Ok([&nonce[..], &tag[..], &ciphertext[..]].concat())
+    }
+
+    fn decrypt(&self, contents: &[u8]) -> io::Result<Vec<u8>> {
+        if contents.len() < NONCE_LENGTH + TAG_LENGTH {
+            return Err(io_error("truncated certificate file"));
+        }
+
+        let (nonce, contents) = contents.split_at(NONCE_LENGTH);
+        let (tag, ciphertext) = contents.split_at(TAG_LENGTH);
+
+        decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.encryption_key,
+            Some(nonce),
+            &[],
+            ciphertext,
+            tag,
+        )
+        .map_err(io_error)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<(String, X509, PKey<Private>)> {
+        let plaintext = self.decrypt(&fs::read(path)?)?;
+        let stored_certificate = serde_json::from_slice::<StoredCertificate>(&plaintext)?;
+
+        let certificate =
+            X509::from_der(&base64::decode(&stored_certificate.certificate).map_err(io_error)?)
+                .map_err(io_error)?;
+        let private_key = PKey::private_key_from_der(
+            &base64::decode(&stored_certificate.private_key).map_err(io_error)?,
+        )
+        .map_err(io_error)?;
+
+        Ok((stored_certificate.authority, certificate, private_key))
+    }
+
+    fn is_expiring(certificate: &X509Ref) -> bool {
+        match Asn1Time::days_from_now(MIN_REMAINING_VALIDITY_DAYS) {
+            Ok(expiration_limit) => certificate.not_after() < &*expiration_limit,
+            Err(_err) => true,
+        }
+    }
+
+    /// Returns the certificate minted for `authority` along with its key, unless there is none
+    /// or it is about to expire.
+    pub(crate) fn load(&self, authority: &Authority) -> Option<(X509, PKey<Private>)> {
+        let path = self.certificate_path(authority);
+
+        let (stored_authority, certificate, private_key) = match self.read(&path) {
+            Ok(stored_certificate) => stored_certificate,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return None,
+            Err(err) => {
+                log::debug!(
+                    "Discarding unreadable certificate of {}: {}",
+                    authority,
+                    err
+                );
+                let _result = fs::remove_file(&path);
+                return None;
+            }
+        };
+
+        if stored_authority != authority.as_str() || Self::is_expiring(&certificate) {
+            let _result = fs::remove_file(&path);
+            return None;
+        }
+
+        Some((certificate, private_key))
+    }
+
+    pub(crate) fn save(
+        &self,
+        authority: &Authority,
+        certificate: &X509Ref,
+        private_key: &PKeyRef<Private>,
+    ) -> io::Result<()> {
+        let stored_certificate = StoredCertificate {
+            authority: authority.to_string(),
+            certificate: base64::encode(certificate.to_der().map_err(io_error)?),
+            private_key: base64::encode(private_key.private_key_to_der().map_err(io_error)?),
+        };
+
+        let contents = self.encrypt(&serde_json::to_vec(&stored_certificate)?)?;
+
+        // Written aside first, so that a certificate is never read half written.
+        let path = self.certificate_path(authority);
+        let temporary_path = path.with_extension(TEMPORARY_FILE_EXTENSION);
+
+        write_private_file(&temporary_path, &contents)?;
+        fs::rename(&temporary_path, &path)
+    }
+
+    /// Removes certificates which expired or can't be read anymore, as well as files left behind
+    /// by interrupted saves.
+    pub(crate) fn prune(&self) -> io::Result<()> {
+        let mut pruned_certificates = 0;
+
+        for path in Self::paths(&self.directory, TEMPORARY_FILE_EXTENSION)? {
+            let age = fs::metadata(&path)?
+                .modified()
+                .ok()
+                .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+
+            // Files whose age can't be told are considered left behind.
+            if age.unwrap_or(Duration::MAX) > TEMPORARY_FILE_MAX_AGE {
+                fs::remove_file(&path)?;
+            }
+        }
+
+        for path in Self::paths(&self.directory, CERTIFICATE_FILE_EXTENSION)? {
+            let is_valid = match self.read(&path) {
+                Ok((_authority, certificate, _private_key)) => !Self::is_expiring(&certificate),
+                Err(_err) => false,
+            };
+
+            if !is_valid {
+                fs::remove_file(&path)?;
+                pruned_certificates += 1;
+            }
+        }
+
+        log::debug!("Pruned {} stored certificates", pruned_certificates);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ca::make_ca_certificate;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static DIRECTORIES: AtomicUsize = AtomicUsize::new(0);
+
+    fn temporary_directory() -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "privaxy-cert-store-test-{}-{}",
+            std::process::id(),
+            DIRECTORIES.fetch_add(1, Ordering::SeqCst)
+        ))
+    }
+
+    fn cert_store(encryption_key: [u8; 32]) -> CertStore {
+        CertStore {
+            directory: temporary_directory(),
+            encryption_key,
+        }
+    }
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let cert_store = cert_store([1; 32]);
+
+        let encrypted = cert_store.encrypt(b"certificate").unwrap();
+        let encrypted_again = cert_store.encrypt(b"certificate").unwrap();
+
+        assert_eq!(
+            encrypted.len(),
+            NONCE_LENGTH + TAG_LENGTH + b"certificate".len()
+        );
+        assert_ne!(encrypted, encrypted_again);
+        assert_eq!(cert_store.decrypt(&encrypted).unwrap(), b"certificate");
+        assert_eq!(
+            cert_store.decrypt(&encrypted_again).unwrap(),
+            b"certificate"
+        );
+    }
+
+    #[test]
+    fn test_decrypt_tampered() {
+        let cert_store = cert_store([1; 32]);
+
+        let mut encrypted = cert_store.encrypt(b"certificate").unwrap();
+        let last_byte = encrypted.len() - 1;
+        encrypted[last_byte] ^= 1;
+
+        assert!(cert_store.decrypt(&encrypted).is_err());
+    }
+
+    #[test]
+    fn test_decrypt_truncated() {
+        let cert_store = cert_store([1; 32]);
+
+        let encrypted = cert_store.encrypt(b"certificate").unwrap();
+
+        assert!(cert_store
+            .decrypt(&encrypted[..NONCE_LENGTH + TAG_LENGTH - 1])
+            .is_err());
+        assert!(cert_store
+            .decrypt(&encrypted[..encrypted.len() - 1])
+            .is_err());
+    }
+
+    #[test]
+    fn test_decrypt_with_other_key() {
+        let encrypted = cert_store([1; 32]).encrypt(b"certificate").unwrap();
+
+        assert!(cert_store([2; 32]).decrypt(&encrypted).is_err());
+    }
+
+    #[test]
+    fn test_save_load() {
+        let directory = temporary_directory();
+        let (ca_certificate, ca_private_key) = make_ca_certificate();
+        let (certificate, private_key) = make_ca_certificate();
+        let authority = Authority::from_static("example.com");
+
+        let cert_store = CertStore::open_directory(
+            directory.clone(),
+            &ca_certificate,
+            &ca_private_key,
+            &CertificatesConfiguration::default(),
+        )
+        .unwrap();
+
+        assert!(cert_store.load(&authority).is_none());
+
+        cert_store
+            .save(&authority, &certificate, &private_key)
+            .unwrap();
+
+        let (stored_certificate, stored_private_key) = cert_store.load(&authority).unwrap();
+        assert_eq!(
+            stored_certificate.to_der().unwrap(),
+            certificate.to_der().unwrap()
+        );
+        assert!(stored_private_key.public_eq(&private_key));
+        assert!(cert_store
+            .load(&Authority::from_static("example.org"))
+            .is_none());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            for path in [
+                cert_store.certificate_path(&authority),
+                directory.join(FINGERPRINT_FILE_NAME),
+            ] {
+                assert_eq!(
+                    fs::metadata(path).unwrap().permissions().mode() & 0o777,
+                    0o600
+                );
+            }
+        }
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_open_with_other_settings() {
+        let directory = temporary_directory();
+        let (ca_certificate, ca_private_key) = make_ca_certificate();
+        let (certificate, private_key) = make_ca_certificate();
+        let authority = Authority::from_static("example.com");
+
+        let open = |configuration: &CertificatesConfiguration| {
+            CertStore::open_directory(
+                directory.clone(),
+                &ca_certificate,
+                &ca_private_key,
+                configuration,
+            )
+            .unwrap()
+        };
+
+        open(&CertificatesConfiguration::default())
+            .save(&authority, &certificate, &private_key)
+            .unwrap();
+
+        assert!(open(&CertificatesConfiguration::default())
+            .load(&authority)
+            .is_some());
+
+        let other_configurations = [
+            CertificatesConfiguration {
+                mirror_upstream: true,
+                ..Default::default()
+            },
+            CertificatesConfiguration {
+                leaf_key_algorithm: LeafKeyAlgorithm::Rsa,
+                ..Default::default()
+            },
+            CertificatesConfiguration {
+                leaf_key_reuse: LeafKeyReuse::PerHost,
+                ..Default::default()
+            },
+            CertificatesConfiguration {
+                leaf_key_pool_size: 4,
+                ..Default::default()
+            },
+            CertificatesConfiguration {
+                leaf_key_rotation_interval: 60,
+                ..Default::default()
+            },
+        ];
+
+        for configuration in other_configurations {
+            let cert_store = open(&CertificatesConfiguration::default());
+            cert_store
+                .save(&authority, &certificate, &private_key)
+                .unwrap();
+
+            assert!(open(&configuration).load(&authority).is_none());
+        }
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_prune() {
+        let directory = temporary_directory();
+        let (ca_certificate, ca_private_key) = make_ca_certificate();
+        let (certificate, private_key) = make_ca_certificate();
+        let authority = Authority::from_static("example.com");
+
+        let cert_store = CertStore::open_directory(
+            directory.clone(),
+            &ca_certificate,
+            &ca_private_key,
+            &CertificatesConfiguration::default(),
+        )
+        .unwrap();
+        cert_store
+            .save(&authority, &certificate, &private_key)
+            .unwrap();
+
+        let unreadable_path = directory.join("unreadable").with_extension("bin");
+        fs::write(&unreadable_path, b"unreadable").unwrap();
+        // Might still be being written.
+        let temporary_path = directory.join("saving").with_extension("tmp");
+        fs::write(&temporary_path, b"saving").unwrap();
+
+        cert_store.prune().unwrap();
+
+        assert!(!unreadable_path.exists());
+        assert!(temporary_path.exists());
+        assert!(cert_store.load(&authority).is_some());
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
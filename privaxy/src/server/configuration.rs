@@ -1,5 +1,10 @@
 use crate::{
-    blocker::AdblockRequester, ca::make_ca_certificate, proxy::exclusions::LocalExclusionStore,
+    blocker::AdblockRequester,
+    ca::make_ca_certificate,
+    proxy::exclusions::LocalExclusionStore,
+    response_rewrite_rules::ResponseRewriteRule,
+    trust_store::{parse_certificate_exception, TrustStore},
+    upstream_proxies::UpstreamProxyRule,
 };
 use dirs::home_dir;
 use futures::future::{try_join_all, AbortHandle, Abortable};
@@ -8,6 +13,7 @@ use openssl::{
     x509::X509,
 };
 use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 use std::{collections::BTreeSet, time::Duration};
 use thiserror::Error;
@@ -20,6 +26,8 @@ const METADATA_FILE_NAME: &str = "metadata.json";
 const CONFIGURATION_DIRECTORY_NAME: &str = ".privaxy";
 const CONFIGURATION_FILE_NAME: &str = "config";
 const FILTERS_DIRECTORY_NAME: &str = "filters";
+const AUTO_EXCLUSIONS_FILE_NAME: &str = "auto_exclusions";
+const CERTIFICATES_DIRECTORY_NAME: &str = "certificates";
 
 // Update filters every 10 minutes.
 const FILTERS_UPDATE_AFTER: Duration = Duration::from_secs(60 * 10);
@@ -113,12 +121,336 @@ pub struct Ca {
     ca_private_key: String,
 }
 
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct HtmlRewriterConfiguration {
+    /// Maximum amount of memory, in bytes, the html rewriter may use to buffer a document.
+    /// Documents requiring more are passed through without further rewriting.
+    pub max_memory_usage: usize,
+    /// Documents larger than this, in bytes, are not rewritten. Documents are held back until
+    /// they are entirely rewritten.
+    pub max_content_length: u64,
+    /// Time, in milliseconds, after which a document is passed through without being rewritten.
+    /// This mostly concerns documents streamed over long periods of time.
+    pub max_rewriting_duration: u64,
+    /// Number of distinct classes and ids collected from a document after which it is passed
+    /// through without being rewritten.
+    pub max_collected_selectors: usize,
+    /// Apply cosmetic filters to elements inserted into documents by scripts, once loaded.
+    pub dynamic_cosmetic_filtering: bool,
+}
+
+impl Default for HtmlRewriterConfiguration {
+    fn default() -> Self {
+        Self {
+            max_memory_usage: 16 * 1024 * 1024,
+            max_content_length: 5 * 1024 * 1024,
+            max_rewriting_duration: 10_000,
+            max_collected_selectors: 20_000,
+            dynamic_cosmetic_filtering: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct UpstreamConfiguration {
+    /// Maximum number of idle connections kept open towards each upstream host.
+    pub max_idle_connections_per_host: usize,
+    /// Time, in seconds, after which idle connections towards upstream hosts are closed.
+    pub idle_connection_timeout: u64,
+}
+
+impl Default for UpstreamConfiguration {
+    fn default() -> Self {
+        Self {
+            max_idle_connections_per_host: 32,
+            idle_connection_timeout: 90,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct ListenerConfiguration {
+    /// Address the HTTP and SOCKS5 proxies listen on. Other devices, such as those fetching the
+    /// proxy auto-config script, can only reach the proxy when it listens on a non-loopback
+    /// address like `0.0.0.0`, in which case anyone able to reach it may use it.
+    pub address: IpAddr,
+}
+
+impl Default for ListenerConfiguration {
+    fn default() -> Self {
+        Self {
+            address: IpAddr::from([127, 0, 0, 1]),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct Socks5ListenerConfiguration {
+    /// Accept SOCKS5 connections, in addition to HTTP proxy ones.
+    pub enabled: bool,
+    pub port: u16,
+}
+
+impl Default for Socks5ListenerConfiguration {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 8101,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct TransparentProxyConfiguration {
+    /// Accept connections redirected by the firewall, only supported on Linux.
+    pub enabled: bool,
+    pub address: IpAddr,
+    pub port: u16,
+    /// Whether connections are redirected with TPROXY rather than NAT.
+    pub tproxy: bool,
+}
+
+impl Default for TransparentProxyConfiguration {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: IpAddr::from([127, 0, 0, 1]),
+            port: 8102,
+            tproxy: false,
+        }
+    }
+}
+
+/// Kind of keys certificates presented to clients are issued for.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LeafKeyAlgorithm {
+    EcdsaP256,
+    /// 2048 bits RSA keys, for clients which don't support ECDSA.
+    Rsa,
+}
+
+/// How keys are shared between the certificates presented to clients.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LeafKeyReuse {
+    /// A single key for every host.
+    Shared,
+    /// A distinct key for every host.
+    PerHost,
+    /// A single key, replaced once `leaf_key_rotation_interval` has elapsed.
+    Rotating,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct CertificatesConfiguration {
+    /// Copy the alternative names, validity and subject of upstream servers' certificates into
+    /// the certificates presented to clients. This costs an additional upstream handshake whenever
+    /// a certificate is minted.
+    pub mirror_upstream: bool,
+    pub leaf_key_algorithm: LeafKeyAlgorithm,
+    pub leaf_key_reuse: LeafKeyReuse,
+    /// Number of keys generated ahead of time, when keys aren't shared.
+    pub leaf_key_pool_size: usize,
+    /// In seconds.
+    pub leaf_key_rotation_interval: u64,
+    /// Keep minted certificates and their keys on disk, encrypted, so that they survive restarts.
+    /// They are discarded whenever the CA or any of the settings above changes.
+    pub persist: bool,
+}
+
+impl Default for CertificatesConfiguration {
+    fn default() -> Self {
+        Self {
+            mirror_upstream: false,
+            leaf_key_algorithm: LeafKeyAlgorithm::EcdsaP256,
+            leaf_key_reuse: LeafKeyReuse::Shared,
+            leaf_key_pool_size: 16,
+            leaf_key_rotation_interval: 24 * 60 * 60,
+            persist: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct ConnectConfiguration {
+    /// Ports clients may open tunnels towards, either single ports such as `443` or ranges such as
+    /// `8000-8999`. Any port is allowed by default, tunnels can be restricted to some ports, e.g.
+    /// `allowed_ports = ["80", "443", "8443", "8000-8999"]`.
+    ///
+    /// Only TLS connections and plaintext HTTP requests are filtered, anything else sent through a
+    /// tunnel is passed along unfiltered.
+    pub allowed_ports: Vec<String>,
+}
+
+impl Default for ConnectConfiguration {
+    fn default() -> Self {
+        Self {
+            allowed_ports: vec![String::from("1-65535")],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct PacConfiguration {
+    /// IPv4 networks, such as `192.168.0.0/16`, the served proxy auto-config script sends
+    /// `DIRECT`.
+    pub bypass_networks: Vec<String>,
+    /// Patterns of host names resolved by the script to find out whether they belong to bypass
+    /// networks. Other host names are sent to the proxy without being resolved.
+    pub resolved_hosts: Vec<String>,
+}
+
+impl Default for PacConfiguration {
+    fn default() -> Self {
+        Self {
+            bypass_networks: vec![
+                String::from("10.0.0.0/8"),
+                String::from("127.0.0.0/8"),
+                String::from("172.16.0.0/12"),
+                String::from("192.168.0.0/16"),
+            ],
+            resolved_hosts: vec![
+                String::from("*.home.arpa"),
+                String::from("*.internal"),
+                String::from("*.lan"),
+                String::from("*.local"),
+                String::from("*.localdomain"),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct UpstreamTlsConfiguration {
+    /// Trust the certificate authorities of the operating system.
+    pub native_roots: bool,
+    /// Trust Mozilla's certificate authorities, bundled with Privaxy.
+    pub bundled_roots: bool,
+    /// PEM files holding additional certificate authorities to trust, such as corporate ones.
+    pub extra_root_certificates: Vec<PathBuf>,
+    /// Certificates accepted even when they can't be verified, such as self-signed certificates of
+    /// devices on the local network. Each exception is a host followed by the SHA-256 fingerprint
+    /// of its certificate, as shown when the certificate is rejected, e.g.
+    /// `"router.lan 3A:1F:...:C4"`. Other certificates presented by the host are still rejected.
+    pub certificate_exceptions: BTreeSet<String>,
+}
+
+impl Default for UpstreamTlsConfiguration {
+    fn default() -> Self {
+        Self {
+            native_roots: true,
+            bundled_roots: true,
+            extra_root_certificates: Vec::new(),
+            certificate_exceptions: BTreeSet::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct UpstreamDnsConfiguration {
+    /// Resolver used to look up upstream hosts: `system`, a DNS-over-HTTPS endpoint such as
+    /// `https://cloudflare-dns.com/dns-query` or a DNS-over-TLS server such as
+    /// `tls://one.one.one.one`.
+    pub resolver: String,
+    /// Addresses of the resolver's host, so that looking it up doesn't go through the system
+    /// resolver.
+    pub bootstrap_addresses: Vec<IpAddr>,
+}
+
+impl Default for UpstreamDnsConfiguration {
+    fn default() -> Self {
+        Self {
+            resolver: String::from("system"),
+            bootstrap_addresses: Vec::new(),
+        }
+    }
+}
+
+/// How queries for blocked domains are answered by the DNS sinkhole.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockedDnsAnswer {
+    /// `0.0.0.0` or `::`, depending on the requested record type.
+    UnspecifiedAddress,
+    Nxdomain,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct DnsSinkholeConfiguration {
+    /// Answer DNS queries over UDP and TCP, blocking domains blocked as a whole by filters.
+    pub enabled: bool,
+    pub address: IpAddr,
+    pub port: u16,
+    /// Resolver queries for domains which aren't blocked are forwarded to.
+    pub upstream_resolver: SocketAddr,
+    pub blocked_answer: BlockedDnsAnswer,
+}
+
+impl Default for DnsSinkholeConfiguration {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: IpAddr::from([127, 0, 0, 1]),
+            port: 5353,
+            upstream_resolver: SocketAddr::from(([1, 1, 1, 1], 53)),
+            blocked_answer: BlockedDnsAnswer::UnspecifiedAddress,
+        }
+    }
+}
+
+fn default_strip_alt_svc() -> bool {
+    true
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 pub struct Configuration {
     pub exclusions: BTreeSet<String>,
     pub custom_filters: Vec<String>,
+    /// Remove HTTP/3 alternatives from `Alt-Svc` headers, browsers would otherwise switch to QUIC
+    /// which bypasses the proxy. The DNS sinkhole also answers HTTPS record queries with no
+    /// records, as these may advertise HTTP/3 as well.
+    #[serde(default = "default_strip_alt_svc")]
+    pub strip_alt_svc: bool,
     ca: Ca,
     pub filters: Vec<Filter>,
+    #[serde(default)]
+    pub response_rewrite_rules: Vec<ResponseRewriteRule>,
+    #[serde(default)]
+    pub html_rewriter: HtmlRewriterConfiguration,
+    #[serde(default)]
+    pub upstream: UpstreamConfiguration,
+    #[serde(default)]
+    pub upstream_proxies: Vec<UpstreamProxyRule>,
+    #[serde(default)]
+    pub upstream_dns: UpstreamDnsConfiguration,
+    #[serde(default)]
+    pub upstream_tls: UpstreamTlsConfiguration,
+    #[serde(default)]
+    pub listener: ListenerConfiguration,
+    #[serde(default)]
+    pub socks5_listener: Socks5ListenerConfiguration,
+    #[serde(default)]
+    pub transparent_proxy: TransparentProxyConfiguration,
+    #[serde(default)]
+    pub dns_sinkhole: DnsSinkholeConfiguration,
+    #[serde(default)]
+    pub pac: PacConfiguration,
+    #[serde(default)]
+    pub connect: ConnectConfiguration,
+    #[serde(default)]
+    pub certificates: CertificatesConfiguration,
 }
 
 #[derive(Error, Debug)]
@@ -229,6 +561,46 @@ impl Configuration {
         Ok(())
     }
 
+    pub async fn set_certificate_exceptions(
+        &mut self,
+        certificate_exceptions: &str,
+        trust_store: TrustStore,
+    ) -> ConfigurationResult<()> {
+        // Valid exceptions are saved normalized, invalid ones are kept for the user to fix them
+        // but ignored.
+        self.upstream_tls.certificate_exceptions =
+            Self::deserialize_lines::<Vec<String>>(certificate_exceptions)
+                .into_iter()
+                .map(|exception| match parse_certificate_exception(&exception) {
+                    Some((host, fingerprint)) => format!("{} {}", host, fingerprint),
+                    None => exception,
+                })
+                .collect();
+
+        self.save().await?;
+
+        trust_store.replace_certificate_exceptions(&self.upstream_tls.certificate_exceptions);
+
+        Ok(())
+    }
+
+    /// Moves a host from the automatically excluded ones to the user defined exclusions.
+    pub async fn promote_auto_exclusion(
+        &mut self,
+        host: &str,
+        mut local_exclusion_store: LocalExclusionStore,
+    ) -> ConfigurationResult<()> {
+        self.exclusions.insert(host.to_string());
+
+        self.save().await?;
+
+        local_exclusion_store
+            .replace_exclusions(Vec::from_iter(self.exclusions.clone().into_iter()));
+        local_exclusion_store.remove_auto_exclusion(host).await?;
+
+        Ok(())
+    }
+
     pub async fn set_filter_enabled_status(
         &mut self,
         filter_file_name: &str,
@@ -304,6 +676,20 @@ impl Configuration {
             },
             exclusions: BTreeSet::new(),
             custom_filters: Vec::new(),
+            strip_alt_svc: default_strip_alt_svc(),
+            response_rewrite_rules: Vec::new(),
+            html_rewriter: HtmlRewriterConfiguration::default(),
+            upstream: UpstreamConfiguration::default(),
+            upstream_proxies: Vec::new(),
+            upstream_dns: UpstreamDnsConfiguration::default(),
+            upstream_tls: UpstreamTlsConfiguration::default(),
+            listener: ListenerConfiguration::default(),
+            socks5_listener: Socks5ListenerConfiguration::default(),
+            transparent_proxy: TransparentProxyConfiguration::default(),
+            dns_sinkhole: DnsSinkholeConfiguration::default(),
+            pac: PacConfiguration::default(),
+            connect: ConnectConfiguration::default(),
+            certificates: CertificatesConfiguration::default(),
         })
     }
 }
@@ -321,6 +707,38 @@ async fn get_default_filters(
     Ok(default_filters)
 }
 
+fn get_auto_exclusions_file_path() -> ConfigurationResult<PathBuf> {
+    Ok(get_home_directory()?
+        .join(CONFIGURATION_DIRECTORY_NAME)
+        .join(AUTO_EXCLUSIONS_FILE_NAME))
+}
+
+/// Reads the hosts which were automatically excluded after failed TLS interceptions. They are
+/// stored apart from the configuration file, one per line, as they are updated by the proxy
+/// itself.
+pub async fn read_auto_exclusions() -> ConfigurationResult<BTreeSet<String>> {
+    match fs::read_to_string(get_auto_exclusions_file_path()?).await {
+        Ok(auto_exclusions) => Ok(Configuration::deserialize_lines(&auto_exclusions)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+        Err(err) => Err(ConfigurationError::FileSystemError(err)),
+    }
+}
+
+pub async fn save_auto_exclusions(auto_exclusions: &BTreeSet<String>) -> ConfigurationResult<()> {
+    let auto_exclusions_serialized = Vec::from_iter(auto_exclusions.iter().cloned()).join("\n");
+
+    fs::write(get_auto_exclusions_file_path()?, auto_exclusions_serialized).await?;
+
+    Ok(())
+}
+
+/// Directory minted certificates are persisted in.
+pub fn get_certificates_directory_path() -> ConfigurationResult<PathBuf> {
+    Ok(get_home_directory()?
+        .join(CONFIGURATION_DIRECTORY_NAME)
+        .join(CERTIFICATES_DIRECTORY_NAME))
+}
+
 fn get_home_directory() -> ConfigurationResult<PathBuf> {
     match home_dir() {
         Some(home_directory) => Ok(home_directory),
@@ -401,6 +819,9 @@ impl ConfigurationUpdater {
                 let filters = get_filters_content(&configuration, &self.http_client).await;
 
                 self.adblock_requester.replace_engine(filters).await;
+                self.adblock_requester
+                    .replace_response_rewrite_rules(configuration.response_rewrite_rules.clone())
+                    .await;
 
                 let new_self = Self::new(
                     configuration,
@@ -0,0 +1,422 @@
+//! Just enough of the DNS wire format to read queries' questions and to answer them ourselves, as
+//! well as to look up addresses from upstream resolvers.
+//!
+//! See: https://www.rfc-editor.org/rfc/rfc1035#section-4
+use crate::configuration::BlockedDnsAnswer;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+pub(crate) const HEADER_LENGTH: usize = 12;
+
+pub(crate) const RECORD_TYPE_A: u16 = 1;
+pub(crate) const RECORD_TYPE_AAAA: u16 = 28;
+pub(crate) const RECORD_TYPE_HTTPS: u16 = 65;
+const CLASS_IN: u16 = 1;
+
+const FLAG_RESPONSE: u8 = 0x80;
+const FLAG_OPCODE_MASK: u8 = 0x78;
+const FLAG_RECURSION_DESIRED: u8 = 0x01;
+const FLAG_RECURSION_AVAILABLE: u8 = 0x80;
+
+pub(crate) const RESPONSE_CODE_NO_ERROR: u8 = 0;
+pub(crate) const RESPONSE_CODE_SERVER_FAILURE: u8 = 2;
+const RESPONSE_CODE_NAME_ERROR: u8 = 3;
+const RESPONSE_CODE_MASK: u8 = 0x0f;
+
+// Pointer to the name of the question, which directly follows the header.
+const QUESTION_NAME_POINTER: [u8; 2] = [0xc0, HEADER_LENGTH as u8];
+const BLOCKED_RECORD_TTL: u32 = 60;
+const MAX_LABEL_LENGTH: usize = 63;
+const COMPRESSED_NAME_MASK: u8 = 0xc0;
+
+#[derive(Debug)]
+pub(crate) struct Question {
+    pub(crate) name: String,
+    pub(crate) record_type: u16,
+    // Offset of the end of the question within the query.
+    end: usize,
+}
+
+pub(crate) fn id(message: &[u8]) -> Option<[u8; 2]> {
+    Some([*message.first()?, *message.get(1)?])
+}
+
+/// Parses the first question of a query.
+pub(crate) fn parse_question(query: &[u8]) -> Option<Question> {
+    if query.len() < HEADER_LENGTH || query[2] & FLAG_RESPONSE != 0 {
+        return None;
+    }
+
+    let question_count = u16::from_be_bytes([query[4], query[5]]);
+    if question_count == 0 {
+        return None;
+    }
+
+    let mut labels = Vec::new();
+    let mut offset = HEADER_LENGTH;
+
+    loop {
+        let length = *query.get(offset)? as usize;
+        offset += 1;
+
+        if length == 0 {
+            break;
+        }
+
+        // Queries' questions are not expected to be compressed.
+        if length > MAX_LABEL_LENGTH {
+            return None;
+        }
+
+        let label = query.get(offset..offset + length)?;
+        labels.push(std::str::from_utf8(label).ok()?.to_lowercase());
+        offset += length;
+    }
+
+    let record_type = u16::from_be_bytes([*query.get(offset)?, *query.get(offset + 1)?]);
+    // Class.
+    query.get(offset + 2..offset + 4)?;
+
+    Some(Question {
+        name: labels.join("."),
+        record_type,
+        end: offset + 4,
+    })
+}
+
+fn response_header(query: &[u8], response_code: u8, answer_count: u16) -> Vec<u8> {
+    let mut header = Vec::with_capacity(HEADER_LENGTH);
+
+    header.extend_from_slice(&query[..2]);
+    header.push(FLAG_RESPONSE | (query[2] & (FLAG_OPCODE_MASK | FLAG_RECURSION_DESIRED)));
+    header.push(FLAG_RECURSION_AVAILABLE | response_code);
+    // Question, answer, authority and additional records counts.
+    header.extend_from_slice(&1u16.to_be_bytes());
+    header.extend_from_slice(&answer_count.to_be_bytes());
+    header.extend_from_slice(&0u16.to_be_bytes());
+    header.extend_from_slice(&0u16.to_be_bytes());
+
+    header
+}
+
+/// Answers a query with an error, such as when upstream resolvers can't be reached, or with no
+/// records at all when `response_code` is `RESPONSE_CODE_NO_ERROR`.
+pub(crate) fn error_response(query: &[u8], question: &Question, response_code: u8) -> Vec<u8> {
+    let mut response = response_header(query, response_code, 0);
+    response.extend_from_slice(&query[HEADER_LENGTH..question.end]);
+
+    response
+}
+
+/// Answers a query for a blocked domain.
+pub(crate) fn blocked_response(
+    query: &[u8],
+    question: &Question,
+    blocked_answer: BlockedDnsAnswer,
+) -> Vec<u8> {
+    if blocked_answer == BlockedDnsAnswer::Nxdomain {
+        return error_response(query, question, RESPONSE_CODE_NAME_ERROR);
+    }
+
+    let record_data = match question.record_type {
+        RECORD_TYPE_A => Some(Ipv4Addr::UNSPECIFIED.octets().to_vec()),
+        RECORD_TYPE_AAAA => Some(Ipv6Addr::UNSPECIFIED.octets().to_vec()),
+        // Other record types get an empty answer.
+        _ => None,
+    };
+
+    let mut response = response_header(query, RESPONSE_CODE_NO_ERROR, record_data.is_some() as u16);
+    response.extend_from_slice(&query[HEADER_LENGTH..question.end]);
+
+    if let Some(record_data) = record_data {
+        response.extend_from_slice(&QUESTION_NAME_POINTER);
+        response.extend_from_slice(&question.record_type.to_be_bytes());
+        response.extend_from_slice(&CLASS_IN.to_be_bytes());
+        response.extend_from_slice(&BLOCKED_RECORD_TTL.to_be_bytes());
+        response.extend_from_slice(&(record_data.len() as u16).to_be_bytes());
+        response.extend_from_slice(&record_data);
+    }
+
+    response
+}
+
+/// Builds a recursive query for a single record type.
+pub(crate) fn build_query(id: u16, name: &str, record_type: u16) -> Option<Vec<u8>> {
+    let mut query = Vec::with_capacity(HEADER_LENGTH + name.len() + 6);
+
+    query.extend_from_slice(&id.to_be_bytes());
+    query.push(FLAG_RECURSION_DESIRED);
+    query.push(0);
+    // Question, answer, authority and additional records counts.
+    query.extend_from_slice(&1u16.to_be_bytes());
+    query.extend_from_slice(&[0; 6]);
+
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > MAX_LABEL_LENGTH {
+            return None;
+        }
+
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+
+    query.push(0);
+    query.extend_from_slice(&record_type.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    Some(query)
+}
+
+fn skip_name(message: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let length = *message.get(offset)?;
+
+        if length == 0 {
+            return Some(offset + 1);
+        }
+
+        // Compressed names end with a pointer.
+        if length & COMPRESSED_NAME_MASK == COMPRESSED_NAME_MASK {
+            return Some(offset + 2);
+        }
+
+        offset += 1 + length as usize;
+    }
+}
+
+fn read_u16(message: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([
+        *message.get(offset)?,
+        *message.get(offset + 1)?,
+    ]))
+}
+
+/// Addresses answered by an upstream resolver along with their TTLs. Responses for domains which
+/// don't exist hold no addresses.
+pub(crate) fn parse_addresses(response: &[u8]) -> Option<Vec<(IpAddr, u32)>> {
+    if response.len() < HEADER_LENGTH || response[2] & FLAG_RESPONSE == 0 {
+        return None;
+    }
+
+    match response[3] & RESPONSE_CODE_MASK {
+        RESPONSE_CODE_NO_ERROR => {}
+        RESPONSE_CODE_NAME_ERROR => return Some(Vec::new()),
+        _ => return None,
+    }
+
+    let question_count = read_u16(response, 4)?;
+    let answer_count = read_u16(response, 6)?;
+
+    let mut offset = HEADER_LENGTH;
+
+    for _ in 0..question_count {
+        // Type and class.
+        offset = skip_name(response, offset)? + 4;
+    }
+
+    let mut addresses = Vec::new();
+
+    for _ in 0..answer_count {
+        offset = skip_name(response, offset)?;
+
+        let record_type = read_u16(response, offset)?;
+        let record_ttl = u32::from_be_bytes(response.get(offset + 4..offset + 8)?.try_into().ok()?);
+        let data_length = read_u16(response, offset + 8)? as usize;
+        let data = response.get(offset + 10..offset + 10 + data_length)?;
+
+        offset += 10 + data_length;
+
+        // Other records, such as the CNAMEs leading to addresses, are skipped.
+        let address = match (record_type, data.len()) {
+            (RECORD_TYPE_A, 4) => IpAddr::from(<[u8; 4]>::try_from(data).ok()?),
+            (RECORD_TYPE_AAAA, 16) => IpAddr::from(<[u8; 16]>::try_from(data).ok()?),
+            _ => continue,
+        };
+
+        addresses.push((address, record_ttl));
+    }
+
+    Some(addresses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn question(query: &[u8]) -> Question {
+        parse_question(query).unwrap()
+    }
+
+    /// A response to `query` holding the given records, whose names point to the question's.
+    fn response(query: &[u8], response_code: u8, records: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut response = response_header(query, response_code, records.len() as u16);
+        response.extend_from_slice(&query[HEADER_LENGTH..]);
+
+        for (record_type, data) in records {
+            response.extend_from_slice(&QUESTION_NAME_POINTER);
+            response.extend_from_slice(&record_type.to_be_bytes());
+            response.extend_from_slice(&CLASS_IN.to_be_bytes());
+            response.extend_from_slice(&300u32.to_be_bytes());
+            response.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            response.extend_from_slice(data);
+        }
+
+        response
+    }
+
+    #[test]
+    fn test_parse_question() {
+        let query = build_query(0x1234, "Ads.Example.com.", RECORD_TYPE_AAAA).unwrap();
+        let question = question(&query);
+
+        assert_eq!(id(&query), Some([0x12, 0x34]));
+        assert_eq!(question.name, "ads.example.com");
+        assert_eq!(question.record_type, RECORD_TYPE_AAAA);
+        assert_eq!(question.end, query.len());
+    }
+
+    #[test]
+    fn test_parse_invalid_question() {
+        let query = build_query(1, "example.com", RECORD_TYPE_A).unwrap();
+
+        assert!(parse_question(&query[..HEADER_LENGTH - 1]).is_none());
+        assert!(parse_question(&query[..query.len() - 1]).is_none());
+
+        let mut response = query.clone();
+        response[2] |= FLAG_RESPONSE;
+        assert!(parse_question(&response).is_none());
+
+        let mut no_questions = query.clone();
+        no_questions[5] = 0;
+        assert!(parse_question(&no_questions).is_none());
+
+        let mut compressed = query;
+        compressed.truncate(HEADER_LENGTH);
+        compressed.extend_from_slice(&QUESTION_NAME_POINTER);
+        compressed.extend_from_slice(&[0, 1, 0, 1]);
+        assert!(parse_question(&compressed).is_none());
+    }
+
+    #[test]
+    fn test_build_query() {
+        assert!(build_query(1, "example..com", RECORD_TYPE_A).is_none());
+        assert!(build_query(1, &format!("{}.com", "a".repeat(64)), RECORD_TYPE_A).is_none());
+
+        let query = build_query(1, "example.com", RECORD_TYPE_A).unwrap();
+
+        assert_eq!(query[2], FLAG_RECURSION_DESIRED);
+        assert_eq!(
+            &query[HEADER_LENGTH..],
+            b"\x07example\x03com\x00\x00\x01\x00\x01"
+        );
+    }
+
+    #[test]
+    fn test_blocked_response() {
+        let query = build_query(0xabcd, "ads.example.com", RECORD_TYPE_A).unwrap();
+        let response = blocked_response(
+            &query,
+            &question(&query),
+            BlockedDnsAnswer::UnspecifiedAddress,
+        );
+
+        assert_eq!(id(&response), Some([0xab, 0xcd]));
+        assert_eq!(
+            response[2],
+            FLAG_RESPONSE | FLAG_RECURSION_DESIRED,
+            "the query's flags are kept"
+        );
+        assert_eq!(
+            parse_addresses(&response).unwrap(),
+            [(IpAddr::from(Ipv4Addr::UNSPECIFIED), BLOCKED_RECORD_TTL)]
+        );
+
+        let query = build_query(1, "ads.example.com", RECORD_TYPE_AAAA).unwrap();
+        let response = blocked_response(
+            &query,
+            &question(&query),
+            BlockedDnsAnswer::UnspecifiedAddress,
+        );
+
+        assert_eq!(
+            parse_addresses(&response).unwrap(),
+            [(IpAddr::from(Ipv6Addr::UNSPECIFIED), BLOCKED_RECORD_TTL)]
+        );
+    }
+
+    #[test]
+    fn test_blocked_response_without_address() {
+        let query = build_query(1, "ads.example.com", RECORD_TYPE_HTTPS).unwrap();
+        let response = blocked_response(
+            &query,
+            &question(&query),
+            BlockedDnsAnswer::UnspecifiedAddress,
+        );
+
+        assert_eq!(response[3] & RESPONSE_CODE_MASK, RESPONSE_CODE_NO_ERROR);
+        assert_eq!(read_u16(&response, 6), Some(0));
+        assert_eq!(&response[HEADER_LENGTH..], &query[HEADER_LENGTH..]);
+
+        let query = build_query(1, "ads.example.com", RECORD_TYPE_A).unwrap();
+        let response = blocked_response(&query, &question(&query), BlockedDnsAnswer::Nxdomain);
+
+        assert_eq!(response[3] & RESPONSE_CODE_MASK, RESPONSE_CODE_NAME_ERROR);
+        assert_eq!(parse_addresses(&response).unwrap(), []);
+    }
+
+    #[test]
+    fn test_error_response() {
+        let mut query = build_query(1, "example.com", RECORD_TYPE_A).unwrap();
+        // An EDNS record, which isn't echoed back.
+        query[11] = 1;
+        query.extend_from_slice(&[0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 0]);
+
+        let response = error_response(&query, &question(&query), RESPONSE_CODE_SERVER_FAILURE);
+
+        assert_eq!(
+            response[3],
+            FLAG_RECURSION_AVAILABLE | RESPONSE_CODE_SERVER_FAILURE
+        );
+        assert_eq!(read_u16(&response, 10), Some(0));
+        assert_eq!(response.len(), query.len() - 11);
+        assert!(parse_addresses(&response).is_none());
+    }
+
+    #[test]
+    fn test_parse_addresses() {
+        let query = build_query(1, "www.example.com", RECORD_TYPE_A).unwrap();
+        let response = response(
+            &query,
+            RESPONSE_CODE_NO_ERROR,
+            &[
+                // CNAME towards example.com, compressed.
+                (5, &[0xc0, 16]),
+                (RECORD_TYPE_A, &[93, 184, 216, 34]),
+                (RECORD_TYPE_AAAA, &Ipv6Addr::LOCALHOST.octets()),
+                // A records of the wrong size are skipped.
+                (RECORD_TYPE_A, &[1, 2, 3]),
+            ],
+        );
+
+        assert_eq!(
+            parse_addresses(&response).unwrap(),
+            [
+                (IpAddr::from([93, 184, 216, 34]), 300),
+                (IpAddr::from(Ipv6Addr::LOCALHOST), 300)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_addresses() {
+        let query = build_query(1, "example.com", RECORD_TYPE_A).unwrap();
+
+        assert!(parse_addresses(&query).is_none());
+
+        let response = response(
+            &query,
+            RESPONSE_CODE_NO_ERROR,
+            &[(RECORD_TYPE_A, &[1, 2, 3, 4])],
+        );
+        assert!(parse_addresses(&response[..response.len() - 1]).is_none());
+    }
+}
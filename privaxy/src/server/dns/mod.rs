@@ -0,0 +1,3 @@
+pub(crate) mod message;
+pub mod resolver;
+pub(crate) mod sinkhole;
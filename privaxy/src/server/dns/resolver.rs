@@ -0,0 +1,642 @@
+//! Resolution of upstream host names, either through the system resolver or through an encrypted
+//! resolver, so that visited hosts aren't leaked through plaintext DNS.
+//!
+//! Encrypted resolvers are either DNS-over-HTTPS endpoints, such as
+//! `https://cloudflare-dns.com/dns-query`, or DNS-over-TLS servers, such as
+//! `tls://one.one.one.one`.
+//!
+//! See: https://www.rfc-editor.org/rfc/rfc8484 and https://www.rfc-editor.org/rfc/rfc7858
+use super::message::{build_query, id, parse_addresses, RECORD_TYPE_A, RECORD_TYPE_AAAA};
+use crate::configuration::UpstreamDnsConfiguration;
+use crate::trust_store::TrustStore;
+use hyper::client::connect::dns::Name;
+use rustls::{ClientConfig, ServerName};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::{client::TlsStream, TlsConnector};
+use url::Url;
+
+const SYSTEM: &str = "system";
+const DNS_OVER_TLS_PORT: u16 = 853;
+const DNS_MESSAGE_CONTENT_TYPE: &str = "application/dns-message";
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+const MIN_CACHE_TTL: Duration = Duration::from_secs(30);
+const MAX_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+const MAX_CACHED_HOSTS: usize = 4096;
+
+#[derive(Debug)]
+enum Transport {
+    Https {
+        client: reqwest::Client,
+        url: Url,
+    },
+    Tls {
+        server_name: ServerName,
+        host: String,
+        port: u16,
+        tls_configuration: Arc<ClientConfig>,
+    },
+}
+
+#[derive(Debug)]
+struct CachedAddresses {
+    addresses: Vec<IpAddr>,
+    expires_at: Instant,
+}
+
+#[derive(Debug)]
+struct EncryptedResolver {
+    transport: Transport,
+    // Addresses of the resolver itself, resolved by the system resolver when empty.
+    bootstrap_addresses: Vec<IpAddr>,
+    // Connection to a DNS-over-TLS resolver, kept open in between lookups.
+    idle_connection: Mutex<Option<TlsStream<TcpStream>>>,
+    cache: Mutex<HashMap<String, CachedAddresses>>,
+}
+
+/// Resolves the host names of upstream servers. Host names are handed to the system resolver
+/// unless an encrypted resolver is configured.
+#[derive(Debug, Clone, Default)]
+pub struct UpstreamResolver {
+    encrypted_resolver: Option<Arc<EncryptedResolver>>,
+}
+
+impl UpstreamResolver {
+    pub fn new(configuration: &UpstreamDnsConfiguration, trust_store: &TrustStore) -> Self {
+        if configuration.resolver.eq_ignore_ascii_case(SYSTEM) {
+            return Self::default();
+        }
+
+        let transport = match Transport::parse(
+            &configuration.resolver,
+            &configuration.bootstrap_addresses,
+            trust_store,
+        ) {
+            Some(transport) => transport,
+            None => {
+                log::warn!(
+                    "Ignoring invalid upstream dns resolver, using the system resolver: {}",
+                    configuration.resolver
+                );
+                return Self::default();
+            }
+        };
+
+        Self {
+            encrypted_resolver: Some(Arc::new(EncryptedResolver {
+                transport,
+                bootstrap_addresses: configuration.bootstrap_addresses.clone(),
+                idle_connection: Mutex::new(None),
+                cache: Mutex::new(HashMap::new()),
+            })),
+        }
+    }
+
+    /// Looks up the addresses of `host`. IP addresses are returned as is.
+    pub async fn lookup(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        if let Ok(ip_address) = host.parse::<IpAddr>() {
+            return Ok(vec![ip_address]);
+        }
+
+        let addresses = match &self.encrypted_resolver {
+            Some(encrypted_resolver) => encrypted_resolver.lookup(host).await?,
+            None => tokio::net::lookup_host((host, 0))
+                .await?
+                .map(|address| address.ip())
+                .collect(),
+        };
+
+        if addresses.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no addresses found for {}", host),
+            ));
+        }
+
+        Ok(addresses)
+    }
+
+    /// Connects to `host`, trying its addresses in turn.
+    pub async fn connect(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        let mut last_error = None;
+
+        for address in self.lookup(host).await? {
+            match TcpStream::connect((address, port)).await {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_error = Some(err),
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| io::ErrorKind::NotFound.into()))
+    }
+}
+
+impl reqwest::dns::Resolve for UpstreamResolver {
+    fn resolve(&self, name: Name) -> reqwest::dns::Resolving {
+        let resolver = self.clone();
+
+        Box::pin(async move {
+            let addresses: reqwest::dns::Addrs = Box::new(
+                resolver
+                    .lookup(name.as_str())
+                    .await?
+                    .into_iter()
+                    .map(|address| SocketAddr::new(address, 0)),
+            );
+
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(addresses)
+        })
+    }
+}
+
+impl Transport {
+    fn parse(
+        resolver: &str,
+        bootstrap_addresses: &[IpAddr],
+        trust_store: &TrustStore,
+    ) -> Option<Self> {
+        let url = Url::parse(resolver).ok()?;
+        let host = url
+            .host_str()?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+
+        match url.scheme() {
+            "https" => {
+                let mut client_builder = trust_store.configure_reqwest(
+                    reqwest::Client::builder()
+                        .no_proxy()
+                        .timeout(LOOKUP_TIMEOUT),
+                );
+
+                if !bootstrap_addresses.is_empty() {
+                    let addresses = bootstrap_addresses
+                        .iter()
+                        .map(|address| SocketAddr::new(*address, 0))
+                        .collect::<Vec<_>>();
+
+                    client_builder = client_builder.resolve_to_addrs(&host, &addresses);
+                }
+
+                Some(Self::Https {
+                    client: client_builder.build().ok()?,
+                    url,
+                })
+            }
+            "tls" => Some(Self::Tls {
+                server_name: ServerName::try_from(host.as_str()).ok()?,
+                port: url.port().unwrap_or(DNS_OVER_TLS_PORT),
+                host,
+                tls_configuration: Arc::new(trust_store.client_configuration()),
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl EncryptedResolver {
+    async fn lookup(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        let host = host.trim_end_matches('.').to_lowercase();
+
+        if let Some(cached) = self.cache.lock().unwrap().get(&host) {
+            if cached.expires_at > Instant::now() {
+                return Ok(cached.addresses.clone());
+            }
+        }
+
+        let mut records = Vec::new();
+
+        for response in self.exchange(&host).await? {
+            records.extend(parse_addresses(&response).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid dns response for {}", host),
+                )
+            })?);
+        }
+
+        // Hosts without addresses are cached for the minimum duration.
+        let ttl = records
+            .iter()
+            .map(|(_address, ttl)| *ttl)
+            .min()
+            .unwrap_or(0);
+        let ttl = Duration::from_secs(ttl as u64).clamp(MIN_CACHE_TTL, MAX_CACHE_TTL);
+
+        let addresses = records
+            .into_iter()
+            .map(|(address, _ttl)| address)
+            .collect::<Vec<_>>();
+
+        let mut cache = self.cache.lock().unwrap();
+        let now = Instant::now();
+
+        if cache.len() >= MAX_CACHED_HOSTS {
+            cache.retain(|_host, cached| cached.expires_at > now);
+
+            if cache.len() >= MAX_CACHED_HOSTS {
+                cache.clear();
+            }
+        }
+
+        cache.insert(
+            host,
+            CachedAddresses {
+                addresses: addresses.clone(),
+                expires_at: now + ttl,
+            },
+        );
+
+        Ok(addresses)
+    }
+
+    /// Queries the A and AAAA records of `host`, returning the responses.
+    async fn exchange(&self, host: &str) -> io::Result<Vec<Vec<u8>>> {
+        // Encrypted transports don't need random ids, see RFC 8484 section 4.1. Queries sent over
+        // the same connection still need ids of their own to be told apart.
+        let queries = [RECORD_TYPE_A, RECORD_TYPE_AAAA]
+            .iter()
+            .enumerate()
+            .map(|(index, record_type)| build_query(index as u16, host, *record_type))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid host: {}", host),
+                )
+            })?;
+
+        match &self.transport {
+            Transport::Https { client, url } => {
+                // Requests share the client's connections.
+                futures::future::try_join_all(queries.into_iter().map(|query| async move {
+                    Ok::<_, io::Error>(
+                        client
+                            .post(url.clone())
+                            .header(reqwest::header::CONTENT_TYPE, DNS_MESSAGE_CONTENT_TYPE)
+                            .header(reqwest::header::ACCEPT, DNS_MESSAGE_CONTENT_TYPE)
+                            .body(query)
+                            .send()
+                            .await
+                            .and_then(|response| response.error_for_status())
+                            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?
+                            .bytes()
+                            .await
+                            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?
+                            .to_vec(),
+                    )
+                }))
+                .await
+            }
+            Transport::Tls {
+                server_name,
+                host: resolver_host,
+                port,
+                tls_configuration,
+            } => tokio::time::timeout(LOOKUP_TIMEOUT, async {
+                let idle_connection = self.idle_connection.lock().unwrap().take();
+
+                // Resolvers close connections they consider idle, in which case queries are sent
+                // again over a new connection.
+                if let Some(mut stream) = idle_connection {
+                    if let Ok(responses) = exchange_over_tls(&mut stream, &queries).await {
+                        *self.idle_connection.lock().unwrap() = Some(stream);
+
+                        return Ok(responses);
+                    }
+                }
+
+                let tcp_stream = if self.bootstrap_addresses.is_empty() {
+                    TcpStream::connect((resolver_host.as_str(), *port)).await?
+                } else {
+                    TcpStream::connect(
+                        &self
+                            .bootstrap_addresses
+                            .iter()
+                            .map(|address| SocketAddr::new(*address, *port))
+                            .collect::<Vec<_>>()[..],
+                    )
+                    .await?
+                };
+
+                let mut stream = TlsConnector::from(tls_configuration.clone())
+                    .connect(server_name.clone(), tcp_stream)
+                    .await?;
+
+                let responses = exchange_over_tls(&mut stream, &queries).await?;
+
+                // Only a single connection is kept when lookups run concurrently.
+                *self.idle_connection.lock().unwrap() = Some(stream);
+
+                Ok(responses)
+            })
+            .await
+            .unwrap_or_else(|_elapsed| Err(io::ErrorKind::TimedOut.into())),
+        }
+    }
+}
+
+/// Sends `queries` at once over a DNS-over-TLS connection, returning their responses in the same
+/// order.
+async fn exchange_over_tls<S>(stream: &mut S, queries: &[Vec<u8>]) -> io::Result<Vec<Vec<u8>>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Messages sent over TCP are prefixed with their length.
+    let mut framed_queries = Vec::new();
+
+    for query in queries {
+        framed_queries.extend_from_slice(&(query.len() as u16).to_be_bytes());
+        framed_queries.extend_from_slice(query);
+    }
+
+    stream.write_all(&framed_queries).await?;
+
+    let mut responses = vec![None; queries.len()];
+
+    // Responses may come in any order, see RFC 7766 section 7.
+    for _ in queries {
+        let mut response = vec![0; stream.read_u16().await? as usize];
+        stream.read_exact(&mut response).await?;
+
+        let index = queries
+            .iter()
+            .position(|query| id(query) == id(&response))
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "unexpected dns response id")
+            })?;
+
+        responses[index] = Some(response);
+    }
+
+    responses
+        .into_iter()
+        .map(|response| {
+            response.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "duplicated dns response id")
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ca::make_ca_certificate,
+        cert::CertCache,
+        configuration::{BlockedDnsAnswer, CertificatesConfiguration, UpstreamTlsConfiguration},
+        dns::message::{blocked_response, parse_question},
+    };
+    use http::uri::Authority;
+    use hyper::{service::service_fn, Body, Request, Response};
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    static TRUST_STORES: AtomicUsize = AtomicUsize::new(0);
+
+    /// Answers queries the way the sinkhole answers queries for blocked domains.
+    fn answer(query: &[u8]) -> Vec<u8> {
+        blocked_response(
+            query,
+            &parse_question(query).unwrap(),
+            BlockedDnsAnswer::UnspecifiedAddress,
+        )
+    }
+
+    fn answered_addresses() -> Vec<IpAddr> {
+        vec![
+            IpAddr::from(Ipv4Addr::UNSPECIFIED),
+            IpAddr::from(Ipv6Addr::UNSPECIFIED),
+        ]
+    }
+
+    /// A certificate for `localhost`, along with a trust store trusting it.
+    async fn localhost_certificate() -> (TlsAcceptor, TrustStore) {
+        let (ca_certificate, ca_private_key) = make_ca_certificate();
+
+        let path = std::env::temp_dir().join(format!(
+            "privaxy-resolver-test-{}-{}.pem",
+            std::process::id(),
+            TRUST_STORES.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::write(&path, ca_certificate.to_pem().unwrap()).unwrap();
+
+        let trust_store = TrustStore::new(&UpstreamTlsConfiguration {
+            native_roots: false,
+            bundled_roots: false,
+            extra_root_certificates: vec![path.clone()],
+            ..Default::default()
+        });
+        std::fs::remove_file(&path).unwrap();
+
+        let cert_cache = CertCache::new(
+            ca_certificate,
+            ca_private_key,
+            &CertificatesConfiguration {
+                persist: false,
+                ..Default::default()
+            },
+            None,
+            None,
+        );
+        let certificate = cert_cache.get(Authority::from_static("localhost")).await;
+
+        (
+            TlsAcceptor::from(Arc::new(certificate.server_configuration)),
+            trust_store,
+        )
+    }
+
+    fn encrypted_resolver(resolver: String, trust_store: &TrustStore) -> UpstreamResolver {
+        UpstreamResolver::new(
+            &UpstreamDnsConfiguration {
+                resolver,
+                bootstrap_addresses: vec![IpAddr::from(Ipv4Addr::LOCALHOST)],
+            },
+            trust_store,
+        )
+    }
+
+    /// A DNS-over-TLS resolver answering pairs of queries in reverse order, closing connections
+    /// after each pair unless `keep_open` is set. Returns its port along with the number of
+    /// connections it accepted.
+    async fn dns_over_tls_resolver(
+        tls_acceptor: TlsAcceptor,
+        keep_open: bool,
+    ) -> (u16, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted_connections = connections.clone();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _client_address)) = listener.accept().await {
+                accepted_connections.fetch_add(1, Ordering::SeqCst);
+                let tls_acceptor = tls_acceptor.clone();
+
+                tokio::spawn(async move {
+                    let mut stream = tls_acceptor.accept(stream).await?;
+
+                    loop {
+                        let mut queries = Vec::new();
+
+                        for _ in 0..2 {
+                            let mut query = vec![0; stream.read_u16().await? as usize];
+                            stream.read_exact(&mut query).await?;
+                            queries.push(query);
+                        }
+
+                        for query in queries.iter().rev() {
+                            let response = answer(query);
+                            stream.write_u16(response.len() as u16).await?;
+                            stream.write_all(&response).await?;
+                        }
+
+                        if !keep_open {
+                            return stream.shutdown().await;
+                        }
+                    }
+                });
+            }
+        });
+
+        (port, connections)
+    }
+
+    /// A DNS-over-HTTPS resolver, answering at `/dns-query`.
+    async fn dns_over_https_resolver(tls_acceptor: TlsAcceptor) -> u16 {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _client_address)) = listener.accept().await {
+                let tls_acceptor = tls_acceptor.clone();
+
+                tokio::spawn(async move {
+                    let stream = tls_acceptor.accept(stream).await?;
+
+                    let _result = hyper::server::conn::Http::new()
+                        .serve_connection(
+                            stream,
+                            service_fn(|request: Request<Body>| async move {
+                                let is_dns_query = request.method() == hyper::Method::POST
+                                    && request.uri().path() == "/dns-query"
+                                    && request.headers()[hyper::header::CONTENT_TYPE]
+                                        == DNS_MESSAGE_CONTENT_TYPE;
+
+                                if !is_dns_query {
+                                    return Response::builder()
+                                        .status(hyper::StatusCode::BAD_REQUEST)
+                                        .body(Body::empty());
+                                }
+
+                                let query =
+                                    hyper::body::to_bytes(request.into_body()).await.unwrap();
+
+                                Response::builder()
+                                    .header(hyper::header::CONTENT_TYPE, DNS_MESSAGE_CONTENT_TYPE)
+                                    .body(Body::from(answer(&query)))
+                            }),
+                        )
+                        .await;
+
+                    Ok::<_, io::Error>(())
+                });
+            }
+        });
+
+        port
+    }
+
+    #[tokio::test]
+    async fn test_ip_addresses_not_looked_up() {
+        let upstream_resolver =
+            encrypted_resolver("tls://localhost:1".to_string(), &TrustStore::default());
+
+        assert_eq!(
+            upstream_resolver.lookup("[::1]").await.unwrap(),
+            [IpAddr::from(Ipv6Addr::LOCALHOST)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dns_over_tls() {
+        let (tls_acceptor, trust_store) = localhost_certificate().await;
+        let (port, connections) = dns_over_tls_resolver(tls_acceptor, true).await;
+        let upstream_resolver =
+            encrypted_resolver(format!("tls://localhost:{}", port), &trust_store);
+
+        assert_eq!(
+            upstream_resolver.lookup("example.com").await.unwrap(),
+            answered_addresses()
+        );
+        assert_eq!(
+            upstream_resolver.lookup("example.org").await.unwrap(),
+            answered_addresses()
+        );
+
+        // Both lookups, of both record types, went through a single connection.
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_dns_over_tls_reconnects() {
+        let (tls_acceptor, trust_store) = localhost_certificate().await;
+        let (port, connections) = dns_over_tls_resolver(tls_acceptor, false).await;
+        let upstream_resolver =
+            encrypted_resolver(format!("tls://localhost:{}", port), &trust_store);
+
+        for host in ["example.com", "example.org"] {
+            assert_eq!(
+                upstream_resolver.lookup(host).await.unwrap(),
+                answered_addresses()
+            );
+        }
+
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_dns_over_tls_untrusted_certificate() {
+        let (tls_acceptor, _trust_store) = localhost_certificate().await;
+        let (port, _connections) = dns_over_tls_resolver(tls_acceptor, true).await;
+        let (_tls_acceptor, trust_store) = localhost_certificate().await;
+        let upstream_resolver =
+            encrypted_resolver(format!("tls://localhost:{}", port), &trust_store);
+
+        assert!(upstream_resolver.lookup("example.com").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dns_over_https() {
+        let (tls_acceptor, trust_store) = localhost_certificate().await;
+        let port = dns_over_https_resolver(tls_acceptor).await;
+        let upstream_resolver = encrypted_resolver(
+            format!("https://localhost:{}/dns-query", port),
+            &trust_store,
+        );
+
+        assert_eq!(
+            upstream_resolver.lookup("Example.com.").await.unwrap(),
+            answered_addresses()
+        );
+
+        let upstream_resolver = encrypted_resolver(
+            format!("https://localhost:{}/not-dns-query", port),
+            &trust_store,
+        );
+        assert!(upstream_resolver.lookup("example.com").await.is_err());
+    }
+}
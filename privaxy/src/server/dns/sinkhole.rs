@@ -0,0 +1,365 @@
+//! A DNS server answering queries for domains blocked as a whole by filters itself, and
+//! forwarding other queries to an upstream resolver.
+//!
+//! Devices which can't be configured to use the proxy still get ads and trackers blocked by
+//! pointing their resolver at Privaxy.
+use super::message::{
+    blocked_response, error_response, id, parse_question, RECORD_TYPE_HTTPS,
+    RESPONSE_CODE_NO_ERROR, RESPONSE_CODE_SERVER_FAILURE,
+};
+use crate::{
+    blocker::AdblockRequester, configuration::DnsSinkholeConfiguration, events::Event,
+    statistics::Statistics,
+};
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::broadcast,
+};
+
+// The largest UDP payload. Queries are forwarded as is, upstream resolvers size their responses
+// after the EDNS buffer size clients advertise, truncating them when needed.
+const MAX_UDP_MESSAGE_LENGTH: usize = 65535;
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transport {
+    Udp,
+    Tcp,
+}
+
+#[derive(Clone)]
+struct Sinkhole {
+    configuration: DnsSinkholeConfiguration,
+    strip_quic_alternatives: bool,
+    adblock_requester: AdblockRequester,
+    broadcast_tx: broadcast::Sender<Event>,
+    statistics: Statistics,
+}
+
+impl Sinkhole {
+    /// Answers a query, returning `None` when it isn't worth answering.
+    async fn resolve(&self, query: &[u8], transport: Transport) -> Option<Vec<u8>> {
+        let question = parse_question(query)?;
+
+        self.statistics.increment_dns_queries();
+
+        let is_blocked = self
+            .adblock_requester
+            .is_domain_blocked(question.name.clone())
+            .await;
+
+        let _result = self.broadcast_tx.send(Event {
+            now: chrono::Utc::now(),
+            method: "DNS".to_string(),
+            url: question.name.clone(),
+            is_request_blocked: is_blocked,
+        });
+
+        if is_blocked {
+            self.statistics.increment_blocked_dns_queries();
+
+            return Some(blocked_response(
+                query,
+                &question,
+                self.configuration.blocked_answer,
+            ));
+        }
+
+        // HTTPS records may advertise HTTP/3 support, the same way `Alt-Svc` headers do. Rather
+        // than rewriting their parameters, we answer that there are none. Browsers then connect
+        // using the addresses of A and AAAA records, although features such as Encrypted Client
+        // Hello, which also rely on HTTPS records, are lost.
+        if self.strip_quic_alternatives && question.record_type == RECORD_TYPE_HTTPS {
+            return Some(error_response(query, &question, RESPONSE_CODE_NO_ERROR));
+        }
+
+        let upstream_resolver = self.configuration.upstream_resolver;
+
+        let response = tokio::time::timeout(UPSTREAM_TIMEOUT, async {
+            match transport {
+                Transport::Udp => forward_udp(upstream_resolver, query).await,
+                Transport::Tcp => forward_tcp(upstream_resolver, query).await,
+            }
+        })
+        .await
+        .unwrap_or_else(|_elapsed| Err(io::ErrorKind::TimedOut.into()));
+
+        match response {
+            Ok(response) => Some(response),
+            Err(err) => {
+                log::debug!(
+                    "Unable to forward dns query for {} to {}: {}",
+                    question.name,
+                    upstream_resolver,
+                    err
+                );
+
+                Some(error_response(
+                    query,
+                    &question,
+                    RESPONSE_CODE_SERVER_FAILURE,
+                ))
+            }
+        }
+    }
+
+    async fn serve_tcp_connection(self, mut stream: TcpStream) {
+        loop {
+            let query =
+                match tokio::time::timeout(TCP_IDLE_TIMEOUT, read_tcp_message(&mut stream)).await {
+                    Ok(Ok(query)) => query,
+                    Ok(Err(_)) | Err(_) => return,
+                };
+
+            let response = match self.resolve(&query, Transport::Tcp).await {
+                Some(response) => response,
+                None => return,
+            };
+
+            if write_tcp_message(&mut stream, &response).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+async fn forward_udp(upstream_resolver: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let bind_address: SocketAddr = match upstream_resolver {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+
+    let socket = UdpSocket::bind(bind_address).await?;
+    socket.connect(upstream_resolver).await?;
+    socket.send(query).await?;
+
+    let mut buffer = vec![0; MAX_UDP_MESSAGE_LENGTH];
+
+    // Responses which don't match the query are ignored.
+    loop {
+        let read = socket.recv(&mut buffer).await?;
+
+        if id(&buffer[..read]) == id(query) {
+            return Ok(buffer[..read].to_vec());
+        }
+    }
+}
+
+async fn forward_tcp(upstream_resolver: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(upstream_resolver).await?;
+
+    write_tcp_message(&mut stream, query).await?;
+    read_tcp_message(&mut stream).await
+}
+
+/// Messages sent over TCP are prefixed with their length.
+async fn read_tcp_message(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let length = stream.read_u16().await?;
+    let mut message = vec![0; length as usize];
+    stream.read_exact(&mut message).await?;
+
+    Ok(message)
+}
+
+async fn write_tcp_message(stream: &mut TcpStream, message: &[u8]) -> io::Result<()> {
+    let length = u16::try_from(message.len())
+        .map_err(|_err| io::Error::new(io::ErrorKind::InvalidData, "dns message too long"))?;
+
+    let mut framed_message = Vec::with_capacity(2 + message.len());
+    framed_message.extend_from_slice(&length.to_be_bytes());
+    framed_message.extend_from_slice(message);
+
+    stream.write_all(&framed_message).await
+}
+
+/// Starts answering queries over both UDP and TCP.
+pub(crate) async fn start(
+    configuration: DnsSinkholeConfiguration,
+    strip_quic_alternatives: bool,
+    adblock_requester: AdblockRequester,
+    broadcast_tx: broadcast::Sender<Event>,
+    statistics: Statistics,
+) -> io::Result<()> {
+    let address = SocketAddr::from((configuration.address, configuration.port));
+
+    let udp_socket = Arc::new(UdpSocket::bind(address).await?);
+    let tcp_listener = TcpListener::bind(address).await?;
+
+    let sinkhole = Sinkhole {
+        configuration,
+        strip_quic_alternatives,
+        adblock_requester,
+        broadcast_tx,
+        statistics,
+    };
+
+    let udp_sinkhole = sinkhole.clone();
+
+    tokio::spawn(async move {
+        let mut buffer = vec![0; MAX_UDP_MESSAGE_LENGTH];
+
+        loop {
+            let (read, client_address) = match udp_socket.recv_from(&mut buffer).await {
+                Ok(received) => received,
+                Err(err) => {
+                    log::debug!("Unable to receive dns query: {}", err);
+                    continue;
+                }
+            };
+
+            let query = buffer[..read].to_vec();
+            let udp_socket = udp_socket.clone();
+            let sinkhole = udp_sinkhole.clone();
+
+            tokio::spawn(async move {
+                if let Some(response) = sinkhole.resolve(&query, Transport::Udp).await {
+                    let _result = udp_socket.send_to(&response, client_address).await;
+                }
+            });
+        }
+    });
+
+    tokio::spawn(async move {
+        loop {
+            match tcp_listener.accept().await {
+                Ok((stream, _client_address)) => {
+                    tokio::spawn(sinkhole.clone().serve_tcp_connection(stream));
+                }
+                Err(err) => log::error!("Unable to accept dns connection: {}", err),
+            }
+        }
+    });
+
+    log::info!("DNS sinkhole available at {}", address);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        blocker::{BlockerRequest, BlockerResult, RequestKind},
+        dns::message::{build_query, parse_addresses, RECORD_TYPE_A},
+        domain_filters::DomainFilterSet,
+    };
+    use std::net::{IpAddr, Ipv4Addr};
+
+    const RECORD_TYPE_TXT: u16 = 16;
+
+    fn sinkhole(upstream_resolver: SocketAddr) -> Sinkhole {
+        let (blocker_sender, blocker_receiver) = crossbeam_channel::unbounded::<BlockerRequest>();
+
+        std::thread::spawn(move || {
+            let domain_filter_set = DomainFilterSet::new(&["||ads.example.com^".to_string()]);
+
+            for request in blocker_receiver {
+                if let RequestKind::Domain(domain) = request.kind {
+                    let _result = request
+                        .respond_to
+                        .send(BlockerResult::Domain(domain_filter_set.is_blocked(&domain)));
+                }
+            }
+        });
+
+        Sinkhole {
+            configuration: DnsSinkholeConfiguration {
+                upstream_resolver,
+                ..Default::default()
+            },
+            strip_quic_alternatives: true,
+            adblock_requester: AdblockRequester::new(blocker_sender),
+            broadcast_tx: broadcast::channel(16).0,
+            statistics: Statistics::new(),
+        }
+    }
+
+    /// A resolver answering every query with `record_count` TXT records of 255 bytes.
+    async fn upstream_resolver(record_count: u16) -> SocketAddr {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let address = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buffer = vec![0; MAX_UDP_MESSAGE_LENGTH];
+
+            while let Ok((read, client_address)) = socket.recv_from(&mut buffer).await {
+                let mut response = buffer[..read].to_vec();
+                response[2] |= 0x80;
+                response[6..8].copy_from_slice(&record_count.to_be_bytes());
+
+                for _ in 0..record_count {
+                    response.extend_from_slice(&[0xc0, 12]);
+                    response.extend_from_slice(&RECORD_TYPE_TXT.to_be_bytes());
+                    // Class, TTL and length of the data, a single string of 255 bytes.
+                    response.extend_from_slice(&[0, 1]);
+                    response.extend_from_slice(&300u32.to_be_bytes());
+                    response.extend_from_slice(&256u16.to_be_bytes());
+                    response.push(255);
+                    response.extend_from_slice(&[b'a'; 255]);
+                }
+
+                let _result = socket.send_to(&response, client_address).await;
+            }
+        });
+
+        address
+    }
+
+    #[tokio::test]
+    async fn test_blocked_query() {
+        let sinkhole = sinkhole(upstream_resolver(0).await);
+        let query = build_query(1, "cdn.ads.example.com", RECORD_TYPE_A).unwrap();
+
+        let response = sinkhole.resolve(&query, Transport::Udp).await.unwrap();
+
+        assert_eq!(
+            parse_addresses(&response).unwrap(),
+            [(IpAddr::from(Ipv4Addr::UNSPECIFIED), 60)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_large_response() {
+        let sinkhole = sinkhole(upstream_resolver(64).await);
+        let query = build_query(1, "example.com", RECORD_TYPE_TXT).unwrap();
+
+        let response = sinkhole.resolve(&query, Transport::Udp).await.unwrap();
+
+        // Larger than what most clients advertise, but what the upstream resolver answered.
+        assert_eq!(response.len(), query.len() + 64 * (13 + 255));
+        assert_eq!(id(&response), id(&query));
+    }
+
+    #[tokio::test]
+    async fn test_https_query_stripped() {
+        let sinkhole = sinkhole(upstream_resolver(1).await);
+        let query = build_query(1, "example.com", RECORD_TYPE_HTTPS).unwrap();
+
+        let response = sinkhole.resolve(&query, Transport::Udp).await.unwrap();
+
+        assert_eq!(response[3] & 0x0f, RESPONSE_CODE_NO_ERROR);
+        assert_eq!(&response[6..8], &[0, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_upstream_resolver() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let sinkhole = sinkhole(listener.local_addr().unwrap());
+        drop(listener);
+
+        let query = build_query(1, "example.com", RECORD_TYPE_A).unwrap();
+        let response = sinkhole.resolve(&query, Transport::Tcp).await.unwrap();
+
+        assert_eq!(response[3] & 0x0f, RESPONSE_CODE_SERVER_FAILURE);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_query_ignored() {
+        let sinkhole = sinkhole(upstream_resolver(0).await);
+
+        assert!(sinkhole.resolve(&[0; 4], Transport::Udp).await.is_none());
+    }
+}
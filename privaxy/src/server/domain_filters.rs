@@ -0,0 +1,246 @@
+//! Domains blocked as a whole by network filters such as `||example.com^`, used by the DNS
+//! sinkhole.
+//!
+//! Filters with options or paths only block some requests towards a domain, they can't be
+//! enforced at the DNS level and are ignored. Exceptions are honored whatever their options or
+//! paths, the domain being reachable for at least some requests, and so are `$badfilter` filters,
+//! which cancel the identical filter.
+use std::collections::HashSet;
+
+const DOMAIN_FILTER_PREFIX: &str = "||";
+const EXCEPTION_PREFIX: &str = "@@";
+const DOMAIN_FILTER_SUFFIX: &str = "^";
+const OPTIONS_SEPARATOR: char = '$';
+const BADFILTER_OPTION: &str = "badfilter";
+
+/// A `||domain` filter, split into its domain and what follows it.
+struct DomainFilter<'a> {
+    is_exception: bool,
+    domain: String,
+    pattern: &'a str,
+    options: Vec<&'a str>,
+}
+
+impl<'a> DomainFilter<'a> {
+    fn parse(line: &'a str) -> Option<Self> {
+        let (is_exception, filter) = match line.strip_prefix(EXCEPTION_PREFIX) {
+            Some(filter) => (true, filter),
+            None => (false, line),
+        };
+        let filter = filter.strip_prefix(DOMAIN_FILTER_PREFIX)?;
+
+        let domain_length = filter
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_'))
+            .unwrap_or(filter.len());
+        let (domain, rest) = filter.split_at(domain_length);
+
+        // Such as `||*.example.com^`, which doesn't start with a domain.
+        if domain.is_empty() || !(rest.is_empty() || rest.starts_with(['^', '/', ':', '$'])) {
+            return None;
+        }
+
+        let (pattern, options) = match rest.split_once(OPTIONS_SEPARATOR) {
+            Some((pattern, options)) => (pattern, options.split(',').collect()),
+            None => (rest, Vec::new()),
+        };
+
+        Some(Self {
+            is_exception,
+            domain: domain.to_lowercase(),
+            pattern,
+            options,
+        })
+    }
+
+    fn is_badfilter(&self) -> bool {
+        self.options.contains(&BADFILTER_OPTION)
+    }
+
+    /// Whether it blocks every request towards its domain.
+    fn blocks_domain(&self) -> bool {
+        !self.is_exception && self.pattern == DOMAIN_FILTER_SUFFIX && self.options.is_empty()
+    }
+
+    /// The filter as cancelled by `$badfilter`, that is without the `badfilter` option.
+    fn key(&self) -> String {
+        let options = self
+            .options
+            .iter()
+            .filter(|option| **option != BADFILTER_OPTION)
+            .copied()
+            .collect::<Vec<_>>();
+
+        format!(
+            "{}{}{}{}{}{}",
+            if self.is_exception {
+                EXCEPTION_PREFIX
+            } else {
+                ""
+            },
+            DOMAIN_FILTER_PREFIX,
+            self.domain,
+            self.pattern,
+            if options.is_empty() { "" } else { "$" },
+            options.join(",")
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DomainFilterSet {
+    blocked_domains: HashSet<String>,
+    excepted_domains: HashSet<String>,
+    // Domains some filter of which is cancelled by a `$badfilter` filter.
+    badfiltered_domains: HashSet<String>,
+}
+
+impl DomainFilterSet {
+    pub fn new(filter_lists: &[String]) -> Self {
+        let domain_filters = filter_lists
+            .iter()
+            .flat_map(|filter_list| filter_list.lines())
+            .filter_map(|line| DomainFilter::parse(line.trim()))
+            .collect::<Vec<_>>();
+
+        let badfilters = domain_filters
+            .iter()
+            .filter(|domain_filter| domain_filter.is_badfilter())
+            .map(|domain_filter| domain_filter.key())
+            .collect::<HashSet<_>>();
+
+        let mut domain_filter_set = Self::default();
+
+        for domain_filter in domain_filters {
+            if domain_filter.is_badfilter() {
+                if !domain_filter.is_exception {
+                    domain_filter_set
+                        .badfiltered_domains
+                        .insert(domain_filter.domain);
+                }
+            } else if badfilters.contains(&domain_filter.key()) {
+                continue;
+            } else if domain_filter.is_exception {
+                domain_filter_set
+                    .excepted_domains
+                    .insert(domain_filter.domain);
+            } else if domain_filter.blocks_domain() {
+                domain_filter_set
+                    .blocked_domains
+                    .insert(domain_filter.domain);
+            }
+        }
+
+        domain_filter_set
+    }
+
+    fn parent_domains(domain: &str) -> Vec<String> {
+        let domain = domain.trim_end_matches('.').to_lowercase();
+
+        std::iter::successors(Some(domain.as_str()), |domain| {
+            domain.split_once('.').map(|(_label, parent)| parent)
+        })
+        .map(|domain| domain.to_string())
+        .collect()
+    }
+
+    /// Whether `domain`, or one of its parent domains, is blocked without being excepted.
+    pub fn is_blocked(&self, domain: &str) -> bool {
+        let parent_domains = Self::parent_domains(domain);
+
+        parent_domains
+            .iter()
+            .any(|domain| self.blocked_domains.contains(domain))
+            && !parent_domains
+                .iter()
+                .any(|domain| self.excepted_domains.contains(domain))
+    }
+
+    /// Whether some requests towards `domain` may be let through by an exception, whatever its
+    /// options, or by a `$badfilter` filter cancelling one of its filters.
+    pub fn has_exception(&self, domain: &str) -> bool {
+        Self::parent_domains(domain).iter().any(|domain| {
+            self.excepted_domains.contains(domain) || self.badfiltered_domains.contains(domain)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn domain_filter_set(filters: &str) -> DomainFilterSet {
+        DomainFilterSet::new(&[filters.to_string()])
+    }
+
+    #[test]
+    fn test_blocked_domains() {
+        let domain_filter_set = domain_filter_set("||ads.example.com^\n  ||Tracker.net^  ");
+
+        assert!(domain_filter_set.is_blocked("ads.example.com"));
+        assert!(domain_filter_set.is_blocked("cdn.ads.example.com"));
+        assert!(domain_filter_set.is_blocked("TRACKER.net."));
+        assert!(!domain_filter_set.is_blocked("example.com"));
+        assert!(!domain_filter_set.is_blocked("notads.example.com"));
+    }
+
+    #[test]
+    fn test_excepted_domains() {
+        let domain_filter_set = domain_filter_set("||example.com^\n@@||www.example.com^");
+
+        assert!(domain_filter_set.is_blocked("example.com"));
+        assert!(domain_filter_set.is_blocked("ads.example.com"));
+        assert!(!domain_filter_set.is_blocked("www.example.com"));
+        assert!(!domain_filter_set.is_blocked("static.www.example.com"));
+    }
+
+    #[test]
+    fn test_partial_filters_ignored() {
+        let domain_filter_set = domain_filter_set(
+            "||example.com^$third-party\n||example.org/ads^\n||example.net\nexample.info^\n||*.example.io^",
+        );
+
+        for domain in [
+            "example.com",
+            "example.org",
+            "example.net",
+            "example.info",
+            "ads.example.io",
+        ] {
+            assert!(!domain_filter_set.is_blocked(domain), "{}", domain);
+        }
+    }
+
+    #[test]
+    fn test_excepted_domains_with_options() {
+        let domain_filter_set = domain_filter_set(
+            "||example.com^\n||example.org^\n||example.net^\n\
+            @@||example.com^$document\n@@||cdn.example.org/lib.js$domain=site.com\n\
+            @@||example.net^$badfilter",
+        );
+
+        assert!(!domain_filter_set.is_blocked("example.com"));
+        assert!(domain_filter_set.has_exception("www.example.com"));
+        assert!(domain_filter_set.is_blocked("example.org"));
+        assert!(!domain_filter_set.is_blocked("cdn.example.org"));
+        assert!(domain_filter_set.has_exception("cdn.example.org"));
+        assert!(!domain_filter_set.has_exception("example.org"));
+        // The exception is cancelled, leaving nothing to cancel.
+        assert!(domain_filter_set.is_blocked("example.net"));
+        assert!(!domain_filter_set.has_exception("example.net"));
+    }
+
+    #[test]
+    fn test_badfilter() {
+        let domain_filter_set = domain_filter_set(
+            "||example.com^\n||example.com^$badfilter\n||example.org^\n||example.org^$third-party,badfilter\n\
+            ||Example.net^\n||example.NET^$badfilter",
+        );
+
+        assert!(!domain_filter_set.is_blocked("example.com"));
+        assert!(domain_filter_set.has_exception("ads.example.com"));
+        // Only cancels the identical filter.
+        assert!(domain_filter_set.is_blocked("example.org"));
+        assert!(domain_filter_set.has_exception("example.org"));
+        assert!(!domain_filter_set.is_blocked("example.net"));
+    }
+}
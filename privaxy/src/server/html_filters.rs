@@ -0,0 +1,343 @@
+//! Support for uBlock Origin's HTML filters (`##^`).
+//!
+//! HTML filters remove elements from documents before they reach the browser. As `adblock-rust`
+//! does not support them, they are extracted from filter lists here and applied by the html
+//! rewriter.
+//!
+//! See: https://github.com/gorhill/uBlock/wiki/Static-filter-syntax#html-filters
+use regex::{Regex, RegexBuilder};
+
+const HTML_FILTER_SEPARATOR: &str = "##^";
+const HTML_FILTER_EXCEPTION_SEPARATOR: &str = "#@#^";
+const HAS_TEXT_PROCEDURAL_OPERATOR: &str = ":has-text(";
+
+/// Elements which only contain text. `:has-text()` is only supported on those, as we otherwise
+/// would have to buffer arbitrary markup.
+pub const RAW_TEXT_ELEMENTS: [&str; 5] = ["script", "style", "title", "textarea", "noscript"];
+
+/// Returns whether a filter list line is an HTML filter or exception.
+pub fn is_html_filter(line: &str) -> bool {
+    line.contains(HTML_FILTER_SEPARATOR) || line.contains(HTML_FILTER_EXCEPTION_SEPARATOR)
+}
+
+/// Returns whether every element matched by `selector` is a raw text element, looking at the
+/// type selector of the last compound selector of each selector in the list.
+fn only_matches_raw_text_elements(selector: &str) -> bool {
+    selector.split(',').all(|selector| {
+        let compound_selector = selector
+            .trim()
+            .rsplit(|c: char| c.is_whitespace() || c == '>' || c == '+' || c == '~')
+            .next()
+            .unwrap_or_default();
+
+        let type_selector_end = compound_selector
+            .find(['.', '#', '[', ':'])
+            .unwrap_or(compound_selector.len());
+
+        RAW_TEXT_ELEMENTS.contains(
+            &compound_selector[..type_selector_end]
+                .to_ascii_lowercase()
+                .as_str(),
+        )
+    })
+}
+
+/// The text an element must contain to be removed, as per uBlock Origin's `:has-text()`
+/// operator.
+#[derive(Debug, Clone)]
+pub enum HasText {
+    Literal(String),
+    Regex(Regex),
+}
+
+impl HasText {
+    fn parse(argument: &str) -> Option<Self> {
+        if argument.is_empty() {
+            return None;
+        }
+
+        // Arguments wrapped in slashes are regular expressions, optionally followed by flags.
+        if let Some(pattern_and_flags) = argument.strip_prefix('/') {
+            let flags_start = pattern_and_flags.rfind('/')?;
+            let (pattern, flags) = pattern_and_flags.split_at(flags_start);
+
+            let regex = RegexBuilder::new(pattern)
+                .case_insensitive(flags.contains('i'))
+                .build()
+                .ok()?;
+
+            return Some(Self::Regex(regex));
+        }
+
+        Some(Self::Literal(argument.to_string()))
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        match self {
+            Self::Literal(literal) => text.contains(literal.as_str()),
+            Self::Regex(regex) => regex.is_match(text),
+        }
+    }
+}
+
+/// An HTML filter applying to a given document.
+#[derive(Debug, Clone)]
+pub struct HtmlFilter {
+    /// A CSS selector, as understood by `lol_html`.
+    pub selector: String,
+    pub has_text: Option<HasText>,
+}
+
+#[derive(Debug)]
+struct HtmlFilterRule {
+    hostnames: Vec<String>,
+    negated_hostnames: Vec<String>,
+    // The selector as written in the filter list, used to match exceptions against rules.
+    raw_selector: String,
+    filter: HtmlFilter,
+}
+
+impl HtmlFilterRule {
+    fn parse(hostnames: &str, raw_selector: &str) -> Option<Self> {
+        let raw_selector = raw_selector.trim();
+
+        // `##^responseheader(...)` filters apply to headers rather than elements.
+        if raw_selector.is_empty() || raw_selector.starts_with("responseheader(") {
+            return None;
+        }
+
+        let (selector, has_text) = match raw_selector.rfind(HAS_TEXT_PROCEDURAL_OPERATOR) {
+            Some(operator_start) => {
+                let argument = raw_selector[operator_start + HAS_TEXT_PROCEDURAL_OPERATOR.len()..]
+                    .strip_suffix(')')?;
+
+                (
+                    raw_selector[..operator_start].trim(),
+                    Some(HasText::parse(argument)?),
+                )
+            }
+            None => (raw_selector, None),
+        };
+
+        if selector.is_empty() {
+            return None;
+        }
+
+        let mut positive_hostnames = Vec::new();
+        let mut negated_hostnames = Vec::new();
+
+        for hostname in hostnames.split(',') {
+            let hostname = hostname.trim().to_lowercase();
+
+            if let Some(negated_hostname) = hostname.strip_prefix('~') {
+                negated_hostnames.push(negated_hostname.to_string());
+            } else if !hostname.is_empty() {
+                positive_hostnames.push(hostname);
+            }
+        }
+
+        Some(Self {
+            hostnames: positive_hostnames,
+            negated_hostnames,
+            raw_selector: raw_selector.to_string(),
+            filter: HtmlFilter {
+                selector: selector.to_string(),
+                has_text,
+            },
+        })
+    }
+
+    fn applies_to(&self, hostname: &str) -> bool {
+        self.hostnames
+            .iter()
+            .any(|pattern| hostname_matches(hostname, pattern))
+            && !self
+                .negated_hostnames
+                .iter()
+                .any(|pattern| hostname_matches(hostname, pattern))
+    }
+}
+
+/// Returns whether `hostname` is matched by a filter's hostname `pattern`, which may either be
+/// a domain (matching its subdomains as well) or an entity such as `example.*`.
+fn hostname_matches(hostname: &str, pattern: &str) -> bool {
+    if let Some(entity) = pattern.strip_suffix(".*") {
+        let entity = format!("{}.", entity);
+
+        return hostname.starts_with(&entity)
+            || hostname
+                .match_indices('.')
+                .any(|(index, _)| hostname[index + 1..].starts_with(&entity));
+    }
+
+    hostname == pattern || hostname.ends_with(&format!(".{}", pattern))
+}
+
+/// HTML filters and exceptions extracted from filter lists.
+///
+/// There are usually only a few hundred HTML filters in enabled filter lists, so we don't bother
+/// indexing them by hostname.
+#[derive(Debug, Default)]
+pub struct HtmlFilterSet {
+    rules: Vec<HtmlFilterRule>,
+    exceptions: Vec<HtmlFilterRule>,
+}
+
+impl HtmlFilterSet {
+    pub fn new(filter_lists: &[String]) -> Self {
+        let mut html_filter_set = Self::default();
+
+        for line in filter_lists
+            .iter()
+            .flat_map(|filter_list| filter_list.lines())
+        {
+            let line = line.trim();
+
+            // Comments
+            if line.starts_with('!') {
+                continue;
+            }
+
+            if let Some((hostnames, selector)) = line.split_once(HTML_FILTER_EXCEPTION_SEPARATOR) {
+                if let Some(exception) = HtmlFilterRule::parse(hostnames, selector) {
+                    html_filter_set.exceptions.push(exception);
+                }
+            } else if let Some((hostnames, selector)) = line.split_once(HTML_FILTER_SEPARATOR) {
+                match HtmlFilterRule::parse(hostnames, selector) {
+                    Some(rule)
+                        if rule.filter.has_text.is_some()
+                            && !only_matches_raw_text_elements(&rule.filter.selector) =>
+                    {
+                        log::warn!(
+                            "Ignoring html filter, :has-text() is only supported on {} elements: {}",
+                            RAW_TEXT_ELEMENTS.join(", "),
+                            line
+                        );
+                    }
+                    // Generic HTML filters are not supported by uBlock Origin either.
+                    Some(rule) if !rule.hostnames.is_empty() => html_filter_set.rules.push(rule),
+                    _ => log::debug!("Ignoring unsupported html filter: {}", line),
+                }
+            }
+        }
+
+        html_filter_set
+    }
+
+    pub fn filters_for_hostname(&self, hostname: &str) -> Vec<HtmlFilter> {
+        let hostname = hostname.to_lowercase();
+
+        self.rules
+            .iter()
+            .filter(|rule| rule.applies_to(&hostname))
+            .filter(|rule| {
+                !self.exceptions.iter().any(|exception| {
+                    exception.raw_selector == rule.raw_selector
+                        // Exceptions without hostnames apply everywhere.
+                        && (exception.hostnames.is_empty() || exception.applies_to(&hostname))
+                })
+            })
+            .map(|rule| rule.filter.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter_set(filter_list: &str) -> HtmlFilterSet {
+        HtmlFilterSet::new(&[filter_list.to_string()])
+    }
+
+    #[test]
+    fn test_filters_for_hostname() {
+        let html_filter_set = filter_set(
+            "example.com##^script[data-ad]\n\
+             ~sub.example.com,example.com##^.banner\n\
+             ! example.com##^.comment\n\
+             other.com##^div",
+        );
+
+        let selectors = |hostname| {
+            html_filter_set
+                .filters_for_hostname(hostname)
+                .into_iter()
+                .map(|filter| filter.selector)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(selectors("example.com"), ["script[data-ad]", ".banner"]);
+        assert_eq!(selectors("www.EXAMPLE.com"), ["script[data-ad]", ".banner"]);
+        assert_eq!(selectors("sub.example.com"), ["script[data-ad]"]);
+        assert!(selectors("notexample.com").is_empty());
+    }
+
+    #[test]
+    fn test_exceptions() {
+        let html_filter_set = filter_set(
+            "example.com,example.org##^script:has-text(ads)\n\
+             example.org#@#^script:has-text(ads)",
+        );
+
+        assert_eq!(html_filter_set.filters_for_hostname("example.com").len(), 1);
+        assert!(html_filter_set
+            .filters_for_hostname("example.org")
+            .is_empty());
+    }
+
+    #[test]
+    fn test_generic_and_unsupported_filters_are_ignored() {
+        let html_filter_set = filter_set(
+            "##^script\n\
+             example.com##^responseheader(x-ads)\n\
+             example.com##^div:has-text(ads)\n\
+             example.com##^script, div:has-text(ads)",
+        );
+
+        assert!(html_filter_set
+            .filters_for_hostname("example.com")
+            .is_empty());
+    }
+
+    #[test]
+    fn test_has_text() {
+        let html_filter_set = filter_set(
+            "example.com##^script:has-text(adsbygoogle)\n\
+             example.com##^body > script[async]:has-text(/ADS\\d+/i)",
+        );
+
+        let filters = html_filter_set.filters_for_hostname("example.com");
+
+        assert_eq!(filters[0].selector, "script");
+        assert_eq!(filters[1].selector, "body > script[async]");
+
+        let has_text = filters
+            .iter()
+            .map(|filter| filter.has_text.clone().unwrap())
+            .collect::<Vec<_>>();
+
+        assert!(has_text[0].is_match("(adsbygoogle = []).push({})"));
+        assert!(!has_text[0].is_match("analytics()"));
+        assert!(has_text[1].is_match("load(\"ads42\")"));
+        assert!(!has_text[1].is_match("load(\"ads\")"));
+    }
+
+    #[test]
+    fn test_only_matches_raw_text_elements() {
+        assert!(only_matches_raw_text_elements("script"));
+        assert!(only_matches_raw_text_elements("head > STYLE.ads"));
+        assert!(only_matches_raw_text_elements("script[src], noscript"));
+        assert!(!only_matches_raw_text_elements(".ads"));
+        assert!(!only_matches_raw_text_elements("script > div"));
+        assert!(!only_matches_raw_text_elements("script, div"));
+    }
+
+    #[test]
+    fn test_is_html_filter() {
+        assert!(is_html_filter("example.com##^script"));
+        assert!(is_html_filter("example.com#@#^script"));
+        assert!(!is_html_filter("example.com##.ads"));
+        assert!(!is_html_filter("||example.com^"));
+    }
+}
@@ -0,0 +1,182 @@
+//! Keys certificates presented to clients are issued for.
+//!
+//! Unless a single key is shared by every host, keys are generated ahead of time on a separate
+//! thread, so that minting certificates for pages pulling resources from dozens of hosts doesn't
+//! wait on key generation. No key is generated at startup otherwise, keys shared between hosts
+//! being generated once first needed.
+use crate::configuration::{CertificatesConfiguration, LeafKeyAlgorithm, LeafKeyReuse};
+use openssl::{
+    ec::{EcGroup, EcKey},
+    nid::Nid,
+    pkey::{PKey, Private},
+    rsa::Rsa,
+};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+fn generate_key(algorithm: LeafKeyAlgorithm) -> PKey<Private> {
+    match algorithm {
+        LeafKeyAlgorithm::EcdsaP256 => {
+            let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+            PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+        }
+        LeafKeyAlgorithm::Rsa => PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap(),
+    }
+}
+
+// A key along with when it was put in use.
+type CurrentKey = (PKey<Private>, Instant);
+
+#[derive(Clone)]
+pub(crate) struct LeafKeys {
+    algorithm: LeafKeyAlgorithm,
+    reuse: LeafKeyReuse,
+    rotation_interval: Duration,
+    // Key shared between hosts, once there is one.
+    current_key: Arc<Mutex<Option<CurrentKey>>>,
+    pool: Arc<Mutex<Vec<PKey<Private>>>>,
+    pool_size: usize,
+    is_pool_refilling: Arc<AtomicBool>,
+}
+
+impl LeafKeys {
+    pub(crate) fn new(configuration: &CertificatesConfiguration) -> Self {
+        let leaf_keys = Self {
+            algorithm: configuration.leaf_key_algorithm,
+            reuse: configuration.leaf_key_reuse,
+            rotation_interval: Duration::from_secs(configuration.leaf_key_rotation_interval),
+            current_key: Arc::new(Mutex::new(None)),
+            pool: Arc::new(Mutex::new(Vec::new())),
+            pool_size: configuration.leaf_key_pool_size,
+            is_pool_refilling: Arc::new(AtomicBool::new(false)),
+        };
+
+        if leaf_keys.reuse != LeafKeyReuse::Shared {
+            leaf_keys.refill_pool();
+        }
+
+        leaf_keys
+    }
+
+    /// The key the next minted certificate is to be issued for. This may block while a key is
+    /// generated, if none was available.
+    pub(crate) fn next_key(&self) -> PKey<Private> {
+        match self.reuse {
+            LeafKeyReuse::Shared => {
+                let mut current_key = self.current_key.lock().unwrap();
+
+                current_key
+                    .get_or_insert_with(|| (generate_key(self.algorithm), Instant::now()))
+                    .0
+                    .clone()
+            }
+            LeafKeyReuse::PerHost => self.take_pooled_key(),
+            LeafKeyReuse::Rotating => {
+                let mut current_key = self.current_key.lock().unwrap();
+
+                match current_key.as_ref() {
+                    Some((_key, put_in_use)) if put_in_use.elapsed() < self.rotation_interval => {}
+                    Some(_expired_key) => {
+                        log::debug!("Rotating leaf key");
+
+                        *current_key = Some((self.take_pooled_key(), Instant::now()));
+                    }
+                    None => *current_key = Some((self.take_pooled_key(), Instant::now())),
+                }
+
+                current_key.as_ref().unwrap().0.clone()
+            }
+        }
+    }
+
+    fn take_pooled_key(&self) -> PKey<Private> {
+        let (key, remaining_keys) = {
+            let mut pool = self.pool.lock().unwrap();
+            (pool.pop(), pool.len())
+        };
+
+        if remaining_keys <= self.pool_size / 2 {
+            self.refill_pool();
+        }
+
+        key.unwrap_or_else(|| generate_key(self.algorithm))
+    }
+
+    fn refill_pool(&self) {
+        // A single thread takes care of refilling the pool at once.
+        if self.is_pool_refilling.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let leaf_keys = self.clone();
+
+        std::thread::spawn(move || {
+            while leaf_keys.pool.lock().unwrap().len() < leaf_keys.pool_size {
+                let key = generate_key(leaf_keys.algorithm);
+
+                leaf_keys.pool.lock().unwrap().push(key);
+            }
+
+            leaf_keys.is_pool_refilling.store(false, Ordering::Release);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf_keys(reuse: LeafKeyReuse, rotation_interval: u64) -> LeafKeys {
+        LeafKeys::new(&CertificatesConfiguration {
+            leaf_key_algorithm: LeafKeyAlgorithm::EcdsaP256,
+            leaf_key_reuse: reuse,
+            leaf_key_pool_size: 2,
+            leaf_key_rotation_interval: rotation_interval,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_shared_key() {
+        let leaf_keys = leaf_keys(LeafKeyReuse::Shared, 0);
+
+        assert!(leaf_keys.current_key.lock().unwrap().is_none());
+        assert!(leaf_keys.next_key().public_eq(&leaf_keys.next_key()));
+    }
+
+    #[test]
+    fn test_per_host_keys() {
+        let leaf_keys = leaf_keys(LeafKeyReuse::PerHost, 0);
+
+        // Keys are only taken from the pool.
+        assert!(leaf_keys.current_key.lock().unwrap().is_none());
+
+        let keys = Vec::from_iter((0..4).map(|_| leaf_keys.next_key()));
+
+        for (index, key) in keys.iter().enumerate() {
+            assert!(!keys[index + 1..]
+                .iter()
+                .any(|other_key| key.public_eq(other_key)));
+        }
+        assert!(leaf_keys.current_key.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_rotating_key() {
+        let leaf_keys = leaf_keys(LeafKeyReuse::Rotating, 60);
+        assert!(leaf_keys.next_key().public_eq(&leaf_keys.next_key()));
+
+        // The key is considered expired once the interval elapsed.
+        let key = leaf_keys.next_key();
+        leaf_keys.current_key.lock().unwrap().as_mut().unwrap().1 -= Duration::from_secs(60);
+
+        let rotated_key = leaf_keys.next_key();
+        assert!(!key.public_eq(&rotated_key));
+        assert!(rotated_key.public_eq(&leaf_keys.next_key()));
+    }
+}
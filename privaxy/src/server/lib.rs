@@ -1,9 +1,14 @@
 use crate::blocker::AdblockRequester;
+use crate::dns::resolver::UpstreamResolver;
 use crate::events::Event;
+use crate::proxy::connect_ports::ConnectPorts;
+use crate::proxy::dynamic_cosmetic_filtering::PageTokens;
 use crate::proxy::exclusions::LocalExclusionStore;
+use crate::proxy::pac::ProxyAutoConfig;
 use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Client, Server};
+use hyper_rustls::HttpsConnector;
 use proxy::exclusions;
 use reqwest::redirect::Policy;
 use std::convert::Infallible;
@@ -11,16 +16,29 @@ use std::net::SocketAddr;
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
+use tokio::net::TcpListener;
 use tokio::sync::broadcast;
+use trust_store::TrustStore;
+use upstream_proxies::UpstreamProxies;
 
 pub mod blocker;
 mod blocker_utils;
 mod ca;
 mod cert;
+mod cert_store;
 pub mod configuration;
+mod dns;
+mod domain_filters;
 pub mod events;
+mod html_filters;
+mod leaf_keys;
 mod proxy;
+pub mod response_rewrite_rules;
 pub mod statistics;
+pub mod trust_store;
+pub mod upstream_proxies;
+
+const PROXY_PORT: u16 = 8100;
 
 #[derive(Debug, Clone)]
 pub struct PrivaxyServer {
@@ -30,26 +48,22 @@ pub struct PrivaxyServer {
     pub blocking_disabled_store: blocker::BlockingDisabledStore,
     pub statistics: statistics::Statistics,
     pub local_exclusion_store: exclusions::LocalExclusionStore,
+    pub trust_store: TrustStore,
     // A Sender is required to subscribe to broadcasted messages
     pub requests_broadcast_sender: broadcast::Sender<Event>,
 }
 
 pub async fn start_privaxy() -> PrivaxyServer {
-    let ip = [127, 0, 0, 1];
-
-    // We use reqwest instead of hyper's client to perform most of the proxying as it's more convenient
-    // to handle compression as well as offers a more convenient interface.
-    let client = reqwest::Client::builder()
-        .use_rustls_tls()
-        .redirect(Policy::none())
-        .no_proxy()
-        .gzip(true)
-        .brotli(true)
-        .deflate(true)
-        .build()
-        .unwrap();
+    let default_trust_store = TrustStore::default();
 
-    let configuration = match configuration::Configuration::read_from_home(client.clone()).await {
+    let configuration = match configuration::Configuration::read_from_home(build_client(
+        configuration::UpstreamConfiguration::default(),
+        UpstreamProxies::new(&[], UpstreamResolver::default(), &default_trust_store),
+        UpstreamResolver::default(),
+        &default_trust_store,
+    ))
+    .await
+    {
         Ok(configuration) => configuration,
         Err(err) => {
             println!(
@@ -60,10 +74,52 @@ pub async fn start_privaxy() -> PrivaxyServer {
         }
     };
 
-    let local_exclusion_store =
-        LocalExclusionStore::new(Vec::from_iter(configuration.exclusions.clone().into_iter()));
+    let html_rewriter_configuration = configuration.html_rewriter;
+    let upstream_configuration = configuration.upstream;
+    let strip_alt_svc = configuration.strip_alt_svc;
+    let ip = configuration.listener.address;
+    let socks5_listener_configuration = configuration.socks5_listener;
+    let transparent_proxy_configuration = configuration.transparent_proxy;
+    let dns_sinkhole_configuration = configuration.dns_sinkhole;
+
+    let trust_store = TrustStore::new(&configuration.upstream_tls);
+    let upstream_resolver = UpstreamResolver::new(&configuration.upstream_dns, &trust_store);
+    let upstream_proxies = UpstreamProxies::new(
+        &configuration.upstream_proxies,
+        upstream_resolver.clone(),
+        &trust_store,
+    );
+    let trust_store_clone = trust_store.clone();
+
+    let client = build_client(
+        upstream_configuration,
+        upstream_proxies.clone(),
+        upstream_resolver,
+        &trust_store,
+    );
+    let page_tokens = PageTokens::new();
+
+    let auto_exclusions = match configuration::read_auto_exclusions().await {
+        Ok(auto_exclusions) => auto_exclusions,
+        Err(err) => {
+            log::error!("Unable to read automatic exclusions: {:?}", err);
+            Default::default()
+        }
+    };
+
+    let local_exclusion_store = LocalExclusionStore::new(
+        Vec::from_iter(configuration.exclusions.clone().into_iter()),
+        auto_exclusions,
+    );
     let local_exclusion_store_clone = local_exclusion_store.clone();
 
+    let proxy_auto_config = ProxyAutoConfig::new(
+        &configuration.pac,
+        PROXY_PORT,
+        local_exclusion_store.clone(),
+    );
+    let connect_ports = ConnectPorts::new(&configuration.connect.allowed_ports);
+
     let ca_certificate = match configuration.ca_certificate() {
         Ok(ca_certificate) => ca_certificate,
         Err(err) => {
@@ -84,7 +140,48 @@ pub async fn start_privaxy() -> PrivaxyServer {
         }
     };
 
-    let cert_cache = cert::CertCache::new(ca_certificate, ca_private_key);
+    let upstream_certificate_fetcher = if configuration.certificates.mirror_upstream {
+        Some(cert::UpstreamCertificateFetcher::new(
+            upstream_proxies.clone(),
+            &trust_store,
+        ))
+    } else {
+        None
+    };
+
+    let cert_store = if configuration.certificates.persist {
+        match cert_store::CertStore::open(
+            &ca_certificate,
+            &ca_private_key,
+            &configuration.certificates,
+        ) {
+            Ok(cert_store) => {
+                let cert_store_clone = cert_store.clone();
+
+                tokio::task::spawn_blocking(move || {
+                    if let Err(err) = cert_store_clone.prune() {
+                        log::warn!("Unable to prune stored certificates: {}", err);
+                    }
+                });
+
+                Some(cert_store)
+            }
+            Err(err) => {
+                log::error!("Unable to open certificate store: {}", err);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let cert_cache = cert::CertCache::new(
+        ca_certificate,
+        ca_private_key,
+        &configuration.certificates,
+        upstream_certificate_fetcher,
+        cert_store,
+    );
 
     let statistics = statistics::Statistics::new();
     let statistics_clone = statistics.clone();
@@ -124,17 +221,109 @@ pub async fn start_privaxy() -> PrivaxyServer {
         blocker.handle_requests()
     });
 
+    // Upgrades are an HTTP/1.1 mechanism, we therefore don't offer HTTP/2 through ALPN
+    // on this connector.
     let https_connector = hyper_rustls::HttpsConnectorBuilder::new()
-        .with_native_roots()
+        .with_tls_config(trust_store.client_configuration())
         .https_or_http()
         .enable_http1()
-        .build();
+        .wrap_connector(upstream_proxies.clone());
 
     // The hyper client is only used to perform upgrades. We don't need to
     // handle compression.
     // Hyper's client don't follow redirects, which is what we want, nothing to
     // disable here.
-    let hyper_client = Client::builder().build(https_connector);
+    let hyper_client = Client::builder()
+        .pool_max_idle_per_host(upstream_configuration.max_idle_connections_per_host)
+        .pool_idle_timeout(Duration::from_secs(
+            upstream_configuration.idle_connection_timeout,
+        ))
+        .build(https_connector);
+
+    if socks5_listener_configuration.enabled {
+        let socks5_listener_addr = SocketAddr::from((ip, socks5_listener_configuration.port));
+
+        match TcpListener::bind(socks5_listener_addr).await {
+            Ok(listener) => {
+                log::info!("SOCKS5 proxy available at {}", socks5_listener_addr);
+
+                let client = client.clone();
+                let hyper_client = hyper_client.clone();
+                let cert_cache = cert_cache.clone();
+                let blocker_requester = blocker_requester.clone();
+                let broadcast_tx = broadcast_tx.clone();
+                let statistics = statistics.clone();
+                let local_exclusion_store = local_exclusion_store.clone();
+                let page_tokens = page_tokens.clone();
+                let upstream_proxies = upstream_proxies.clone();
+                let trust_store = trust_store.clone();
+                let connect_ports = connect_ports.clone();
+
+                tokio::spawn(async move {
+                    loop {
+                        let (stream, client_address) = match listener.accept().await {
+                            Ok(accepted) => accepted,
+                            Err(err) => {
+                                log::error!("Unable to accept socks5 connection: {}", err);
+                                continue;
+                            }
+                        };
+
+                        tokio::spawn(proxy::socks5::serve_socks5_connection(
+                            stream,
+                            blocker_requester.clone(),
+                            hyper_client.clone(),
+                            client.clone(),
+                            cert_cache.clone(),
+                            broadcast_tx.clone(),
+                            statistics.clone(),
+                            client_address.ip(),
+                            local_exclusion_store.clone(),
+                            html_rewriter_configuration,
+                            page_tokens.clone(),
+                            strip_alt_svc,
+                            upstream_proxies.clone(),
+                            trust_store.clone(),
+                            connect_ports.clone(),
+                        ));
+                    }
+                });
+            }
+            Err(err) => log::error!("Unable to start SOCKS5 proxy: {}", err),
+        }
+    }
+
+    if dns_sinkhole_configuration.enabled {
+        if let Err(err) = dns::sinkhole::start(
+            dns_sinkhole_configuration,
+            strip_alt_svc,
+            blocker_requester.clone(),
+            broadcast_tx.clone(),
+            statistics.clone(),
+        )
+        .await
+        {
+            log::error!("Unable to start DNS sinkhole: {}", err);
+        }
+    }
+
+    if transparent_proxy_configuration.enabled {
+        start_transparent_proxy(
+            transparent_proxy_configuration,
+            blocker_requester.clone(),
+            hyper_client.clone(),
+            client.clone(),
+            cert_cache.clone(),
+            broadcast_tx.clone(),
+            statistics.clone(),
+            local_exclusion_store.clone(),
+            html_rewriter_configuration,
+            page_tokens.clone(),
+            strip_alt_svc,
+            upstream_proxies.clone(),
+            trust_store.clone(),
+        );
+    }
 
     let make_service = make_service_fn(move |conn: &AddrStream| {
         let client_ip_address = conn.remote_addr().ip();
@@ -146,6 +335,11 @@ pub async fn start_privaxy() -> PrivaxyServer {
         let broadcast_tx = broadcast_tx.clone();
         let statistics = statistics.clone();
         let local_exclusion_store = local_exclusion_store.clone();
+        let page_tokens = page_tokens.clone();
+        let upstream_proxies = upstream_proxies.clone();
+        let trust_store = trust_store.clone();
+        let proxy_auto_config = proxy_auto_config.clone();
+        let connect_ports = connect_ports.clone();
 
         async move {
             Ok::<_, Infallible>(service_fn(move |req| {
@@ -159,12 +353,19 @@ pub async fn start_privaxy() -> PrivaxyServer {
                     statistics.clone(),
                     client_ip_address,
                     local_exclusion_store.clone(),
+                    html_rewriter_configuration,
+                    page_tokens.clone(),
+                    strip_alt_svc,
+                    upstream_proxies.clone(),
+                    trust_store.clone(),
+                    proxy_auto_config.clone(),
+                    connect_ports.clone(),
                 )
             }))
         }
     });
 
-    let proxy_server_addr = SocketAddr::from((ip, 8100));
+    let proxy_server_addr = SocketAddr::from((ip, PROXY_PORT));
 
     let server = Server::bind(&proxy_server_addr)
         .http1_preserve_header_case(true)
@@ -183,6 +384,190 @@ pub async fn start_privaxy() -> PrivaxyServer {
         blocking_disabled_store: blocking_disabled_store_clone,
         statistics: statistics_clone,
         local_exclusion_store: local_exclusion_store_clone,
+        trust_store: trust_store_clone,
         requests_broadcast_sender: broadcast_tx_clone,
     }
 }
+
+#[cfg(target_os = "linux")]
+#[allow(clippy::too_many_arguments)]
+fn start_transparent_proxy(
+    transparent_proxy_configuration: configuration::TransparentProxyConfiguration,
+    blocker_requester: AdblockRequester,
+    hyper_client: hyper::Client<HttpsConnector<UpstreamProxies>>,
+    client: reqwest::Client,
+    cert_cache: cert::CertCache,
+    broadcast_tx: broadcast::Sender<Event>,
+    statistics: statistics::Statistics,
+    local_exclusion_store: LocalExclusionStore,
+    html_rewriter_configuration: configuration::HtmlRewriterConfiguration,
+    page_tokens: PageTokens,
+    strip_alt_svc: bool,
+    upstream_proxies: UpstreamProxies,
+    trust_store: TrustStore,
+) {
+    let transparent_proxy_addr = SocketAddr::from((
+        transparent_proxy_configuration.address,
+        transparent_proxy_configuration.port,
+    ));
+    let tproxy = transparent_proxy_configuration.tproxy;
+
+    let listener = match proxy::transparent::bind(transparent_proxy_addr, tproxy) {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::error!("Unable to start transparent proxy: {}", err);
+            return;
+        }
+    };
+
+    log::info!("Transparent proxy available at {}", transparent_proxy_addr);
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, client_address) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    log::error!("Unable to accept transparently proxied connection: {}", err);
+                    continue;
+                }
+            };
+
+            tokio::spawn(proxy::transparent::serve_transparent_connection(
+                stream,
+                tproxy,
+                blocker_requester.clone(),
+                hyper_client.clone(),
+                client.clone(),
+                cert_cache.clone(),
+                broadcast_tx.clone(),
+                statistics.clone(),
+                client_address.ip(),
+                local_exclusion_store.clone(),
+                html_rewriter_configuration,
+                page_tokens.clone(),
+                strip_alt_svc,
+                upstream_proxies.clone(),
+                trust_store.clone(),
+            ));
+        }
+    });
+}
+
+#[cfg(not(target_os = "linux"))]
+#[allow(clippy::too_many_arguments)]
+fn start_transparent_proxy(
+    _transparent_proxy_configuration: configuration::TransparentProxyConfiguration,
+    _blocker_requester: AdblockRequester,
+    _hyper_client: hyper::Client<HttpsConnector<UpstreamProxies>>,
+    _client: reqwest::Client,
+    _cert_cache: cert::CertCache,
+    _broadcast_tx: broadcast::Sender<Event>,
+    _statistics: statistics::Statistics,
+    _local_exclusion_store: LocalExclusionStore,
+    _html_rewriter_configuration: configuration::HtmlRewriterConfiguration,
+    _page_tokens: PageTokens,
+    _strip_alt_svc: bool,
+    _upstream_proxies: UpstreamProxies,
+    _trust_store: TrustStore,
+) {
+    log::error!("Transparent proxying is only supported on Linux");
+}
+
+// We use reqwest instead of hyper's client to perform most of the proxying as it's more convenient
+// to handle compression as well as offers a more convenient interface.
+// HTTP/2 is used with upstream servers offering it through ALPN.
+fn build_client(
+    upstream_configuration: configuration::UpstreamConfiguration,
+    upstream_proxies: UpstreamProxies,
+    upstream_resolver: UpstreamResolver,
+    trust_store: &TrustStore,
+) -> reqwest::Client {
+    trust_store
+        .configure_reqwest(reqwest::Client::builder())
+        .redirect(Policy::none())
+        // System proxies are ignored, upstream proxies must be configured explicitly.
+        .no_proxy()
+        .proxy(reqwest::Proxy::custom(move |url| {
+            upstream_proxies.proxy_url_for(url.host_str()?)
+        }))
+        .gzip(true)
+        .brotli(true)
+        .deflate(true)
+        .pool_max_idle_per_host(upstream_configuration.max_idle_connections_per_host)
+        .pool_idle_timeout(Duration::from_secs(
+            upstream_configuration.idle_connection_timeout,
+        ))
+        .http2_adaptive_window(true)
+        .dns_resolver(Arc::new(upstream_resolver))
+        .build()
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::UpstreamTlsConfiguration;
+    use crate::upstream_proxies::UpstreamProxyRule;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_client_lets_socks5_proxies_resolve_host_names() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_address = listener.local_addr().unwrap();
+
+        // Accepts the greeting, then records the requested destination and refuses it.
+        let proxy_task = tokio::spawn(async move {
+            let (mut stream, _client_address) = listener.accept().await.unwrap();
+
+            let mut greeting = [0; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            stream.write_all(&[0x05, 0x00]).await.unwrap();
+
+            let mut request = [0; 5];
+            stream.read_exact(&mut request).await.unwrap();
+            let mut host = vec![0; request[4] as usize];
+            stream.read_exact(&mut host).await.unwrap();
+
+            stream
+                .write_all(&[0x05, 0x02, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+
+            (request[3], String::from_utf8(host).unwrap())
+        });
+
+        let trust_store = TrustStore::new(&UpstreamTlsConfiguration {
+            native_roots: false,
+            bundled_roots: false,
+            ..Default::default()
+        });
+        let upstream_proxies = UpstreamProxies::new(
+            &[UpstreamProxyRule {
+                hosts: vec![String::from("*")],
+                proxy: format!("socks5://{}", proxy_address),
+            }],
+            UpstreamResolver::default(),
+            &trust_store,
+        );
+        let client = build_client(
+            configuration::UpstreamConfiguration::default(),
+            upstream_proxies,
+            UpstreamResolver::default(),
+            &trust_store,
+        );
+
+        // The host name doesn't exist, it is only known to reach the proxy if the client didn't
+        // attempt to resolve it.
+        let result = client.get("http://privaxy.invalid/").send().await;
+        assert!(result.is_err());
+
+        let (address_type, host) = tokio::time::timeout(Duration::from_secs(5), proxy_task)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Domain name address type.
+        assert_eq!(address_type, 0x03);
+        assert_eq!(host, "privaxy.invalid");
+    }
+}
@@ -0,0 +1,110 @@
+//! Rewriting of `Alt-Svc` headers, which advertise alternative services such as HTTP/3.
+//!
+//! Browsers switching to HTTP/3 talk QUIC, over UDP, to servers and therefore stop going through
+//! the proxy. Alternatives using other protocols are kept.
+//!
+//! HTTP/3 may also be advertised by DNS HTTPS records, which are handled by the DNS sinkhole.
+//! Browsers resolving names by other means, such as their own DNS over HTTPS resolver, may still
+//! learn about it.
+//!
+//! See: https://www.rfc-editor.org/rfc/rfc7838
+use http::header::{HeaderMap, HeaderValue, ALT_SVC};
+
+// Protocol ids of HTTP/3, including drafts, and of Google's QUIC.
+const QUIC_PROTOCOL_ID_PREFIXES: [&str; 2] = ["h3", "quic"];
+
+fn is_quic_alternative(alternative: &str) -> bool {
+    let protocol_id = alternative
+        .split('=')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+
+    QUIC_PROTOCOL_ID_PREFIXES
+        .iter()
+        .any(|prefix| protocol_id.starts_with(prefix))
+}
+
+/// Removes QUIC based alternatives from `Alt-Svc` headers, removing headers left empty.
+pub(crate) fn strip_quic_alternatives(headers: &mut HeaderMap) {
+    if !headers.contains_key(ALT_SVC) {
+        return;
+    }
+
+    let alternatives = headers
+        .get_all(ALT_SVC)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|alternative| alternative.trim())
+        .filter(|alternative| !alternative.is_empty() && !is_quic_alternative(alternative))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    headers.remove(ALT_SVC);
+
+    if alternatives.is_empty() {
+        return;
+    }
+
+    if let Ok(value) = HeaderValue::from_str(&alternatives) {
+        headers.insert(ALT_SVC, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alt_svc_values(headers: &HeaderMap) -> Vec<&str> {
+        headers
+            .get_all(ALT_SVC)
+            .iter()
+            .map(|value| value.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_header_is_removed() {
+        let mut headers = HeaderMap::new();
+        headers.append(
+            ALT_SVC,
+            HeaderValue::from_static(r#"h3=":443"; ma=86400, h3-29=":443"; ma=86400"#),
+        );
+        headers.append(
+            ALT_SVC,
+            HeaderValue::from_static(r#"quic=":443"; ma=2592000"#),
+        );
+
+        strip_quic_alternatives(&mut headers);
+
+        assert!(!headers.contains_key(ALT_SVC));
+    }
+
+    #[test]
+    fn test_other_alternatives_are_kept() {
+        let mut headers = HeaderMap::new();
+        headers.append(
+            ALT_SVC,
+            HeaderValue::from_static(r#"H3=":443", h2="alt.example.com:443"; ma=60"#),
+        );
+        headers.append(ALT_SVC, HeaderValue::from_static("clear"));
+
+        strip_quic_alternatives(&mut headers);
+
+        assert_eq!(
+            alt_svc_values(&headers),
+            [r#"h2="alt.example.com:443"; ma=60, clear"#]
+        );
+    }
+
+    #[test]
+    fn test_without_header() {
+        let mut headers = HeaderMap::new();
+
+        strip_quic_alternatives(&mut headers);
+
+        assert!(headers.is_empty());
+    }
+}
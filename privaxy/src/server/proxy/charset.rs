@@ -0,0 +1,125 @@
+//! Detection of html documents' character encoding, following a simplified version of
+//! https://html.spec.whatwg.org/multipage/parsing.html#determining-the-character-encoding
+use encoding_rs::{Encoding, UTF_8, WINDOWS_1252, X_USER_DEFINED};
+use once_cell::sync::Lazy;
+use regex::bytes::Regex;
+
+/// Number of bytes examined to find a `<meta>` declared charset.
+pub(crate) const PRESCAN_LENGTH: usize = 1024;
+
+static CONTENT_TYPE_CHARSET_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"(?i)charset\s*=\s*["']?\s*([a-z0-9_:.\-]+)"#).unwrap());
+// Matches both `<meta charset="...">` and `<meta http-equiv="Content-Type" content="...; charset=...">`.
+static META_CHARSET_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"(?i)<meta\s[^>]*?charset\s*=\s*["']?\s*([a-z0-9_:.\-]+)"#).unwrap());
+
+fn encoding_from_label(label: &[u8]) -> Option<&'static Encoding> {
+    let encoding = Encoding::for_label(label)?;
+
+    // As per the specification, documents declaring utf-16 in a `<meta>` element are
+    // actually utf-8 encoded.
+    if encoding == encoding_rs::UTF_16BE || encoding == encoding_rs::UTF_16LE {
+        return Some(UTF_8);
+    }
+
+    if encoding == X_USER_DEFINED {
+        return Some(WINDOWS_1252);
+    }
+
+    Some(encoding)
+}
+
+/// Returns the encoding declared by the charset parameter of a `Content-Type` header.
+pub(crate) fn encoding_from_content_type(content_type: &str) -> Option<&'static Encoding> {
+    let captures = CONTENT_TYPE_CHARSET_RE.captures(content_type.as_bytes())?;
+
+    Encoding::for_label(&captures[1])
+}
+
+/// Works out the encoding of a document from its byte order mark, the charset of its
+/// `Content-Type` header or the `<meta>` elements found in its first bytes, in this order.
+pub(crate) fn detect_encoding(
+    content_type_encoding: Option<&'static Encoding>,
+    head: &[u8],
+) -> &'static Encoding {
+    if let Some((encoding, _bom_length)) = Encoding::for_bom(head) {
+        return encoding;
+    }
+
+    if let Some(encoding) = content_type_encoding {
+        return encoding;
+    }
+
+    let prescanned = &head[..head.len().min(PRESCAN_LENGTH)];
+
+    META_CHARSET_RE
+        .captures(prescanned)
+        .and_then(|captures| encoding_from_label(&captures[1]))
+        .unwrap_or(UTF_8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::{EUC_JP, ISO_8859_2, SHIFT_JIS, UTF_16LE};
+
+    #[test]
+    fn test_encoding_from_content_type() {
+        assert_eq!(
+            encoding_from_content_type("text/html; charset=Shift_JIS"),
+            Some(SHIFT_JIS)
+        );
+        assert_eq!(
+            encoding_from_content_type(r#"text/html;charset="iso-8859-1""#),
+            Some(WINDOWS_1252)
+        );
+        assert_eq!(encoding_from_content_type("text/html"), None);
+        assert_eq!(encoding_from_content_type("text/html; charset=bogus"), None);
+    }
+
+    #[test]
+    fn test_byte_order_mark() {
+        assert_eq!(
+            detect_encoding(Some(SHIFT_JIS), b"\xef\xbb\xbf<html>"),
+            UTF_8
+        );
+        assert_eq!(detect_encoding(None, b"\xff\xfe<\x00h\x00"), UTF_16LE);
+    }
+
+    #[test]
+    fn test_content_type_takes_precedence_over_meta() {
+        assert_eq!(
+            detect_encoding(Some(EUC_JP), br#"<meta charset="iso-8859-2">"#),
+            EUC_JP
+        );
+    }
+
+    #[test]
+    fn test_meta() {
+        assert_eq!(
+            detect_encoding(None, br#"<html><head><META Charset='Shift_JIS'>"#),
+            SHIFT_JIS
+        );
+        assert_eq!(
+            detect_encoding(
+                None,
+                br#"<meta http-equiv="Content-Type" content="text/html; charset=iso-8859-2">"#
+            ),
+            ISO_8859_2
+        );
+        assert_eq!(detect_encoding(None, br#"<meta charset="utf-16">"#), UTF_8);
+        assert_eq!(
+            detect_encoding(None, br#"<meta charset="x-user-defined">"#),
+            WINDOWS_1252
+        );
+        assert_eq!(detect_encoding(None, br#"<meta name="viewport">"#), UTF_8);
+    }
+
+    #[test]
+    fn test_meta_past_prescan_is_ignored() {
+        let mut head = vec![b' '; PRESCAN_LENGTH];
+        head.extend_from_slice(br#"<meta charset="shift_jis">"#);
+
+        assert_eq!(detect_encoding(None, &head), UTF_8);
+    }
+}
@@ -0,0 +1,416 @@
+//! Peeking at the first bytes clients send, to find out whether they speak TLS and which server
+//! they intend to reach, through the server name indication of their ClientHello, or plaintext
+//! HTTP.
+//!
+//! See: https://www.rfc-editor.org/rfc/rfc8446#section-4.1.2 and
+//! https://www.rfc-editor.org/rfc/rfc6066#section-3
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+const TLS_HANDSHAKE_RECORD: u8 = 0x16;
+const TLS_CLIENT_HELLO: u8 = 0x01;
+const TLS_SERVER_NAME_EXTENSION: u16 = 0x0000;
+const TLS_HOST_NAME: u8 = 0x00;
+const TLS_RECORD_HEADER_LENGTH: usize = 5;
+// A record may not hold more than 2^14 bytes.
+const MAX_CLIENT_HELLO_LENGTH: usize = TLS_RECORD_HEADER_LENGTH + 16 * 1024;
+// Clients of protocols where servers speak first won't send anything.
+const FIRST_BYTES_TIMEOUT: Duration = Duration::from_secs(2);
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+// Methods HTTP/1.1 requests may start with, `CONNECT` aside as there is no point in nesting
+// tunnels.
+const HTTP_METHODS: [&[u8]; 8] = [
+    b"GET ",
+    b"HEAD ",
+    b"POST ",
+    b"PUT ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"TRACE ",
+    b"PATCH ",
+];
+
+enum ParsedClientHello {
+    Incomplete,
+    Invalid,
+    Complete { server_name: Option<String> },
+}
+
+struct Cursor<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, length: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < length {
+            return None;
+        }
+
+        let (taken, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.take(2)?;
+
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        let bytes = self.take(3)?;
+
+        Some(u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]) as usize)
+    }
+
+    fn skip_vector_u8(&mut self) -> Option<()> {
+        let length = self.u8()? as usize;
+        self.take(length).map(|_| ())
+    }
+
+    fn skip_vector_u16(&mut self) -> Option<()> {
+        let length = self.u16()? as usize;
+        self.take(length).map(|_| ())
+    }
+}
+
+fn parse_server_name_extension(extension: &[u8]) -> Option<String> {
+    let mut cursor = Cursor { bytes: extension };
+    let list_length = cursor.u16()? as usize;
+    let mut list = Cursor {
+        bytes: cursor.take(list_length)?,
+    };
+
+    while !list.bytes.is_empty() {
+        let name_type = list.u8()?;
+        let name_length = list.u16()? as usize;
+        let name = list.take(name_length)?;
+
+        if name_type == TLS_HOST_NAME {
+            return Some(std::str::from_utf8(name).ok()?.to_lowercase());
+        }
+    }
+
+    None
+}
+
+/// Parses ClientHellos contained in a single record, which is what clients send in practice.
+fn parse_client_hello(bytes: &[u8]) -> ParsedClientHello {
+    // Non TLS clients are told apart from their first byte.
+    match bytes.first() {
+        Some(&TLS_HANDSHAKE_RECORD) => {}
+        Some(_) => return ParsedClientHello::Invalid,
+        None => return ParsedClientHello::Incomplete,
+    }
+
+    let mut cursor = Cursor { bytes };
+
+    let record_length = match (cursor.u8(), cursor.take(2), cursor.u16()) {
+        (Some(_record_type), Some(_version), Some(record_length)) => record_length as usize,
+        _ => return ParsedClientHello::Incomplete,
+    };
+
+    let record = match cursor.take(record_length) {
+        Some(record) => record,
+        None => return ParsedClientHello::Incomplete,
+    };
+
+    let server_name = (|| {
+        let mut cursor = Cursor { bytes: record };
+
+        if cursor.u8()? != TLS_CLIENT_HELLO {
+            return None;
+        }
+
+        let handshake_length = cursor.u24()?;
+        let mut cursor = Cursor {
+            bytes: cursor.take(handshake_length)?,
+        };
+
+        // Legacy version and random.
+        cursor.take(2 + 32)?;
+        // Legacy session id, cipher suites and legacy compression methods.
+        cursor.skip_vector_u8()?;
+        cursor.skip_vector_u16()?;
+        cursor.skip_vector_u8()?;
+
+        let extensions_length = cursor.u16()? as usize;
+        let mut extensions = Cursor {
+            bytes: cursor.take(extensions_length)?,
+        };
+
+        while !extensions.bytes.is_empty() {
+            let extension_type = extensions.u16()?;
+            let extension_length = extensions.u16()? as usize;
+            let extension = extensions.take(extension_length)?;
+
+            if extension_type == TLS_SERVER_NAME_EXTENSION {
+                return parse_server_name_extension(extension);
+            }
+        }
+
+        None
+    })();
+
+    ParsedClientHello::Complete { server_name }
+}
+
+/// A stream replaying the bytes which were read from it while peeking.
+pub(crate) struct PeekedStream<S> {
+    peeked: Vec<u8>,
+    position: usize,
+    inner: S,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PeekedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.position < self.peeked.len() {
+            let length = (self.peeked.len() - self.position).min(buf.remaining());
+            let position = self.position;
+
+            buf.put_slice(&self.peeked[position..position + length]);
+            self.position += length;
+
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PeekedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+pub(crate) struct ClientHello<S> {
+    pub(crate) stream: PeekedStream<S>,
+    /// Whether the client started a TLS handshake.
+    pub(crate) is_tls: bool,
+    pub(crate) server_name: Option<String>,
+    /// Whether the client started a plaintext HTTP request.
+    pub(crate) is_http: bool,
+}
+
+/// Reads the client's first bytes, up to a complete ClientHello if it speaks TLS. Everything
+/// read is replayed by the returned stream.
+pub(crate) async fn peek_client_hello<S>(mut stream: S) -> io::Result<ClientHello<S>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut peeked = Vec::new();
+    let mut buffer = vec![0; MAX_CLIENT_HELLO_LENGTH];
+
+    let (is_tls, server_name) = match tokio::time::timeout(FIRST_BYTES_TIMEOUT, async {
+        stream.read(&mut buffer).await
+    })
+    .await
+    {
+        Ok(Ok(0)) | Err(_) => (false, None),
+        Ok(Ok(read)) => {
+            peeked.extend_from_slice(&buffer[..read]);
+
+            let client_hello = tokio::time::timeout(CLIENT_HELLO_TIMEOUT, async {
+                loop {
+                    match parse_client_hello(&peeked) {
+                        ParsedClientHello::Complete { server_name } => return (true, server_name),
+                        ParsedClientHello::Invalid => return (false, None),
+                        ParsedClientHello::Incomplete
+                            if peeked.len() >= MAX_CLIENT_HELLO_LENGTH =>
+                        {
+                            return (false, None)
+                        }
+                        ParsedClientHello::Incomplete => {}
+                    }
+
+                    let remaining = MAX_CLIENT_HELLO_LENGTH - peeked.len();
+
+                    match stream.read(&mut buffer[..remaining]).await {
+                        Ok(0) | Err(_) => return (false, None),
+                        Ok(read) => peeked.extend_from_slice(&buffer[..read]),
+                    }
+                }
+            })
+            .await;
+
+            match client_hello {
+                Ok(client_hello) => client_hello,
+                Err(_elapsed) => (peeked.first() == Some(&TLS_HANDSHAKE_RECORD), None),
+            }
+        }
+        Ok(Err(err)) => return Err(err),
+    };
+
+    let is_http = !is_tls && HTTP_METHODS.iter().any(|method| peeked.starts_with(method));
+
+    Ok(ClientHello {
+        stream: PeekedStream {
+            peeked,
+            position: 0,
+            inner: stream,
+        },
+        is_tls,
+        server_name,
+        is_http,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    fn client_hello(server_name: Option<&str>) -> Vec<u8> {
+        // An unrelated extension, supported versions, precedes the server name.
+        let mut extensions = vec![0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04];
+
+        if let Some(server_name) = server_name {
+            let mut list = vec![TLS_HOST_NAME];
+            list.extend_from_slice(&(server_name.len() as u16).to_be_bytes());
+            list.extend_from_slice(server_name.as_bytes());
+
+            extensions.extend_from_slice(&TLS_SERVER_NAME_EXTENSION.to_be_bytes());
+            extensions.extend_from_slice(&(list.len() as u16 + 2).to_be_bytes());
+            extensions.extend_from_slice(&(list.len() as u16).to_be_bytes());
+            extensions.extend_from_slice(&list);
+        }
+
+        // Legacy version and random, session id, cipher suites and compression methods.
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0; 32]);
+        body.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut handshake = vec![TLS_CLIENT_HELLO];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&body);
+
+        let mut record = vec![TLS_HANDSHAKE_RECORD, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+
+        record
+    }
+
+    #[test]
+    fn test_parse_client_hello() {
+        assert!(matches!(
+            parse_client_hello(&client_hello(Some("Example.com"))),
+            ParsedClientHello::Complete { server_name: Some(server_name) } if server_name == "example.com"
+        ));
+        assert!(matches!(
+            parse_client_hello(&client_hello(None)),
+            ParsedClientHello::Complete { server_name: None }
+        ));
+    }
+
+    #[test]
+    fn test_parse_incomplete_client_hello() {
+        let client_hello = client_hello(Some("example.com"));
+
+        for length in [0, 3, TLS_RECORD_HEADER_LENGTH, client_hello.len() - 1] {
+            assert!(matches!(
+                parse_client_hello(&client_hello[..length]),
+                ParsedClientHello::Incomplete
+            ));
+        }
+    }
+
+    #[test]
+    fn test_parse_invalid_client_hello() {
+        assert!(matches!(
+            parse_client_hello(b"GET / HTTP/1.1\r\n"),
+            ParsedClientHello::Invalid
+        ));
+
+        // Handshake records which don't hold a ClientHello carry no server name.
+        let mut server_hello = client_hello(Some("example.com"));
+        server_hello[TLS_RECORD_HEADER_LENGTH] = 0x02;
+
+        assert!(matches!(
+            parse_client_hello(&server_hello),
+            ParsedClientHello::Complete { server_name: None }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_peek_client_hello() {
+        let client_hello = client_hello(Some("example.com"));
+        let (mut client, server) = tokio::io::duplex(MAX_CLIENT_HELLO_LENGTH);
+
+        // The ClientHello is split across writes.
+        client.write_all(&client_hello[..10]).await.unwrap();
+        let (peeked, _result) = tokio::join!(
+            peek_client_hello(server),
+            client.write_all(&client_hello[10..])
+        );
+        let mut peeked = peeked.unwrap();
+
+        assert!(peeked.is_tls);
+        assert_eq!(peeked.server_name.as_deref(), Some("example.com"));
+
+        drop(client);
+        let mut replayed = Vec::new();
+        peeked.stream.read_to_end(&mut replayed).await.unwrap();
+
+        assert_eq!(replayed, client_hello);
+    }
+
+    #[tokio::test]
+    async fn test_peek_non_tls_client() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(b"SSH-2.0-OpenSSH\r\n").await.unwrap();
+
+        let mut peeked = peek_client_hello(server).await.unwrap();
+
+        assert!(!peeked.is_tls);
+        assert!(!peeked.is_http);
+        assert_eq!(peeked.server_name, None);
+
+        drop(client);
+        let mut replayed = Vec::new();
+        peeked.stream.read_to_end(&mut replayed).await.unwrap();
+
+        assert_eq!(replayed, b"SSH-2.0-OpenSSH\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_peek_http_client() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        client
+            .write_all(b"GET /chat HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .await
+            .unwrap();
+
+        let peeked = peek_client_hello(server).await.unwrap();
+
+        assert!(!peeked.is_tls);
+        assert!(peeked.is_http);
+    }
+}
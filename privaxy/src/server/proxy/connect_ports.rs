@@ -0,0 +1,100 @@
+//! Ports clients may open tunnels towards, through `CONNECT` requests or SOCKS5.
+//!
+//! Tunnels carrying TLS or plaintext HTTP are filtered, anything else is passed along unfiltered.
+//! Any port is allowed by default.
+use std::{ops::RangeInclusive, str::FromStr, sync::Arc};
+
+// Tunnels without an explicit port are opened towards HTTPS servers.
+const DEFAULT_PORT: u16 = 443;
+
+fn parse_port_range(rule: &str) -> Option<RangeInclusive<u16>> {
+    let rule = rule.trim();
+
+    match rule.split_once('-') {
+        Some((start, end)) => {
+            let start = u16::from_str(start.trim()).ok()?;
+            let end = u16::from_str(end.trim()).ok()?;
+
+            if start <= end {
+                Some(start..=end)
+            } else {
+                None
+            }
+        }
+        None => {
+            let port = u16::from_str(rule).ok()?;
+
+            Some(port..=port)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ConnectPorts(Arc<Vec<RangeInclusive<u16>>>);
+
+impl ConnectPorts {
+    pub(crate) fn new(allowed_ports: &[String]) -> Self {
+        let port_ranges = allowed_ports
+            .iter()
+            .filter_map(|rule| {
+                let port_range = parse_port_range(rule);
+
+                if port_range.is_none() {
+                    log::warn!("Ignoring invalid allowed connect port: {}", rule);
+                }
+
+                port_range
+            })
+            .collect();
+
+        Self(Arc::new(port_ranges))
+    }
+
+    pub(crate) fn is_allowed(&self, port: Option<u16>) -> bool {
+        let port = port.unwrap_or(DEFAULT_PORT);
+
+        self.0.iter().any(|port_range| port_range.contains(&port))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_port_range() {
+        assert_eq!(parse_port_range("443"), Some(443..=443));
+        assert_eq!(parse_port_range(" 8443 "), Some(8443..=8443));
+        assert_eq!(parse_port_range("8000-8999"), Some(8000..=8999));
+        assert_eq!(parse_port_range("1 - 65535"), Some(1..=65535));
+        assert_eq!(parse_port_range("80-80"), Some(80..=80));
+    }
+
+    #[test]
+    fn test_parse_invalid_port_range() {
+        assert_eq!(parse_port_range(""), None);
+        assert_eq!(parse_port_range("https"), None);
+        assert_eq!(parse_port_range("65536"), None);
+        assert_eq!(parse_port_range("-443"), None);
+        assert_eq!(parse_port_range("443-"), None);
+        assert_eq!(parse_port_range("8999-8000"), None);
+        assert_eq!(parse_port_range("1-2-3"), None);
+    }
+
+    #[test]
+    fn test_is_allowed() {
+        let connect_ports = ConnectPorts::new(&[
+            String::from("443"),
+            String::from("8000-8999"),
+            String::from("invalid"),
+        ]);
+
+        assert!(connect_ports.is_allowed(Some(443)));
+        assert!(connect_ports.is_allowed(Some(8000)));
+        assert!(connect_ports.is_allowed(Some(8999)));
+        assert!(connect_ports.is_allowed(None));
+        assert!(!connect_ports.is_allowed(Some(22)));
+        assert!(!connect_ports.is_allowed(Some(80)));
+        assert!(!connect_ports.is_allowed(Some(9000)));
+    }
+}
@@ -0,0 +1,292 @@
+//! Handling of documents' content security policies, which may prevent the scripts Privaxy
+//! injects from running.
+//!
+//! See: https://www.w3.org/TR/CSP3/
+use http::header::{HeaderMap, HeaderValue, CONTENT_SECURITY_POLICY};
+
+// Directives governing inline scripts, from the most to the least specific.
+const SCRIPT_DIRECTIVES: [&str; 3] = ["script-src-elem", "script-src", "default-src"];
+const HASH_SOURCE_PREFIXES: [&str; 3] = ["'sha256-", "'sha384-", "'sha512-"];
+const NONCE_SOURCE_PREFIX: &str = "'nonce-";
+const NONCE_LENGTH: usize = 18;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ScriptInjection {
+    /// Inline scripts are allowed to run as is.
+    Allowed,
+    /// Inline scripts must carry the given nonce to be allowed to run.
+    Nonce(String),
+    /// The document's policy doesn't allow injected scripts to run at all.
+    Impossible,
+    /// No script was to be injected when the response headers were sent, so the document's
+    /// policy was left untouched.
+    Unprepared,
+}
+
+#[derive(Debug)]
+struct Directive {
+    name: String,
+    values: Vec<String>,
+}
+
+type Policy = Vec<Directive>;
+
+fn parse_policy(serialized_policy: &str) -> Policy {
+    serialized_policy
+        .split(';')
+        .filter_map(|directive| {
+            let mut tokens = directive.split_ascii_whitespace();
+            let name = tokens.next()?.to_lowercase();
+
+            Some(Directive {
+                name,
+                values: tokens.map(|token| token.to_string()).collect(),
+            })
+        })
+        .collect()
+}
+
+fn serialize_policy(policy: &Policy) -> String {
+    policy
+        .iter()
+        .map(|directive| {
+            std::iter::once(directive.name.as_str())
+                .chain(directive.values.iter().map(|value| value.as_str()))
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+fn script_directive(policy: &mut Policy) -> Option<&mut Directive> {
+    let directive_name = SCRIPT_DIRECTIVES
+        .iter()
+        .find(|name| policy.iter().any(|directive| &directive.name == *name))?;
+
+    policy
+        .iter_mut()
+        .find(|directive| &directive.name == directive_name)
+}
+
+fn is_sandboxed_without_scripts(policy: &Policy) -> bool {
+    policy.iter().any(|directive| {
+        directive.name == "sandbox"
+            && !directive
+                .values
+                .iter()
+                .any(|value| value.eq_ignore_ascii_case("allow-scripts"))
+    })
+}
+
+fn allows_inline_scripts(directive: &Directive) -> bool {
+    let has_keyword = |keyword: &str| {
+        directive
+            .values
+            .iter()
+            .any(|value| value.eq_ignore_ascii_case(keyword))
+    };
+
+    // `'unsafe-inline'` is ignored as soon as a nonce, a hash or `'strict-dynamic'` is present.
+    let has_nonce_or_hash = directive.values.iter().any(|value| {
+        let value = value.to_lowercase();
+
+        value.starts_with(NONCE_SOURCE_PREFIX)
+            || HASH_SOURCE_PREFIXES
+                .iter()
+                .any(|prefix| value.starts_with(prefix))
+    });
+
+    has_keyword("'unsafe-inline'") && !has_nonce_or_hash && !has_keyword("'strict-dynamic'")
+}
+
+fn nonces(directive: &Directive) -> impl Iterator<Item = &str> {
+    directive.values.iter().filter_map(|value| {
+        value
+            .strip_prefix(NONCE_SOURCE_PREFIX)
+            .and_then(|nonce| nonce.strip_suffix('\''))
+    })
+}
+
+fn generate_nonce() -> String {
+    let mut bytes = [0; NONCE_LENGTH];
+    openssl::rand::rand_bytes(&mut bytes).unwrap();
+
+    base64::encode(bytes)
+}
+
+/// Inspects the content security policies of a document response and works out how an inline
+/// script has to be injected for it to run.
+///
+/// When policies restrict inline scripts, the nonce used by the document is reused if every
+/// policy accepts it. Otherwise, a nonce is generated and added to the policies' headers.
+///
+/// Policies delivered through `<meta http-equiv>` elements are not taken into account.
+pub(crate) fn prepare_script_injection(headers: &mut HeaderMap) -> ScriptInjection {
+    let header_values = match headers
+        .get_all(CONTENT_SECURITY_POLICY)
+        .iter()
+        .map(|value| value.to_str().map(|value| value.to_string()))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(header_values) => header_values,
+        // We wouldn't be able to amend a policy we can't read.
+        Err(_err) => return ScriptInjection::Impossible,
+    };
+
+    // A single header may hold several comma separated policies, every one of them
+    // has to allow our script.
+    let mut policies = header_values
+        .iter()
+        .flat_map(|header_value| header_value.split(','))
+        .map(parse_policy)
+        .collect::<Vec<_>>();
+
+    if policies.iter().any(is_sandboxed_without_scripts) {
+        return ScriptInjection::Impossible;
+    }
+
+    let mut restrictive_directives = policies
+        .iter_mut()
+        .filter_map(script_directive)
+        .filter(|directive| !allows_inline_scripts(directive))
+        .collect::<Vec<_>>();
+
+    if restrictive_directives.is_empty() {
+        return ScriptInjection::Allowed;
+    }
+
+    let shared_nonce = nonces(restrictive_directives[0])
+        .find(|nonce| {
+            restrictive_directives
+                .iter()
+                .all(|directive| nonces(directive).any(|other_nonce| other_nonce == *nonce))
+        })
+        .map(|nonce| nonce.to_string());
+
+    if let Some(nonce) = shared_nonce {
+        return ScriptInjection::Nonce(nonce);
+    }
+
+    let nonce = generate_nonce();
+
+    for directive in restrictive_directives.iter_mut() {
+        // `'none'` doesn't mix with other sources.
+        directive
+            .values
+            .retain(|value| !value.eq_ignore_ascii_case("'none'"));
+        directive
+            .values
+            .push(format!("{}{}'", NONCE_SOURCE_PREFIX, nonce));
+    }
+
+    headers.remove(CONTENT_SECURITY_POLICY);
+
+    for policy in policies.iter() {
+        match HeaderValue::from_str(&serialize_policy(policy)) {
+            Ok(header_value) => headers.append(CONTENT_SECURITY_POLICY, header_value),
+            Err(_err) => return ScriptInjection::Impossible,
+        };
+    }
+
+    ScriptInjection::Nonce(nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(policies: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        for policy in policies {
+            headers.append(
+                CONTENT_SECURITY_POLICY,
+                HeaderValue::from_str(policy).unwrap(),
+            );
+        }
+
+        headers
+    }
+
+    fn policies(headers: &HeaderMap) -> Vec<&str> {
+        headers
+            .get_all(CONTENT_SECURITY_POLICY)
+            .iter()
+            .map(|value| value.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_without_policy() {
+        let mut headers = headers(&[]);
+
+        assert_eq!(
+            prepare_script_injection(&mut headers),
+            ScriptInjection::Allowed
+        );
+        assert!(policies(&headers).is_empty());
+    }
+
+    #[test]
+    fn test_unsafe_inline() {
+        let mut headers = headers(&["default-src 'self'; script-src 'self' 'unsafe-inline'"]);
+
+        assert_eq!(
+            prepare_script_injection(&mut headers),
+            ScriptInjection::Allowed
+        );
+        assert_eq!(
+            policies(&headers),
+            ["default-src 'self'; script-src 'self' 'unsafe-inline'"]
+        );
+    }
+
+    #[test]
+    fn test_shared_nonce_is_reused() {
+        let mut headers = headers(&[
+            "script-src 'nonce-abc' 'nonce-def'",
+            "default-src 'nonce-def'",
+        ]);
+
+        assert_eq!(
+            prepare_script_injection(&mut headers),
+            ScriptInjection::Nonce(String::from("def"))
+        );
+        assert_eq!(
+            policies(&headers),
+            [
+                "script-src 'nonce-abc' 'nonce-def'",
+                "default-src 'nonce-def'"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nonce_is_added() {
+        let mut headers = headers(&["img-src *; script-src 'none', default-src 'self'"]);
+
+        let nonce = match prepare_script_injection(&mut headers) {
+            ScriptInjection::Nonce(nonce) => nonce,
+            script_injection => panic!("unexpected script injection: {:?}", script_injection),
+        };
+
+        assert_eq!(
+            policies(&headers),
+            [
+                format!("img-src *; script-src 'nonce-{}'", nonce),
+                format!("default-src 'self' 'nonce-{}'", nonce)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sandbox_without_scripts() {
+        let mut headers = headers(&["sandbox allow-forms"]);
+
+        assert_eq!(
+            prepare_script_injection(&mut headers),
+            ScriptInjection::Impossible
+        );
+    }
+}
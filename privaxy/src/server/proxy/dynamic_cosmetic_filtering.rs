@@ -0,0 +1,226 @@
+//! Cosmetic filtering of elements inserted into documents after they have been loaded.
+//!
+//! A script injected into documents reports the classes and ids of inserted elements to an
+//! endpoint served by the proxy on the document's own origin, and hides elements matching the
+//! selectors it gets back. Each document is issued a token, so the endpoint only answers to pages
+//! Privaxy injected the script into.
+use crate::blocker::AdblockRequester;
+use http::{header, HeaderValue, Method, StatusCode, Uri};
+use hyper::body::HttpBody;
+use hyper::{Body, Request, Response};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use uluru::LRUCache;
+
+pub(crate) const ENDPOINT_PATH: &str = "/__privaxy__/cosmetic-filters";
+const MAX_PAGE_TOKENS: usize = 1_000;
+const TOKEN_LENGTH: usize = 18;
+// Requests only hold class names and ids, anything larger is not coming from our script.
+const MAX_REQUEST_BODY_SIZE: usize = 1024 * 1024;
+
+struct PageToken {
+    token: String,
+    url: String,
+}
+
+/// Tokens issued to the documents the script was injected into, along with their url.
+#[derive(Clone)]
+pub(crate) struct PageTokens(Arc<Mutex<LRUCache<PageToken, MAX_PAGE_TOKENS>>>);
+
+impl PageTokens {
+    pub(crate) fn new() -> Self {
+        Self(Arc::new(Mutex::new(LRUCache::default())))
+    }
+
+    pub(crate) fn issue(&self, url: String) -> String {
+        let mut bytes = [0; TOKEN_LENGTH];
+        openssl::rand::rand_bytes(&mut bytes).unwrap();
+
+        let token = base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+
+        self.0.lock().unwrap().insert(PageToken {
+            token: token.clone(),
+            url,
+        });
+
+        token
+    }
+
+    fn page_url(&self, token: &str) -> Option<String> {
+        self.0
+            .lock()
+            .unwrap()
+            .find(|page_token| page_token.token == token)
+            .map(|page_token| page_token.url.clone())
+    }
+}
+
+#[derive(Deserialize)]
+struct CosmeticFiltersRequest {
+    token: String,
+    ids: Vec<String>,
+    classes: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct CosmeticFiltersResponse {
+    hidden_selectors: Vec<String>,
+}
+
+/// Returns the script to inject into the document the token was issued to.
+pub(crate) fn script(token: &str) -> String {
+    include_str!("../../resources/dynamic_cosmetic_filtering.js")
+        .replace("#{endpoint}#", ENDPOINT_PATH)
+        .replace("#{token}#", token)
+}
+
+pub(crate) async fn serve_endpoint(
+    request: Request<Body>,
+    uri: &Uri,
+    page_tokens: PageTokens,
+    adblock_requester: AdblockRequester,
+) -> Response<Body> {
+    if request.method() != Method::POST {
+        return get_status_response(StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    let is_too_large = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|content_length| content_length.to_str().ok())
+        .and_then(|content_length| content_length.parse::<usize>().ok())
+        .map_or(false, |content_length| {
+            content_length > MAX_REQUEST_BODY_SIZE
+        });
+
+    if is_too_large {
+        return get_status_response(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    let body = match read_body(request.into_body()).await {
+        Ok(Some(body)) => body,
+        Ok(None) => return get_status_response(StatusCode::PAYLOAD_TOO_LARGE),
+        Err(_err) => return get_status_response(StatusCode::BAD_REQUEST),
+    };
+
+    let cosmetic_filters_request = match serde_json::from_slice::<CosmeticFiltersRequest>(&body) {
+        Ok(cosmetic_filters_request) => cosmetic_filters_request,
+        Err(_err) => return get_status_response(StatusCode::BAD_REQUEST),
+    };
+
+    // Tokens are only valid on the origin of the page they were issued to.
+    let page_url = match page_tokens.page_url(&cosmetic_filters_request.token) {
+        Some(page_url)
+            if page_url.parse::<Uri>().ok().as_ref().and_then(Uri::host) == uri.host() =>
+        {
+            page_url
+        }
+        _ => return get_status_response(StatusCode::FORBIDDEN),
+    };
+
+    let blocker_result = adblock_requester
+        .get_cosmetic_response(
+            page_url,
+            cosmetic_filters_request.ids,
+            cosmetic_filters_request.classes,
+        )
+        .await;
+
+    let response_body = serde_json::to_string(&CosmeticFiltersResponse {
+        hidden_selectors: blocker_result.hidden_selectors,
+    })
+    .unwrap();
+
+    let mut response = Response::new(Body::from(response_body));
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    response
+        .headers_mut()
+        .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+
+    response
+}
+
+/// Reads a request body, unless it is larger than `MAX_REQUEST_BODY_SIZE`.
+async fn read_body(mut body: Body) -> Result<Option<Vec<u8>>, hyper::Error> {
+    let mut bytes = Vec::new();
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+
+        if bytes.len() + chunk.len() > MAX_REQUEST_BODY_SIZE {
+            return Ok(None);
+        }
+
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(Some(bytes))
+}
+
+fn get_status_response(status_code: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status_code;
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::body::Bytes;
+
+    #[tokio::test]
+    async fn test_read_body() {
+        let body = Body::from(vec![b'a'; MAX_REQUEST_BODY_SIZE]);
+        assert_eq!(
+            read_body(body).await.unwrap().unwrap().len(),
+            MAX_REQUEST_BODY_SIZE
+        );
+
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            // Never ending, reading has to stop at the limit.
+            while sender
+                .send_data(Bytes::from_static(&[b'a'; 1024]))
+                .await
+                .is_ok()
+            {}
+        });
+        assert!(read_body(body).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_content_length_is_rejected() {
+        let request = Request::post("https://example.com/__privaxy__/cosmetic-filters")
+            .header(header::CONTENT_LENGTH, MAX_REQUEST_BODY_SIZE + 1)
+            .body(Body::empty())
+            .unwrap();
+
+        let (adblock_request_sender, _adblock_request_receiver) = crossbeam_channel::unbounded();
+
+        let response = serve_endpoint(
+            request,
+            &Uri::from_static("https://example.com/__privaxy__/cosmetic-filters"),
+            PageTokens::new(),
+            AdblockRequester::new(adblock_request_sender),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn test_page_tokens() {
+        let page_tokens = PageTokens::new();
+        let token = page_tokens.issue(String::from("https://example.com/"));
+
+        assert_eq!(
+            page_tokens.page_url(&token).as_deref(),
+            Some("https://example.com/")
+        );
+        assert!(page_tokens.page_url("unknown").is_none());
+    }
+}
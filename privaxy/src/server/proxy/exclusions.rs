@@ -1,30 +1,39 @@
+use crate::configuration::{save_auto_exclusions, ConfigurationError};
 use lazy_static::lazy_static;
-use std::sync::{Arc, RwLock};
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use uluru::LRUCache;
 use wildmatch::WildMatch;
 
 #[derive(Debug, Clone)]
-struct WildMatchCollection(Vec<WildMatch>);
+struct WildMatchCollection {
+    patterns: Vec<String>,
+    matchers: Vec<WildMatch>,
+}
 
 impl WildMatchCollection {
     fn new(patterns: Vec<String>) -> Self {
-        Self(
-            patterns
-                .into_iter()
-                .map(|pattern| {
-                    // Making things case insensitive
-
-                    let pattern_lowercase = pattern.to_lowercase();
-                    WildMatch::new(&pattern_lowercase)
-                })
+        // Making things case insensitive
+        let patterns = patterns
+            .into_iter()
+            .map(|pattern| pattern.to_lowercase())
+            .collect::<Vec<_>>();
+
+        Self {
+            matchers: patterns
+                .iter()
+                .map(|pattern| WildMatch::new(pattern))
                 .collect(),
-        )
+            patterns,
+        }
     }
 
     fn is_match(&self, element: &str) -> bool {
         // Making things case insensitive
         let lowercase_element = element.to_lowercase();
 
-        self.0
+        self.matchers
             .iter()
             .any(|pattern| pattern.matches(&lowercase_element))
     }
@@ -77,26 +86,304 @@ lazy_static! {
     };
 }
 
+// Handshakes a host's clients must abort in a row before it gets excluded automatically. A single
+// aborted handshake may be caused by a client going away.
+const AUTO_EXCLUSION_THRESHOLD: u8 = 3;
+
+type HandshakeFailures = LRUCache<(IpAddr, String, u8), 1_000>;
+type TrustingClients = LRUCache<(IpAddr, String, Option<String>), 1_000>;
+
 #[derive(Debug, Clone)]
-pub struct LocalExclusionStore(Arc<RwLock<WildMatchCollection>>);
+pub struct LocalExclusionStore {
+    exclusions: Arc<RwLock<WildMatchCollection>>,
+    // Hosts whose clients don't tolerate TLS interception, such as certificate pinning apps.
+    auto_exclusions: Arc<RwLock<BTreeSet<String>>>,
+    // Handshakes aborted in a row, per client and host.
+    handshake_failures: Arc<Mutex<HandshakeFailures>>,
+    // The last two distinct hosts clients completed intercepted handshakes with, which proves they
+    // trust the CA.
+    trusting_clients: Arc<Mutex<TrustingClients>>,
+    auto_exclusions_save_lock: Arc<tokio::sync::Mutex<()>>,
+}
 
 impl LocalExclusionStore {
-    pub fn new(exclusions: Vec<String>) -> Self {
-        let collection = WildMatchCollection::new(exclusions);
-        Self(Arc::new(RwLock::new(collection)))
+    pub fn new(exclusions: Vec<String>, auto_exclusions: BTreeSet<String>) -> Self {
+        Self {
+            exclusions: Arc::new(RwLock::new(WildMatchCollection::new(exclusions))),
+            auto_exclusions: Arc::new(RwLock::new(auto_exclusions)),
+            handshake_failures: Arc::new(Mutex::new(LRUCache::default())),
+            trusting_clients: Arc::new(Mutex::new(LRUCache::default())),
+            auto_exclusions_save_lock: Arc::new(tokio::sync::Mutex::new(())),
+        }
     }
 
     pub fn replace_exclusions(&mut self, exclusions: Vec<String>) {
-        let new_exclusion_store = LocalExclusionStore::new(exclusions);
-
-        *self.0.write().unwrap() = new_exclusion_store.0.read().unwrap().clone();
+        *self.exclusions.write().unwrap() = WildMatchCollection::new(exclusions);
     }
 
     pub fn contains(&self, element: &str) -> bool {
         if DEFAULT_EXCLUSIONS.is_match(element) {
             true
         } else {
-            self.0.read().unwrap().is_match(element)
+            self.exclusions.read().unwrap().is_match(element)
+                || self
+                    .auto_exclusions
+                    .read()
+                    .unwrap()
+                    .contains(&element.to_lowercase())
+        }
+    }
+
+    /// Every excluded pattern, default and automatic exclusions included.
+    pub fn patterns(&self) -> Vec<String> {
+        DEFAULT_EXCLUSIONS
+            .patterns
+            .iter()
+            .chain(self.exclusions.read().unwrap().patterns.iter())
+            .chain(self.auto_exclusions.read().unwrap().iter())
+            .cloned()
+            .collect()
+    }
+
+    pub fn auto_exclusions(&self) -> Vec<String> {
+        Vec::from_iter(self.auto_exclusions.read().unwrap().iter().cloned())
+    }
+
+    pub(crate) fn record_handshake_success(&self, client_ip_address: IpAddr, host: &str) {
+        let host = host.to_lowercase();
+
+        if let Some((_client_ip_address, _host, failures)) =
+            self.handshake_failures.lock().unwrap().find(
+                |(client_ip_address_, host_, _failures)| {
+                    *client_ip_address_ == client_ip_address && *host_ == host
+                },
+            )
+        {
+            *failures = 0;
+        }
+
+        let mut trusting_clients = self.trusting_clients.lock().unwrap();
+
+        match trusting_clients.find(|(client_ip_address_, _last_host, _previous_host)| {
+            *client_ip_address_ == client_ip_address
+        }) {
+            Some((_client_ip_address, last_host, previous_host)) => {
+                if *last_host != host {
+                    *previous_host = Some(std::mem::replace(last_host, host));
+                }
+            }
+            None => {
+                trusting_clients.insert((client_ip_address, host, None));
+            }
+        }
+    }
+
+    /// Whether the client completed an intercepted handshake with another host than `host`, in
+    /// which case it trusts the CA and its failures are caused by the host's service.
+    fn trusts_ca(&self, client_ip_address: IpAddr, host: &str) -> bool {
+        self.trusting_clients
+            .lock()
+            .unwrap()
+            .find(|(client_ip_address_, _last_host, _previous_host)| {
+                *client_ip_address_ == client_ip_address
+            })
+            .map(|(_client_ip_address, last_host, previous_host)| {
+                *last_host != host || previous_host.is_some()
+            })
+            .unwrap_or(false)
+    }
+
+    /// Records a handshake the client aborted, excluding the host once this happened too many
+    /// times in a row.
+    ///
+    /// Clients which don't trust the CA abort every handshake, failures are only counted for
+    /// clients which are known to trust it.
+    pub(crate) async fn record_handshake_failure(&self, client_ip_address: IpAddr, host: &str) {
+        let host = host.to_lowercase();
+
+        if !self.trusts_ca(client_ip_address, &host) {
+            log::debug!(
+                "Ignoring aborted handshake of {} for host {}, the client may not trust the CA",
+                client_ip_address,
+                host
+            );
+
+            return;
+        }
+
+        let failures = {
+            let mut handshake_failures = self.handshake_failures.lock().unwrap();
+
+            match handshake_failures.find(|(client_ip_address_, host_, _failures)| {
+                *client_ip_address_ == client_ip_address && *host_ == host
+            }) {
+                Some((_client_ip_address, _host, failures)) => {
+                    *failures = failures.saturating_add(1);
+                    *failures
+                }
+                None => {
+                    handshake_failures.insert((client_ip_address, host.clone(), 1));
+                    1
+                }
+            }
+        };
+
+        if failures < AUTO_EXCLUSION_THRESHOLD {
+            return;
+        }
+
+        if !self.auto_exclusions.write().unwrap().insert(host.clone()) {
+            return;
+        }
+
+        log::warn!(
+            "A client aborted {} handshakes in a row for host: {}. It is now excluded automatically and will be tunneled.",
+            failures,
+            host
+        );
+
+        if let Err(err) = self.save_auto_exclusions().await {
+            log::error!("Unable to save automatic exclusions: {:?}", err);
+        }
+    }
+
+    pub async fn remove_auto_exclusion(&self, host: &str) -> Result<(), ConfigurationError> {
+        let host = host.to_lowercase();
+
+        self.auto_exclusions.write().unwrap().remove(&host);
+
+        // Every client has to fail the whole threshold again.
+        {
+            let mut handshake_failures = self.handshake_failures.lock().unwrap();
+
+            while let Some((_client_ip_address, _host, failures)) = handshake_failures
+                .find(|(_client_ip_address, host_, failures)| *host_ == host && *failures > 0)
+            {
+                *failures = 0;
+            }
+        }
+
+        self.save_auto_exclusions().await
+    }
+
+    pub async fn clear_auto_exclusions(&self) -> Result<(), ConfigurationError> {
+        self.auto_exclusions.write().unwrap().clear();
+        // Hosts only get excluded again after failing the whole threshold again.
+        self.handshake_failures.lock().unwrap().clear();
+
+        self.save_auto_exclusions().await
+    }
+
+    async fn save_auto_exclusions(&self) -> Result<(), ConfigurationError> {
+        // Saves are serialized so that the last one always wins.
+        let _guard = self.auto_exclusions_save_lock.lock().await;
+
+        let auto_exclusions = self.auto_exclusions.read().unwrap().clone();
+
+        save_auto_exclusions(&auto_exclusions).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT_IP_ADDRESS: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 1, 10));
+
+    fn handshake_failures(local_exclusion_store: &LocalExclusionStore, host: &str) -> u8 {
+        local_exclusion_store
+            .handshake_failures
+            .lock()
+            .unwrap()
+            .find(|(client_ip_address, host_, _failures)| {
+                *client_ip_address == CLIENT_IP_ADDRESS && host_ == host
+            })
+            .map(|(_client_ip_address, _host, failures)| *failures)
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn test_contains() {
+        let local_exclusion_store = LocalExclusionStore::new(
+            vec![String::from("*.Example.com")],
+            BTreeSet::from([String::from("pinned.example.org")]),
+        );
+
+        assert!(local_exclusion_store.contains("www.example.COM"));
+        assert!(local_exclusion_store.contains("Pinned.example.org"));
+        assert!(local_exclusion_store.contains("setup.icloud.com"));
+        assert!(!local_exclusion_store.contains("example.org"));
+    }
+
+    #[test]
+    fn test_patterns() {
+        let mut local_exclusion_store = LocalExclusionStore::new(
+            vec![String::from("*.example.com")],
+            BTreeSet::from([String::from("pinned.example.org")]),
+        );
+        local_exclusion_store.replace_exclusions(vec![String::from("*.example.net")]);
+
+        let patterns = local_exclusion_store.patterns();
+
+        assert!(patterns.contains(&String::from("*.apple.com")));
+        assert!(patterns.contains(&String::from("*.example.net")));
+        assert!(patterns.contains(&String::from("pinned.example.org")));
+        assert!(!patterns.contains(&String::from("*.example.com")));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_failures_of_client_not_trusting_ca() {
+        let local_exclusion_store = LocalExclusionStore::new(Vec::new(), BTreeSet::new());
+
+        for _ in 0..AUTO_EXCLUSION_THRESHOLD {
+            local_exclusion_store
+                .record_handshake_failure(CLIENT_IP_ADDRESS, "example.com")
+                .await;
+        }
+
+        assert_eq!(handshake_failures(&local_exclusion_store, "example.com"), 0);
+        assert!(!local_exclusion_store.contains("example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_failures_of_host_only_trusted_by_client() {
+        let local_exclusion_store = LocalExclusionStore::new(Vec::new(), BTreeSet::new());
+
+        // Succeeding with the same host doesn't tell anything about the CA being trusted.
+        local_exclusion_store.record_handshake_success(CLIENT_IP_ADDRESS, "example.com");
+        local_exclusion_store
+            .record_handshake_failure(CLIENT_IP_ADDRESS, "example.com")
+            .await;
+
+        assert_eq!(handshake_failures(&local_exclusion_store, "example.com"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_failures_of_client_trusting_ca() {
+        let local_exclusion_store = LocalExclusionStore::new(Vec::new(), BTreeSet::new());
+        let other_client_ip_address = IpAddr::from([192, 168, 1, 11]);
+
+        local_exclusion_store.record_handshake_success(CLIENT_IP_ADDRESS, "example.org");
+        local_exclusion_store.record_handshake_success(CLIENT_IP_ADDRESS, "example.com");
+
+        for _ in 1..AUTO_EXCLUSION_THRESHOLD {
+            local_exclusion_store
+                .record_handshake_failure(CLIENT_IP_ADDRESS, "Example.com")
+                .await;
+            local_exclusion_store
+                .record_handshake_failure(other_client_ip_address, "example.com")
+                .await;
         }
+
+        assert_eq!(
+            handshake_failures(&local_exclusion_store, "example.com"),
+            AUTO_EXCLUSION_THRESHOLD - 1
+        );
+        assert!(!local_exclusion_store.contains("example.com"));
+
+        // Failures only count in a row.
+        local_exclusion_store.record_handshake_success(CLIENT_IP_ADDRESS, "EXAMPLE.com");
+
+        assert_eq!(handshake_failures(&local_exclusion_store, "example.com"), 0);
     }
 }
@@ -1,12 +1,32 @@
+use super::charset;
+use super::csp::ScriptInjection;
+use super::dynamic_cosmetic_filtering;
+use crate::configuration::HtmlRewriterConfiguration;
+use crate::html_filters::{HasText, HtmlFilter, RAW_TEXT_ELEMENTS};
 use crate::{blocker::AdblockRequester, statistics::Statistics};
-use crossbeam_channel::Receiver;
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use encoding_rs::Encoding;
 use hyper::body::Bytes;
-use lol_html::{element, HtmlRewriter, Settings};
+use lol_html::html_content::{ContentType, Element};
+use lol_html::{
+    element, AsciiCompatibleEncoding, ElementContentHandlers, HtmlRewriter, MemorySettings,
+    Selector, Settings,
+};
 use regex::Regex;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
 use std::collections::HashSet;
 use std::fmt::Write;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync;
 
+// Elements held back by `:has-text()` filters with more text than this are let through, so
+// that huge inline scripts don't end up entirely buffered.
+const MAX_HELD_BACK_TEXT_LENGTH: usize = 256 * 1024;
+
 type InternalBodyChannel = (
     sync::mpsc::UnboundedSender<(Bytes, Option<AdblockProperties>)>,
     sync::mpsc::UnboundedReceiver<(Bytes, Option<AdblockProperties>)>,
@@ -16,24 +36,39 @@ struct AdblockProperties {
     url: String,
     ids: HashSet<String>,
     classes: HashSet<String>,
+    removed_html_elements: u64,
+    encoding: &'static Encoding,
 }
 
 pub struct Rewriter {
     url: String,
     adblock_requester: AdblockRequester,
-    receiver: Receiver<Bytes>,
+    receiver: Receiver<Result<Bytes, reqwest::Error>>,
     body_sender: hyper::body::Sender,
     statistics: Statistics,
+    html_filters: Vec<HtmlFilter>,
+    script_injection: ScriptInjection,
+    page_token: Option<String>,
+    content_type_encoding: Option<&'static Encoding>,
+    content_length: Option<u64>,
+    configuration: HtmlRewriterConfiguration,
     internal_body_channel: InternalBodyChannel,
 }
 
 impl Rewriter {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         url: String,
         adblock_requester: AdblockRequester,
-        receiver: Receiver<Bytes>,
+        receiver: Receiver<Result<Bytes, reqwest::Error>>,
         body_sender: hyper::body::Sender,
         statistics: Statistics,
+        html_filters: Vec<HtmlFilter>,
+        script_injection: ScriptInjection,
+        page_token: Option<String>,
+        content_type_encoding: Option<&'static Encoding>,
+        content_length: Option<u64>,
+        configuration: HtmlRewriterConfiguration,
     ) -> Self {
         Self {
             url,
@@ -41,6 +76,12 @@ impl Rewriter {
             statistics,
             adblock_requester,
             receiver,
+            html_filters,
+            script_injection,
+            page_token,
+            content_type_encoding,
+            content_length,
+            configuration,
             internal_body_channel: sync::mpsc::unbounded_channel(),
         }
     }
@@ -51,70 +92,256 @@ impl Rewriter {
         let adblock_requester = self.adblock_requester.clone();
         let statistics = self.statistics.clone();
 
-        let mut classes = HashSet::new();
-        let mut ids = HashSet::new();
+        let classes = RefCell::new(HashSet::new());
+        let ids = RefCell::new(HashSet::new());
+        let removed_html_elements = Rc::new(Cell::new(0));
+        let is_body_truncated = Arc::new(AtomicBool::new(false));
 
         tokio::spawn(Self::write_body(
             internal_body_receiver,
             body_sender,
+            is_body_truncated.clone(),
             adblock_requester,
             statistics,
+            self.script_injection,
+            self.page_token,
         ));
 
-        let mut rewriter = HtmlRewriter::new(
-            Settings {
-                element_content_handlers: vec![
-                    element!("*", |element| {
-                        let id = element.get_attribute("id");
+        let started_at = Instant::now();
 
-                        if let Some(id) = id {
-                            ids.insert(id);
-                        }
+        // Receiving is bounded by the rewriting deadline, so that stalled upstream servers don't
+        // delay documents. It is lifted once we give up on rewriting.
+        let deadline = Cell::new(Some(
+            started_at + Duration::from_millis(self.configuration.max_rewriting_duration),
+        ));
+        let is_deadline_exceeded = Cell::new(false);
+        let upstream_error = RefCell::new(None);
 
+        let receiver = &self.receiver;
+        let mut messages = std::iter::from_fn(|| {
+            let message = match deadline.get() {
+                Some(deadline) => match receiver.recv_deadline(deadline) {
+                    Ok(message) => message,
+                    Err(RecvTimeoutError::Timeout) => {
+                        is_deadline_exceeded.set(true);
+                        return None;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => return None,
+                },
+                None => receiver.recv().ok()?,
+            };
+
+            match message {
+                Ok(message) => Some(message),
+                Err(err) => {
+                    *upstream_error.borrow_mut() = Some(err);
+                    None
+                }
+            }
+        });
+
+        // We need the first bytes of the document to find out about its encoding.
+        let mut head = Vec::new();
+        while head.len() < charset::PRESCAN_LENGTH {
+            match messages.next() {
+                Some(message) => head.extend_from_slice(&message),
+                None => break,
+            }
+        }
+
+        let encoding = charset::detect_encoding(self.content_type_encoding, &head);
+
+        let ascii_compatible_encoding = match AsciiCompatibleEncoding::new(encoding) {
+            Some(ascii_compatible_encoding) => ascii_compatible_encoding,
+            // `lol_html` is unable to parse documents such as utf-16 encoded ones,
+            // let's pass them through untouched.
+            None => {
+                log::debug!(
+                    "Not rewriting {}, unsupported encoding: {}",
+                    self.url,
+                    encoding.name()
+                );
+
+                deadline.set(None);
+
+                let _result = internal_body_sender.send((Bytes::from(head), None));
+                for message in messages {
+                    let _result = internal_body_sender.send((message, None));
+                }
+
+                if let Some(err) = upstream_error.take() {
+                    truncate_body(&self.url, &is_body_truncated, err);
+                }
+
+                return;
+            }
+        };
+
+        let mut element_content_handlers = vec![
+            element!("*", |element| {
+                let id = element.get_attribute("id");
+
+                if let Some(id) = id {
+                    ids.borrow_mut().insert(id);
+                }
+
+                Ok(())
+            }),
+            element!("*", |element| {
+                let class = element.get_attribute("class");
+
+                if let Some(class) = class {
+                    let re = Regex::new(r"\s+").unwrap();
+                    let classes_without_duplicate_spaces = re.replace_all(&class, " ");
+
+                    let class = classes_without_duplicate_spaces
+                        .split(' ')
+                        .map(|s| s.to_string())
+                        .collect::<HashSet<_>>();
+
+                    classes.borrow_mut().extend(class);
+                }
+
+                Ok(())
+            }),
+            // Let's discard of end html and body tag
+            // to inject style and scripts before the implicit
+            // close.
+            element!("html, body", |element| {
+                element
+                    .on_end_tag(|end| {
+                        end.remove();
                         Ok(())
-                    }),
-                    element!("*", |element| {
-                        let class = element.get_attribute("class");
+                    })
+                    .unwrap();
+
+                Ok(())
+            }),
+        ];
+
+        // Raw text elements can't be nested, a single element is held back by `:has-text()`
+        // filters at once.
+        let held_back_element = Rc::new(RefCell::new(None));
+
+        element_content_handlers.extend(html_filter_handlers(
+            &self.html_filters,
+            &held_back_element,
+            &removed_html_elements,
+        ));
 
-                        if let Some(class) = class {
-                            let re = Regex::new(r"\s+").unwrap();
-                            let classes_without_duplicate_spaces = re.replace_all(&class, " ");
+        let mut pending_messages = std::iter::once(Bytes::from(head)).chain(messages);
 
-                            let class = classes_without_duplicate_spaces
-                                .split(' ')
-                                .map(|s| s.to_string())
-                                .collect::<HashSet<_>>();
+        // When set, the rest of the document is passed through and we fall back to hostname only
+        // cosmetic filtering.
+        let mut bypass_reason = match self.content_length {
+            Some(content_length) if content_length > self.configuration.max_content_length => {
+                Some(format!("content length of {} bytes", content_length))
+            }
+            _ => None,
+        };
+
+        // The message the rewriter failed on. What it emitted of it can't be told, it is passed
+        // through as a whole along with the rest of the document.
+        let mut unconsumed_message = None;
+
+        let rewriting_result = if bypass_reason.is_some() {
+            Ok(())
+        } else {
+            let mut rewriter = HtmlRewriter::new(
+                Settings {
+                    element_content_handlers,
+                    encoding: ascii_compatible_encoding,
+                    memory_settings: MemorySettings {
+                        max_allowed_memory_usage: self.configuration.max_memory_usage,
+                        ..MemorySettings::default()
+                    },
+                    ..Settings::default()
+                },
+                |c: &[u8]| {
+                    let _result = internal_body_sender.send((Bytes::copy_from_slice(c), None));
+                },
+            );
+
+            let mut document_length = 0;
+
+            loop {
+                match pending_messages.next() {
+                    Some(message) => {
+                        document_length += message.len();
 
-                            classes.extend(class);
+                        // Once the rewriter has failed, it can't be used anymore.
+                        if let Err(err) = rewriter.write(&message) {
+                            unconsumed_message = Some(message);
+                            break Err(err);
                         }
 
-                        Ok(())
-                    }),
-                    // Let's discard of end html and body tag
-                    // to inject style and scripts before the implicit
-                    // close.
-                    element!("html, body", |element| {
-                        element
-                            .on_end_tag(|end| {
-                                end.remove();
-                                Ok(())
-                            })
-                            .unwrap();
+                        bypass_reason = exceeded_threshold(
+                            &self.configuration,
+                            started_at.elapsed(),
+                            document_length,
+                            ids.borrow().len() + classes.borrow().len(),
+                        );
 
-                        Ok(())
-                    }),
-                ],
-                ..Settings::default()
-            },
-            |c: &[u8]| {
-                let _result = internal_body_sender.send((Bytes::copy_from_slice(c), None));
-            },
-        );
+                        if bypass_reason.is_some() {
+                            // Flushes what the rewriter may still be holding onto.
+                            break rewriter.end();
+                        }
+                    }
+                    None if is_deadline_exceeded.get() => {
+                        bypass_reason = Some(format!(
+                            "the document took more than {} ms to be received",
+                            self.configuration.max_rewriting_duration
+                        ));
+
+                        break rewriter.end();
+                    }
+                    None => break rewriter.end(),
+                }
+            }
+        };
+
+        // `pending_messages` keeps on receiving as its iterators are not fused.
+        deadline.set(None);
+
+        // Elements left open when rewriting stops never see their end tag. They are held back
+        // decoded, unlike the rest of the document.
+        if let Some(mut element) = held_back_element.borrow_mut().take() {
+            let element = element.take();
+            let (element, _encoding, _had_unmappable_characters) = encoding.encode(&element);
+            let _result = internal_body_sender.send((Bytes::from(element.into_owned()), None));
+        }
+
+        if let Err(err) = &rewriting_result {
+            self.statistics.increment_failed_html_rewrites();
+
+            log::warn!(
+                "Unable to rewrite {}, passing the rest of the document through: {}",
+                self.url,
+                err
+            );
+        } else if let Some(bypass_reason) = &bypass_reason {
+            log::debug!(
+                "Passing the rest of {} through without rewriting it, {}",
+                self.url,
+                bypass_reason
+            );
+        }
 
-        for message in self.receiver {
-            rewriter.write(&message).unwrap();
+        for message in unconsumed_message.into_iter().chain(pending_messages) {
+            let _result = internal_body_sender.send((message, None));
         }
-        rewriter.end().unwrap();
+
+        // The rest of the document is missing, let's not append anything to it.
+        if let Some(err) = upstream_error.take() {
+            truncate_body(&self.url, &is_body_truncated, err);
+            return;
+        }
+
+        let (ids, classes) = if rewriting_result.is_err() || bypass_reason.is_some() {
+            (HashSet::new(), HashSet::new())
+        } else {
+            (ids.take(), classes.take())
+        };
 
         let _result = internal_body_sender.send((
             Bytes::new(),
@@ -122,6 +349,8 @@ impl Rewriter {
                 ids,
                 classes,
                 url: self.url,
+                removed_html_elements: removed_html_elements.get(),
+                encoding,
             }),
         ));
     }
@@ -129,8 +358,11 @@ impl Rewriter {
     async fn write_body(
         mut receiver: sync::mpsc::UnboundedReceiver<(Bytes, Option<AdblockProperties>)>,
         mut body_sender: hyper::body::Sender,
+        is_body_truncated: Arc<AtomicBool>,
         adblock_requester: AdblockRequester,
         statistics: Statistics,
+        script_injection: ScriptInjection,
+        page_token: Option<String>,
     ) {
         while let Some((bytes, adblock_properties)) = receiver.recv().await {
             if let Err(_err) = body_sender.send_data(bytes).await {
@@ -139,9 +371,15 @@ impl Rewriter {
             if let Some(adblock_properties) = adblock_properties {
                 let mut response_has_been_modified = false;
 
+                if adblock_properties.removed_html_elements > 0 {
+                    response_has_been_modified = true;
+
+                    statistics.add_removed_html_elements(adblock_properties.removed_html_elements);
+                }
+
                 let blocker_result = adblock_requester
                     .get_cosmetic_response(
-                        adblock_properties.url,
+                        adblock_properties.url.clone(),
                         Vec::from_iter(adblock_properties.ids.into_iter()),
                         Vec::from_iter(adblock_properties.classes.into_iter()),
                     )
@@ -193,31 +431,597 @@ impl Rewriter {
                     }
                 );
 
-                if let Some(injected_script) = blocker_result.injected_script {
-                    response_has_been_modified = true;
+                let nonce_attribute = match &script_injection {
+                    ScriptInjection::Allowed => Some(String::new()),
+                    ScriptInjection::Nonce(nonce) => Some(format!(r#" nonce="{}""#, nonce)),
+                    ScriptInjection::Impossible | ScriptInjection::Unprepared => None,
+                };
+
+                let scripts = blocker_result
+                    .injected_script
+                    .into_iter()
+                    .chain(
+                        page_token
+                            .as_deref()
+                            .map(dynamic_cosmetic_filtering::script),
+                    )
+                    .collect::<Vec<_>>();
+
+                if !scripts.is_empty() {
+                    match &nonce_attribute {
+                        Some(nonce_attribute) => {
+                            response_has_been_modified = true;
 
-                    write!(
-                        to_append_to_response,
-                        r#"
+                            for script in scripts {
+                                write!(
+                                    to_append_to_response,
+                                    r#"
 <!-- Privaxy proxy -->
-<script type="application/javascript">{}</script>
+<script type="application/javascript"{}>{}</script>
 <!-- privaxy proxy -->
 "#,
-                        injected_script
-                    )
-                    .unwrap();
+                                    nonce_attribute, script
+                                )
+                                .unwrap();
+                            }
+                        }
+                        None => {
+                            statistics.increment_failed_script_injections();
+
+                            log::warn!(
+                                "Unable to inject scripts into {}, its content security policy doesn't allow it",
+                                adblock_properties.url
+                            );
+                        }
+                    }
                 }
 
                 if response_has_been_modified {
                     statistics.increment_modified_responses();
                 }
 
-                let bytes = Bytes::copy_from_slice(&to_append_to_response.into_bytes());
+                // What we append has to be encoded the same way as the rest of the document.
+                let (encoded_to_append_to_response, _encoding, _had_unmappable_characters) =
+                    adblock_properties.encoding.encode(&to_append_to_response);
+
+                let bytes = Bytes::copy_from_slice(&encoded_to_append_to_response);
 
                 if let Err(_err) = body_sender.send_data(bytes).await {
                     break;
                 }
             }
         }
+
+        // Clients must not mistake a truncated document for a complete one.
+        if is_body_truncated.load(Ordering::Acquire) {
+            body_sender.abort();
+        }
+    }
+}
+
+/// Signals that the upstream body of the document couldn't be read entirely, once the
+/// internal body channel is closed.
+fn truncate_body(url: &str, is_body_truncated: &AtomicBool, err: reqwest::Error) {
+    log::debug!("Unable to read the response body of {}: {}", url, err);
+
+    is_body_truncated.store(true, Ordering::Release);
+}
+
+/// Returns why the rest of a document should be passed through without being rewritten, if it
+/// should.
+fn exceeded_threshold(
+    configuration: &HtmlRewriterConfiguration,
+    rewriting_duration: Duration,
+    document_length: usize,
+    collected_selectors: usize,
+) -> Option<String> {
+    if rewriting_duration > Duration::from_millis(configuration.max_rewriting_duration) {
+        Some(format!(
+            "rewriting took more than {} ms",
+            configuration.max_rewriting_duration
+        ))
+    } else if document_length as u64 > configuration.max_content_length {
+        Some(format!(
+            "document is larger than {} bytes",
+            configuration.max_content_length
+        ))
+    } else if collected_selectors > configuration.max_collected_selectors {
+        Some(format!(
+            "more than {} classes and ids were collected",
+            configuration.max_collected_selectors
+        ))
+    } else {
+        None
+    }
+}
+
+/// Builds the content handlers removing elements matched by html filters (`##^`).
+fn html_filter_handlers<'s, 'h>(
+    html_filters: &[HtmlFilter],
+    held_back_element: &Rc<RefCell<Option<HeldBackElement>>>,
+    removed_html_elements: &Rc<Cell<u64>>,
+) -> Vec<(Cow<'s, Selector>, ElementContentHandlers<'h>)> {
+    html_filters
+        .iter()
+        .enumerate()
+        .filter_map(|(filter_index, html_filter)| {
+            // Filter lists may contain selectors that `lol_html` doesn't support.
+            let selector = match html_filter.selector.parse::<Selector>() {
+                Ok(selector) => selector,
+                Err(err) => {
+                    log::debug!(
+                        "Skipping html filter with unsupported selector: {}, {:?}",
+                        html_filter.selector,
+                        err
+                    );
+                    return None;
+                }
+            };
+
+            let removed_html_elements = removed_html_elements.clone();
+
+            let handlers = match html_filter.has_text.clone() {
+                None => ElementContentHandlers::default().element(move |element| {
+                    element.remove();
+                    removed_html_elements.set(removed_html_elements.get() + 1);
+
+                    Ok(())
+                }),
+                Some(has_text) => {
+                    // The element's text is only known once its end tag is reached. In the
+                    // meantime, we hold back the element and emit it back if it doesn't match.
+                    // The held back element is shared between filters, so that an element
+                    // matched by several of them is emitted once.
+                    let held_back_element = held_back_element.clone();
+                    let held_back_element_clone = held_back_element.clone();
+
+                    ElementContentHandlers::default()
+                        .element(move |element| {
+                            hold_back_element(
+                                element,
+                                filter_index,
+                                has_text.clone(),
+                                held_back_element.clone(),
+                                removed_html_elements.clone(),
+                            )
+                        })
+                        .text(move |chunk| {
+                            if let Some(held_back_element) =
+                                held_back_element_clone.borrow_mut().as_mut()
+                            {
+                                // Text is collected by the filter holding the element back.
+                                if held_back_element.holding_filter_index != filter_index
+                                    || held_back_element.is_released
+                                {
+                                    return Ok(());
+                                }
+
+                                if held_back_element.text.len() + chunk.as_str().len()
+                                    > MAX_HELD_BACK_TEXT_LENGTH
+                                {
+                                    // Too much text to hold back, let's emit what we held back
+                                    // and let the rest of the element through.
+                                    chunk.before(&held_back_element.take(), ContentType::Html);
+                                    held_back_element.is_released = true;
+                                } else {
+                                    held_back_element.text.push_str(chunk.as_str());
+                                    chunk.remove();
+                                }
+                            }
+
+                            Ok(())
+                        })
+                }
+            };
+
+            Some((Cow::Owned(selector), handlers))
+        })
+        .collect()
+}
+
+/// An element held back by `:has-text()` filters until its end tag is reached.
+struct HeldBackElement {
+    // The filter which started holding the element back.
+    holding_filter_index: usize,
+    // Texts of every filter matching the element, it is removed if any of them is found.
+    has_texts: Vec<HasText>,
+    start_tag: String,
+    text: String,
+    // Set once the element was emitted without waiting for its end tag.
+    is_released: bool,
+}
+
+impl HeldBackElement {
+    /// Takes the start tag and the text held back so far.
+    fn take(&mut self) -> String {
+        std::mem::take(&mut self.start_tag) + &std::mem::take(&mut self.text)
+    }
+}
+
+fn hold_back_element(
+    element: &mut Element,
+    filter_index: usize,
+    has_text: HasText,
+    held_back_element: Rc<RefCell<Option<HeldBackElement>>>,
+    removed_html_elements: Rc<Cell<u64>>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let tag_name = element.tag_name();
+
+    // Html filters with `:has-text()` only target raw text elements, this is not expected.
+    if !RAW_TEXT_ELEMENTS.contains(&tag_name.as_str()) {
+        return Ok(());
+    }
+
+    // The element is already held back by a previous filter.
+    if let Some(held_back_element) = held_back_element.borrow_mut().as_mut() {
+        held_back_element.has_texts.push(has_text);
+        return Ok(());
+    }
+
+    let start_tag =
+        element
+            .attributes()
+            .iter()
+            .fold(format!("<{}", tag_name), |mut start_tag, attribute| {
+                write!(
+                    start_tag,
+                    r#" {}="{}""#,
+                    attribute.name(),
+                    attribute.value().replace('"', "&quot;")
+                )
+                .unwrap();
+
+                start_tag
+            })
+            + ">";
+
+    element.remove_and_keep_content();
+    *held_back_element.borrow_mut() = Some(HeldBackElement {
+        holding_filter_index: filter_index,
+        has_texts: vec![has_text],
+        start_tag,
+        text: String::new(),
+        is_released: false,
+    });
+
+    element.on_end_tag(move |end| {
+        let mut held_back_element = match held_back_element.borrow_mut().take() {
+            Some(held_back_element) => held_back_element,
+            None => return Ok(()),
+        };
+
+        if held_back_element.is_released {
+            end.before(&format!("</{}>", end.name()), ContentType::Html);
+        } else if held_back_element
+            .has_texts
+            .iter()
+            .any(|has_text| has_text.is_match(&held_back_element.text))
+        {
+            removed_html_elements.set(removed_html_elements.get() + 1);
+        } else {
+            end.before(
+                &format!("{}</{}>", held_back_element.take(), end.name()),
+                ContentType::Html,
+            );
+        }
+
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocker::{BlockerRequest, BlockerResult, CosmeticBlockerResult};
+    use encoding_rs::{SHIFT_JIS, WINDOWS_1252};
+    use std::collections::HashMap;
+
+    /// Starts rewriting the document received from `chunk_receiver`, with a blocker which
+    /// doesn't hide anything.
+    fn start_rewriting(
+        chunk_receiver: Receiver<Result<Bytes, reqwest::Error>>,
+        content_type_encoding: Option<&'static Encoding>,
+        html_filters: Vec<HtmlFilter>,
+        configuration: HtmlRewriterConfiguration,
+    ) -> hyper::Body {
+        let (blocker_sender, blocker_receiver) = crossbeam_channel::unbounded::<BlockerRequest>();
+
+        std::thread::spawn(move || {
+            for request in blocker_receiver {
+                let _result =
+                    request
+                        .respond_to
+                        .send(BlockerResult::Cosmetic(CosmeticBlockerResult {
+                            hidden_selectors: Vec::new(),
+                            style_selectors: HashMap::new(),
+                            injected_script: None,
+                        }));
+            }
+        });
+
+        let (body_sender, body) = hyper::Body::channel();
+
+        let rewriter = Rewriter::new(
+            String::from("https://example.com/"),
+            AdblockRequester::new(blocker_sender),
+            chunk_receiver,
+            body_sender,
+            Statistics::new(),
+            html_filters,
+            ScriptInjection::Unprepared,
+            None,
+            content_type_encoding,
+            None,
+            configuration,
+        );
+
+        tokio::task::spawn_blocking(|| rewriter.rewrite());
+
+        body
+    }
+
+    /// Rewrites `document`, fed to the rewriter in small chunks.
+    async fn rewrite(
+        document: &[u8],
+        content_type_encoding: Option<&'static Encoding>,
+        html_filters: Vec<HtmlFilter>,
+        configuration: HtmlRewriterConfiguration,
+    ) -> Vec<u8> {
+        let (chunk_sender, chunk_receiver) = crossbeam_channel::unbounded();
+        for chunk in document.chunks(7) {
+            chunk_sender
+                .send(Ok(Bytes::copy_from_slice(chunk)))
+                .unwrap();
+        }
+        drop(chunk_sender);
+
+        let body = start_rewriting(
+            chunk_receiver,
+            content_type_encoding,
+            html_filters,
+            configuration,
+        );
+
+        hyper::body::to_bytes(body).await.unwrap().to_vec()
+    }
+
+    fn has_text_filter(selector: &str, text: &str) -> HtmlFilter {
+        HtmlFilter {
+            selector: selector.to_string(),
+            has_text: Some(HasText::Literal(text.to_string())),
+        }
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    #[tokio::test]
+    async fn test_windows_1252_meta_charset() {
+        let document = b"<html><head><meta charset=\"windows-1252\"><title>Caf\xe9</title>\
+            <script>loadAds()</script><script>var caf\xe9 = 1;</script></head>\
+            <body><p>d\xe9j\xe0 vu</p></body></html>";
+
+        let rewritten_document = rewrite(
+            document,
+            None,
+            vec![
+                has_text_filter("script", "loadAds"),
+                has_text_filter("script", "missing"),
+            ],
+            HtmlRewriterConfiguration::default(),
+        )
+        .await;
+
+        assert!(contains(&rewritten_document, b"<title>Caf\xe9</title>"));
+        assert!(contains(&rewritten_document, b"<p>d\xe9j\xe0 vu</p>"));
+        assert!(contains(
+            &rewritten_document,
+            b"<script>var caf\xe9 = 1;</script>"
+        ));
+        assert!(!contains(&rewritten_document, b"loadAds"));
+        // Held back by both filters, the script must only be emitted once.
+        assert_eq!(
+            rewritten_document
+                .windows(b"<script>".len())
+                .filter(|window| window == b"<script>")
+                .count(),
+            1
+        );
+
+        let (text, had_errors) = WINDOWS_1252.decode_without_bom_handling(&rewritten_document);
+        assert!(!had_errors);
+        assert!(text.contains("<!-- privaxy proxy -->"));
+    }
+
+    #[tokio::test]
+    async fn test_shift_jis_content_type_charset() {
+        let (document, _encoding, _had_unmappable_characters) = SHIFT_JIS.encode(
+            "<html><head><title>広告のないページ</title><script>広告()</script></head>\
+            <body><div class=\"ad\">広告</div><p>こんにちは</p></body></html>",
+        );
+
+        let rewritten_document = rewrite(
+            &document,
+            Some(SHIFT_JIS),
+            vec![HtmlFilter {
+                selector: String::from("div.ad"),
+                has_text: None,
+            }],
+            HtmlRewriterConfiguration::default(),
+        )
+        .await;
+
+        let (text, had_errors) = SHIFT_JIS.decode_without_bom_handling(&rewritten_document);
+
+        assert!(!had_errors);
+        assert!(text.contains("<title>広告のないページ</title>"));
+        assert!(text.contains("<script>広告()</script>"));
+        assert!(text.contains("<p>こんにちは</p>"));
+        assert!(!text.contains("<div"));
+    }
+
+    #[tokio::test]
+    async fn test_failed_rewrite_passes_the_rest_of_the_document_through() {
+        // The head of the document, up to the encoding prescan length, is rewritten at once.
+        let document = format!(
+            "<html><body><script>loadAds()</script><p>{}</p><div title=\"{}\"></div></body></html>",
+            "text ".repeat(256),
+            "x".repeat(8192)
+        );
+
+        let rewritten_document = rewrite(
+            document.as_bytes(),
+            None,
+            vec![has_text_filter("script", "loadAds")],
+            HtmlRewriterConfiguration {
+                max_memory_usage: 2048,
+                ..HtmlRewriterConfiguration::default()
+            },
+        )
+        .await;
+
+        // Rewritten up to the failure.
+        assert!(rewritten_document.starts_with(b"<html><body><p>text"));
+        assert!(!contains(&rewritten_document, b"loadAds"));
+        // The rest of the document is passed through.
+        assert!(contains(
+            &rewritten_document,
+            format!("{}\"></div></body></html>", "x".repeat(2048)).as_bytes()
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_bypassed_rewrite_passes_the_rest_of_the_document_through() {
+        let document = format!(
+            "<html><body><script>loadAds()</script><p>{}</p><p class=\"a b\">text</p></body></html>",
+            "text ".repeat(256),
+        );
+
+        for configuration in [
+            HtmlRewriterConfiguration {
+                max_collected_selectors: 1,
+                ..HtmlRewriterConfiguration::default()
+            },
+            HtmlRewriterConfiguration {
+                max_content_length: 16,
+                ..HtmlRewriterConfiguration::default()
+            },
+        ] {
+            let rewritten_document = rewrite(
+                document.as_bytes(),
+                None,
+                vec![has_text_filter("script", "loadAds")],
+                configuration,
+            )
+            .await;
+
+            assert!(rewritten_document.starts_with(b"<html><body><p>text"));
+            assert!(!contains(&rewritten_document, b"loadAds"));
+            assert!(contains(
+                &rewritten_document,
+                b"<p class=\"a b\">text</p></body></html>"
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rewritten_document_is_streamed() {
+        use hyper::body::HttpBody;
+
+        let (chunk_sender, chunk_receiver) = crossbeam_channel::unbounded();
+        let head = format!("<html><body><p>{}</p>", "text ".repeat(256));
+        chunk_sender
+            .send(Ok(Bytes::copy_from_slice(head.as_bytes())))
+            .unwrap();
+
+        let mut body = start_rewriting(
+            chunk_receiver,
+            None,
+            Vec::new(),
+            HtmlRewriterConfiguration {
+                max_rewriting_duration: 60_000,
+                ..HtmlRewriterConfiguration::default()
+            },
+        );
+
+        // Sent before the rest of the document is received.
+        let chunk = tokio::time::timeout(Duration::from_secs(5), body.data())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(head.as_bytes().starts_with(&chunk));
+
+        chunk_sender
+            .send(Ok(Bytes::from_static(
+                b"<p class=\"ad\">end</p></body></html>",
+            )))
+            .unwrap();
+        drop(chunk_sender);
+
+        let rest = hyper::body::to_bytes(body).await.unwrap();
+        assert!(contains(&rest, b"<p class=\"ad\">end</p>"));
+        // Rewritten up to the end, the body is only closed after the injected resources.
+        assert!(!contains(&rest, b"</body>"));
+    }
+
+    #[tokio::test]
+    async fn test_unterminated_held_back_element_is_emitted() {
+        let document = b"<html><body><p>text</p><script>var unterminated = 1;";
+
+        let rewritten_document = rewrite(
+            document,
+            None,
+            vec![has_text_filter("script", "loadAds")],
+            HtmlRewriterConfiguration::default(),
+        )
+        .await;
+
+        assert!(rewritten_document.starts_with(document));
+    }
+
+    #[tokio::test]
+    async fn test_stalled_document_is_passed_through() {
+        use hyper::body::HttpBody;
+
+        let (chunk_sender, chunk_receiver) = crossbeam_channel::unbounded();
+        chunk_sender
+            .send(Ok(Bytes::from_static(b"<html><body><p>stalled")))
+            .unwrap();
+
+        let mut body = start_rewriting(
+            chunk_receiver,
+            None,
+            Vec::new(),
+            HtmlRewriterConfiguration {
+                max_rewriting_duration: 100,
+                ..HtmlRewriterConfiguration::default()
+            },
+        );
+
+        // The upstream server doesn't send anything more, what was received is still sent.
+        let mut received = Vec::new();
+        while received.len() < b"<html><body><p>stalled".len() {
+            let chunk = tokio::time::timeout(Duration::from_secs(5), body.data())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            received.extend_from_slice(&chunk);
+        }
+        assert_eq!(&received[..], b"<html><body><p>stalled");
+
+        chunk_sender
+            .send(Ok(Bytes::from_static(b"</p></body></html>")))
+            .unwrap();
+        drop(chunk_sender);
+
+        let rest = hyper::body::to_bytes(body).await.unwrap();
+        assert!(rest.starts_with(b"</p></body></html>"));
     }
 }
@@ -1,19 +1,27 @@
-use super::{exclusions::LocalExclusionStore, serve::serve};
-use crate::{blocker::AdblockRequester, cert::CertCache, events::Event, statistics::Statistics};
-use http::uri::{Authority, Scheme};
-use hyper::{
-    client::HttpConnector, http, server::conn::Http, service::service_fn, upgrade::Upgraded, Body,
-    Method, Request, Response,
+use super::{
+    client_hello::peek_client_hello, connect_ports::ConnectPorts,
+    dynamic_cosmetic_filtering::PageTokens, exclusions::LocalExclusionStore, pac::ProxyAutoConfig,
+    serve::serve,
+};
+use crate::{
+    blocker::AdblockRequester, cert::CertCache, configuration::HtmlRewriterConfiguration,
+    events::Event, statistics::Statistics, trust_store::TrustStore,
+    upstream_proxies::UpstreamProxies,
 };
+use http::uri::{Authority, Scheme};
+use hyper::{http, server::conn::Http, service::service_fn, Body, Method, Request, Response};
 use hyper_rustls::HttpsConnector;
-use std::{net::IpAddr, sync::Arc};
-use tokio::{net::TcpStream, sync::broadcast};
+use std::{net::IpAddr, str::FromStr, sync::Arc};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::broadcast,
+};
 use tokio_rustls::TlsAcceptor;
 
 #[allow(clippy::too_many_arguments)]
 pub(crate) async fn serve_mitm_session(
     adblock_requester: AdblockRequester,
-    hyper_client: hyper::Client<HttpsConnector<HttpConnector>>,
+    hyper_client: hyper::Client<HttpsConnector<UpstreamProxies>>,
     client: reqwest::Client,
     req: Request<Body>,
     cert_cache: CertCache,
@@ -21,7 +29,18 @@ pub(crate) async fn serve_mitm_session(
     statistics: Statistics,
     client_ip_address: IpAddr,
     local_exclusion_store: LocalExclusionStore,
+    html_rewriter_configuration: HtmlRewriterConfiguration,
+    page_tokens: PageTokens,
+    strip_alt_svc: bool,
+    upstream_proxies: UpstreamProxies,
+    trust_store: TrustStore,
+    proxy_auto_config: ProxyAutoConfig,
+    connect_ports: ConnectPorts,
 ) -> Result<Response<Body>, hyper::Error> {
+    if ProxyAutoConfig::is_pac_request(&req) {
+        return Ok(proxy_auto_config.serve(&req));
+    }
+
     let authority = match req.uri().authority().cloned() {
         Some(authority) => authority,
         None => {
@@ -44,57 +63,51 @@ pub(crate) async fn serve_mitm_session(
         //
         // When HTTP method is CONNECT we should return an empty body
         // then we can eventually upgrade the connection and talk a new protocol.
-        let server_configuration =
-            Arc::new(cert_cache.get(authority.clone()).await.server_configuration);
+        if !connect_ports.is_allowed(authority.port_u16()) {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = http::StatusCode::FORBIDDEN;
+
+            log::debug!("Refused tunnel towards disallowed port: {}", authority);
+
+            return Ok(response);
+        }
+
+        if is_connection_blocked(
+            &authority,
+            &adblock_requester,
+            &broadcast_tx,
+            &statistics,
+            client_ip_address,
+        )
+        .await
+        {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = http::StatusCode::FORBIDDEN;
+
+            return Ok(response);
+        }
 
         tokio::task::spawn(async move {
             match hyper::upgrade::on(req).await {
-                Ok(mut upgraded) => {
-                    let is_host_blacklisted = local_exclusion_store.contains(authority.host());
-
-                    if is_host_blacklisted {
-                        let _result = tunnel(&mut upgraded, &authority).await;
-
-                        return;
-                    }
-
-                    let http = Http::new();
-
-                    match TlsAcceptor::from(server_configuration)
-                        .accept(upgraded)
-                        .await
-                    {
-                        Ok(tls_stream) => {
-                            let _result = http
-                                .serve_connection(
-                                    tls_stream,
-                                    service_fn(move |req| {
-                                        serve(
-                                            adblock_requester.clone(),
-                                            req,
-                                            hyper_client.clone(),
-                                            client.clone(),
-                                            authority.clone(),
-                                            Scheme::HTTPS,
-                                            broadcast_tx.clone(),
-                                            statistics.clone(),
-                                            client_ip_address,
-                                        )
-                                    }),
-                                )
-                                .with_upgrades()
-                                .await;
-                        }
-                        // Couldn't perform the tls handshake, they may only support TLS features that we don't or
-                        // make use of untrusted certificates. Let's add them to a blacklist so we'll be able to
-                        // tunnel them instead of trying to perform MITM.
-                        // No blocking will be able to be performed.
-                        Err(error) => {
-                            if error.kind() == std::io::ErrorKind::UnexpectedEof {
-                                log::warn!("Unable to perform handshake for host: {}. Consider excluding it from blocking. The service may not tolerate TLS interception.", authority);
-                            }
-                        }
-                    }
+                Ok(upgraded) => {
+                    serve_intercepted_stream(
+                        upgraded,
+                        authority,
+                        adblock_requester,
+                        hyper_client,
+                        client,
+                        cert_cache,
+                        broadcast_tx,
+                        statistics,
+                        client_ip_address,
+                        local_exclusion_store,
+                        html_rewriter_configuration,
+                        page_tokens,
+                        strip_alt_svc,
+                        upstream_proxies,
+                        trust_store,
+                    )
+                    .await
                 }
                 Err(e) => log::error!("upgrade error: {}", e),
             }
@@ -114,17 +127,523 @@ pub(crate) async fn serve_mitm_session(
             broadcast_tx,
             statistics,
             client_ip_address,
+            html_rewriter_configuration,
+            page_tokens,
+            strip_alt_svc,
+            trust_store,
         )
         .await
     }
 }
 
-async fn tunnel(mut upgraded: &mut Upgraded, authority: &Authority) -> std::io::Result<()> {
-    let mut server = TcpStream::connect(authority.to_string()).await?;
+/// Serves a client's stream towards `requested_authority`, performing TLS interception unless the
+/// host is excluded, in which case the stream is tunneled. Plaintext HTTP requests are served
+/// as is, anything else is tunneled.
+///
+/// The host is taken from the server name indication of the client's ClientHello when there is
+/// one, as the requested authority may be an IP address or differ from the server the client
+/// actually talks to.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn serve_intercepted_stream<S>(
+    stream: S,
+    requested_authority: Authority,
+    adblock_requester: AdblockRequester,
+    hyper_client: hyper::Client<HttpsConnector<UpstreamProxies>>,
+    client: reqwest::Client,
+    cert_cache: CertCache,
+    broadcast_tx: broadcast::Sender<Event>,
+    statistics: Statistics,
+    client_ip_address: IpAddr,
+    local_exclusion_store: LocalExclusionStore,
+    html_rewriter_configuration: HtmlRewriterConfiguration,
+    page_tokens: PageTokens,
+    strip_alt_svc: bool,
+    upstream_proxies: UpstreamProxies,
+    trust_store: TrustStore,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let client_hello = match peek_client_hello(stream).await {
+        Ok(client_hello) => client_hello,
+        Err(err) => {
+            log::debug!(
+                "Unable to read client hello for {}: {}",
+                requested_authority,
+                err
+            );
+            return;
+        }
+    };
+
+    let mut stream = client_hello.stream;
+
+    // Plaintext HTTP, such as websockets opened towards port 80, is filtered like requests sent
+    // to the proxy itself.
+    if client_hello.is_http {
+        let _result = Http::new()
+            .serve_connection(
+                stream,
+                service_fn(move |req| {
+                    serve(
+                        adblock_requester.clone(),
+                        req,
+                        hyper_client.clone(),
+                        client.clone(),
+                        requested_authority.clone(),
+                        Scheme::HTTP,
+                        broadcast_tx.clone(),
+                        statistics.clone(),
+                        client_ip_address,
+                        html_rewriter_configuration,
+                        page_tokens.clone(),
+                        strip_alt_svc,
+                        trust_store.clone(),
+                    )
+                }),
+            )
+            .with_upgrades()
+            .await;
+
+        return;
+    }
+
+    // Other protocols, such as SSH, are tunneled as is.
+    if !client_hello.is_tls {
+        log::debug!("Tunneling non TLS connection: {}", requested_authority);
+
+        let _result = tunnel(&mut stream, &requested_authority, &upstream_proxies).await;
+
+        return;
+    }
+
+    let authority = client_hello
+        .server_name
+        .and_then(|server_name| {
+            Authority::from_str(&format!(
+                "{}:{}",
+                server_name,
+                requested_authority.port_u16().unwrap_or(443)
+            ))
+            .ok()
+        })
+        .unwrap_or_else(|| requested_authority.clone());
+
+    if authority.host() != requested_authority.host() {
+        log::debug!(
+            "Client requested {} but indicated {} as server name",
+            requested_authority,
+            authority
+        );
+    }
+
+    // Excluded hosts are tunneled, this is the only chance to block them.
+    if is_connection_blocked(
+        &authority,
+        &adblock_requester,
+        &broadcast_tx,
+        &statistics,
+        client_ip_address,
+    )
+    .await
+    {
+        return;
+    }
+
+    let is_host_blacklisted = local_exclusion_store.contains(authority.host());
+
+    if is_host_blacklisted {
+        let _result = tunnel(&mut stream, &requested_authority, &upstream_proxies).await;
+
+        return;
+    }
+
+    let server_configuration =
+        Arc::new(cert_cache.get(authority.clone()).await.server_configuration);
+
+    match TlsAcceptor::from(server_configuration).accept(stream).await {
+        Ok(tls_stream) => {
+            local_exclusion_store.record_handshake_success(client_ip_address, authority.host());
+
+            let mut http = Http::new();
+
+            // Clients which negotiated HTTP/2 through ALPN start with its preface right away.
+            if tls_stream.get_ref().1.alpn_protocol() == Some(&b"h2"[..]) {
+                http.http2_only(true);
+            }
+
+            let _result = http
+                .serve_connection(
+                    tls_stream,
+                    service_fn(move |req| {
+                        serve(
+                            adblock_requester.clone(),
+                            req,
+                            hyper_client.clone(),
+                            client.clone(),
+                            authority.clone(),
+                            Scheme::HTTPS,
+                            broadcast_tx.clone(),
+                            statistics.clone(),
+                            client_ip_address,
+                            html_rewriter_configuration,
+                            page_tokens.clone(),
+                            strip_alt_svc,
+                            trust_store.clone(),
+                        )
+                    }),
+                )
+                .with_upgrades()
+                .await;
+        }
+        // Couldn't perform the tls handshake, they may only support TLS features that we don't or
+        // make use of untrusted certificates. Hosts whose clients keep aborting handshakes, while
+        // trusting our CA for other hosts, are added to the automatic exclusions so we'll be able
+        // to tunnel them instead of trying to perform MITM.
+        // No blocking will be able to be performed.
+        Err(error) => {
+            let is_handshake_aborted = error.kind() == std::io::ErrorKind::UnexpectedEof
+                || matches!(
+                    error
+                        .get_ref()
+                        .and_then(|error| error.downcast_ref::<rustls::Error>()),
+                    Some(rustls::Error::AlertReceived(_))
+                );
+
+            if is_handshake_aborted {
+                log::warn!("Unable to perform handshake for host: {}. The service may not tolerate TLS interception.", authority);
+
+                local_exclusion_store
+                    .record_handshake_failure(client_ip_address, authority.host())
+                    .await;
+            }
+        }
+    }
+}
+
+/// Whether connections towards `authority` are to be refused as a whole, its pages being blocked
+/// by a filter such as `||example.com^` without any exception. Refused connections are reported
+/// like blocked requests.
+pub(crate) async fn is_connection_blocked(
+    authority: &Authority,
+    adblock_requester: &AdblockRequester,
+    broadcast_tx: &broadcast::Sender<Event>,
+    statistics: &Statistics,
+    client_ip_address: IpAddr,
+) -> bool {
+    let is_blocked = adblock_requester
+        .is_connection_blocked(authority.host().to_string())
+        .await;
+
+    if is_blocked {
+        statistics.increment_top_clients(client_ip_address);
+        statistics.increment_blocked_requests();
+        statistics.increment_top_blocked_paths(authority.to_string());
+
+        let _result = broadcast_tx.send(Event {
+            now: chrono::Utc::now(),
+            method: Method::CONNECT.to_string(),
+            url: authority.to_string(),
+            is_request_blocked: true,
+        });
+
+        log::debug!("Blocked connection: {}", authority);
+    }
+
+    is_blocked
+}
 
-    tokio::io::copy_bidirectional(&mut upgraded, &mut server).await?;
+pub(crate) async fn tunnel<S>(
+    stream: &mut S,
+    authority: &Authority,
+    upstream_proxies: &UpstreamProxies,
+) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut server = upstream_proxies
+        .connect(authority.host(), authority.port_u16().unwrap_or(443))
+        .await?;
 
     log::debug!("Started tunneling host: {}", authority);
 
+    tokio::io::copy_bidirectional(stream, &mut server).await?;
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        blocker::{Blocker, BlockingDisabledStore},
+        ca::make_ca_certificate,
+        configuration::{CertificatesConfiguration, PacConfiguration, UpstreamTlsConfiguration},
+        dns::resolver::UpstreamResolver,
+    };
+    use std::{
+        collections::BTreeSet,
+        net::Ipv4Addr,
+        sync::{Arc, RwLock},
+        time::Duration,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+        net::TcpListener,
+    };
+    use tokio_rustls::TlsConnector;
+
+    const CLIENT_IP_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    struct Session {
+        adblock_requester: AdblockRequester,
+        hyper_client: hyper::Client<HttpsConnector<UpstreamProxies>>,
+        cert_cache: CertCache,
+        broadcast_tx: broadcast::Sender<Event>,
+        statistics: Statistics,
+        local_exclusion_store: LocalExclusionStore,
+        upstream_proxies: UpstreamProxies,
+        trust_store: TrustStore,
+    }
+
+    impl Session {
+        async fn new(filters: &[&str], exclusions: &[&str]) -> Self {
+            let (sender, receiver) = crossbeam_channel::unbounded();
+            let blocker = Blocker::new(
+                sender.clone(),
+                receiver,
+                BlockingDisabledStore(Arc::new(RwLock::new(false))),
+            );
+
+            std::thread::spawn(move || blocker.handle_requests());
+
+            let adblock_requester = AdblockRequester::new(sender);
+            adblock_requester
+                .replace_engine(Vec::from_iter(filters.iter().map(|f| f.to_string())))
+                .await;
+
+            let trust_store = TrustStore::new(&UpstreamTlsConfiguration {
+                native_roots: false,
+                bundled_roots: false,
+                ..Default::default()
+            });
+            let upstream_proxies =
+                UpstreamProxies::new(&[], UpstreamResolver::default(), &trust_store);
+
+            let https_connector = hyper_rustls::HttpsConnectorBuilder::new()
+                .with_tls_config(trust_store.client_configuration())
+                .https_or_http()
+                .enable_http1()
+                .wrap_connector(upstream_proxies.clone());
+
+            let (ca_certificate, ca_private_key) = make_ca_certificate();
+
+            Self {
+                adblock_requester,
+                hyper_client: hyper::Client::builder().build(https_connector),
+                cert_cache: CertCache::new(
+                    ca_certificate,
+                    ca_private_key,
+                    &CertificatesConfiguration {
+                        persist: false,
+                        ..Default::default()
+                    },
+                    None,
+                    None,
+                ),
+                broadcast_tx: broadcast::channel(16).0,
+                statistics: Statistics::new(),
+                local_exclusion_store: LocalExclusionStore::new(
+                    Vec::from_iter(exclusions.iter().map(|e| e.to_string())),
+                    BTreeSet::new(),
+                ),
+                upstream_proxies,
+                trust_store,
+            }
+        }
+
+        /// Serves a stream whose client end is returned, as if a tunnel was opened towards
+        /// `authority`.
+        fn serve_stream(&self, authority: &str) -> tokio::io::DuplexStream {
+            let (client_stream, stream) = tokio::io::duplex(64 * 1024);
+
+            tokio::spawn(serve_intercepted_stream(
+                stream,
+                Authority::from_str(authority).unwrap(),
+                self.adblock_requester.clone(),
+                self.hyper_client.clone(),
+                reqwest::Client::new(),
+                self.cert_cache.clone(),
+                self.broadcast_tx.clone(),
+                self.statistics.clone(),
+                CLIENT_IP_ADDRESS,
+                self.local_exclusion_store.clone(),
+                HtmlRewriterConfiguration::default(),
+                PageTokens::new(),
+                true,
+                self.upstream_proxies.clone(),
+                self.trust_store.clone(),
+            ));
+
+            client_stream
+        }
+
+        async fn connect(&self, authority: &str) -> Response<Body> {
+            let req = Request::builder()
+                .method(Method::CONNECT)
+                .uri(authority)
+                .body(Body::empty())
+                .unwrap();
+
+            serve_mitm_session(
+                self.adblock_requester.clone(),
+                self.hyper_client.clone(),
+                reqwest::Client::new(),
+                req,
+                self.cert_cache.clone(),
+                self.broadcast_tx.clone(),
+                self.statistics.clone(),
+                CLIENT_IP_ADDRESS,
+                self.local_exclusion_store.clone(),
+                HtmlRewriterConfiguration::default(),
+                PageTokens::new(),
+                true,
+                self.upstream_proxies.clone(),
+                self.trust_store.clone(),
+                ProxyAutoConfig::new(
+                    &PacConfiguration::default(),
+                    8100,
+                    self.local_exclusion_store.clone(),
+                ),
+                ConnectPorts::new(&[String::from("443")]),
+            )
+            .await
+            .unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_to_blocked_host_is_refused() {
+        let session = Session::new(&["||ads.example.com^"], &[]).await;
+        let mut events = session.broadcast_tx.subscribe();
+
+        let response = session.connect("ads.example.com:443").await;
+
+        assert_eq!(response.status(), http::StatusCode::FORBIDDEN);
+
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.method, "CONNECT");
+        assert_eq!(event.url, "ads.example.com:443");
+        assert!(event.is_request_blocked);
+    }
+
+    #[tokio::test]
+    async fn test_connect_to_excepted_host_is_accepted() {
+        let session = Session::new(
+            &[
+                "||example.com^",
+                "@@||cdn.example.com/library.js$domain=site.com",
+            ],
+            &[],
+        )
+        .await;
+
+        let response = session.connect("cdn.example.com:443").await;
+
+        assert_eq!(response.status(), http::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_plaintext_http_through_tunnel_is_filtered() {
+        let session = Session::new(&["||ads.example.com^"], &[]).await;
+        let mut client_stream = session.serve_stream("ads.example.com:80");
+
+        client_stream
+            .write_all(b"GET /ad.js HTTP/1.1\r\nHost: ads.example.com\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut response = vec![0; 1024];
+        let read = client_stream.read(&mut response).await.unwrap();
+
+        assert!(response[..read].starts_with(b"HTTP/1.1 403 Forbidden\r\n"));
+    }
+
+    /// Performs a TLS handshake indicating `server_name`, as a client trusting no certificate.
+    async fn handshake(
+        client_stream: DuplexStream,
+        server_name: &str,
+        trust_store: TrustStore,
+    ) -> std::io::Result<()> {
+        TlsConnector::from(Arc::new(trust_store.client_configuration()))
+            .connect(
+                rustls::ServerName::try_from(server_name).unwrap(),
+                client_stream,
+            )
+            .await
+            .map(|_tls_stream| ())
+    }
+
+    #[tokio::test]
+    async fn test_ip_connect_with_excluded_server_name_is_tunneled() {
+        let session = Session::new(&[], &["excluded.example.com"]).await;
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+
+        // The client requested an IP address, which isn't excluded itself.
+        let client_stream = session.serve_stream(&server.local_addr().unwrap().to_string());
+        tokio::spawn(handshake(
+            client_stream,
+            "excluded.example.com",
+            session.trust_store.clone(),
+        ));
+
+        let (mut server_stream, _client_address) =
+            tokio::time::timeout(Duration::from_secs(5), server.accept())
+                .await
+                .unwrap()
+                .unwrap();
+
+        // The client's ClientHello reaches the server as is.
+        let mut client_hello = Vec::new();
+        while !client_hello
+            .windows(b"excluded.example.com".len())
+            .any(|window| window == b"excluded.example.com")
+        {
+            let mut buffer = vec![0; 1024];
+            let read = server_stream.read(&mut buffer).await.unwrap();
+            assert_ne!(read, 0);
+
+            client_hello.extend_from_slice(&buffer[..read]);
+        }
+
+        assert_eq!(client_hello[0], 0x16);
+    }
+
+    #[tokio::test]
+    async fn test_ip_connect_with_blocked_server_name_is_refused() {
+        let session = Session::new(&["||blocked.example.com^"], &[]).await;
+        let mut events = session.broadcast_tx.subscribe();
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = server.local_addr().unwrap().port();
+
+        let client_stream = session.serve_stream(&server.local_addr().unwrap().to_string());
+        let result = handshake(
+            client_stream,
+            "blocked.example.com",
+            session.trust_store.clone(),
+        )
+        .await;
+
+        assert!(result.is_err());
+
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.url, format!("blocked.example.com:{}", port));
+        assert!(event.is_request_blocked);
+
+        // Nothing was tunneled.
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), server.accept())
+                .await
+                .is_err()
+        );
+    }
+}
@@ -1,5 +1,15 @@
 pub(crate) mod mitm;
 pub(crate) mod serve;
 pub(crate) use mitm::serve_mitm_session;
+pub(crate) mod alt_svc;
+pub(crate) mod charset;
+pub(crate) mod client_hello;
+pub(crate) mod connect_ports;
+pub(crate) mod csp;
+pub(crate) mod dynamic_cosmetic_filtering;
 pub(crate) mod exclusions;
 pub(crate) mod html_rewriter;
+pub(crate) mod pac;
+pub(crate) mod socks5;
+#[cfg(target_os = "linux")]
+pub(crate) mod transparent;
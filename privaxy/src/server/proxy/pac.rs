@@ -0,0 +1,239 @@
+//! Proxy auto-config script, served at `/proxy.pac` and `/wpad.dat` to clients requesting them
+//! from the proxy directly, easing the rollout of Privaxy on many devices.
+//!
+//! Excluded hosts and bypass networks are routed `DIRECT`, everything else goes through the
+//! proxy. Scripts are generated for every request, they therefore always reflect the current
+//! exclusions. Resolving host names blocks browsers, only IP addresses and host names matching
+//! `resolved_hosts` are checked against bypass networks.
+//!
+//! Other devices can only fetch the script, and use the proxy, when the proxy listens on a
+//! non-loopback address.
+//!
+//! See: https://developer.mozilla.org/en-US/docs/Web/HTTP/Proxy_servers_and_tunneling/Proxy_Auto-Configuration_PAC_file
+use super::exclusions::LocalExclusionStore;
+use crate::configuration::PacConfiguration;
+use http::uri::Authority;
+use hyper::{http, Body, Method, Request, Response};
+use std::{fmt::Write, net::Ipv4Addr, str::FromStr, sync::Arc};
+
+const PAC_PATHS: [&str; 2] = ["/proxy.pac", "/wpad.dat"];
+const PAC_CONTENT_TYPE: &str = "application/x-ns-proxy-autoconfig";
+
+fn parse_network(network: &str) -> Option<(Ipv4Addr, Ipv4Addr)> {
+    let (address, prefix_length) = network.split_once('/')?;
+
+    let address = Ipv4Addr::from_str(address).ok()?;
+    let prefix_length = u8::from_str(prefix_length)
+        .ok()
+        .filter(|length| *length <= 32)?;
+
+    let mask = u32::MAX.checked_shl(32 - prefix_length as u32).unwrap_or(0);
+
+    Some((
+        Ipv4Addr::from(u32::from(address) & mask),
+        Ipv4Addr::from(mask),
+    ))
+}
+
+// Patterns and addresses are embedded in the script as JSON strings, which are valid
+// javascript strings.
+fn string_literal(string: &str) -> String {
+    serde_json::to_string(string).unwrap()
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ProxyAutoConfig {
+    // Networks as addresses and masks, PAC scripts only support IPv4 networks.
+    bypass_networks: Arc<Vec<(Ipv4Addr, Ipv4Addr)>>,
+    resolved_hosts: Arc<Vec<String>>,
+    proxy_port: u16,
+    local_exclusion_store: LocalExclusionStore,
+}
+
+impl ProxyAutoConfig {
+    pub(crate) fn new(
+        configuration: &PacConfiguration,
+        proxy_port: u16,
+        local_exclusion_store: LocalExclusionStore,
+    ) -> Self {
+        let bypass_networks = configuration
+            .bypass_networks
+            .iter()
+            .filter_map(|network| {
+                let parsed_network = parse_network(network);
+
+                if parsed_network.is_none() {
+                    log::warn!("Ignoring invalid PAC bypass network: {}", network);
+                }
+
+                parsed_network
+            })
+            .collect();
+
+        Self {
+            bypass_networks: Arc::new(bypass_networks),
+            resolved_hosts: Arc::new(
+                configuration
+                    .resolved_hosts
+                    .iter()
+                    .map(|pattern| pattern.to_lowercase())
+                    .collect(),
+            ),
+            proxy_port,
+            local_exclusion_store,
+        }
+    }
+
+    /// Requests for the script are sent to the proxy as regular requests, without any authority.
+    pub(crate) fn is_pac_request(req: &Request<Body>) -> bool {
+        req.method() == Method::GET
+            && req.uri().authority().is_none()
+            && PAC_PATHS.contains(&req.uri().path())
+    }
+
+    fn script(&self, proxy_host: &str) -> String {
+        let mut script = String::from("function FindProxyForURL(url, host) {\n");
+        script.push_str("  host = host.toLowerCase();\n\n");
+        script.push_str(
+            "  if (isPlainHostName(host) || host === \"localhost\") {\n    return \"DIRECT\";\n  }\n\n",
+        );
+
+        let exclusions = self
+            .local_exclusion_store
+            .patterns()
+            .iter()
+            .map(|pattern| string_literal(pattern))
+            .collect::<Vec<_>>();
+
+        let _result = write!(
+            script,
+            "  var exclusions = [{}];\n\n  for (var i = 0; i < exclusions.length; i++) {{\n    if (shExpMatch(host, exclusions[i])) {{\n      return \"DIRECT\";\n    }}\n  }}\n\n",
+            exclusions.join(", ")
+        );
+
+        if !self.bypass_networks.is_empty() {
+            let bypass_networks = self
+                .bypass_networks
+                .iter()
+                .map(|(address, mask)| {
+                    format!(
+                        "[{}, {}]",
+                        string_literal(&address.to_string()),
+                        string_literal(&mask.to_string())
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            let resolved_hosts = self
+                .resolved_hosts
+                .iter()
+                .map(|pattern| string_literal(pattern))
+                .collect::<Vec<_>>();
+
+            let _result = write!(
+                script,
+                "  var bypassNetworks = [{}];\n  var resolvedHosts = [{}];\n  var address = null;\n\n  if (/^\\d+\\.\\d+\\.\\d+\\.\\d+$/.test(host)) {{\n    address = host;\n  }} else {{\n    for (var i = 0; i < resolvedHosts.length; i++) {{\n      if (shExpMatch(host, resolvedHosts[i])) {{\n        address = dnsResolve(host);\n        break;\n      }}\n    }}\n  }}\n\n  if (address) {{\n    for (var i = 0; i < bypassNetworks.length; i++) {{\n      if (isInNet(address, bypassNetworks[i][0], bypassNetworks[i][1])) {{\n        return \"DIRECT\";\n      }}\n    }}\n  }}\n\n",
+                bypass_networks.join(", "),
+                resolved_hosts.join(", ")
+            );
+        }
+
+        let _result = write!(
+            script,
+            "  return {};\n}}\n",
+            string_literal(&format!("PROXY {}:{}", proxy_host, self.proxy_port))
+        );
+
+        script
+    }
+
+    pub(crate) fn serve(&self, req: &Request<Body>) -> Response<Body> {
+        // Clients reach the proxy through the host they fetched the script from.
+        let proxy_host = req
+            .headers()
+            .get(http::header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .and_then(|host| Authority::from_str(host).ok())
+            .map(|authority| authority.host().to_string())
+            .unwrap_or_else(|| Ipv4Addr::LOCALHOST.to_string());
+
+        Response::builder()
+            .header(http::header::CONTENT_TYPE, PAC_CONTENT_TYPE)
+            .header(http::header::CACHE_CONTROL, "no-cache")
+            .body(Body::from(self.script(&proxy_host)))
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    fn proxy_auto_config(configuration: &PacConfiguration) -> ProxyAutoConfig {
+        ProxyAutoConfig::new(
+            configuration,
+            8100,
+            LocalExclusionStore::new(vec![String::from("*.example.com")], BTreeSet::new()),
+        )
+    }
+
+    #[test]
+    fn test_parse_network() {
+        assert_eq!(
+            parse_network("192.168.1.12/16"),
+            Some((Ipv4Addr::new(192, 168, 0, 0), Ipv4Addr::new(255, 255, 0, 0)))
+        );
+        assert_eq!(
+            parse_network("0.0.0.0/0"),
+            Some((Ipv4Addr::UNSPECIFIED, Ipv4Addr::UNSPECIFIED))
+        );
+        assert_eq!(parse_network("10.0.0.1/33"), None);
+        assert_eq!(parse_network("fd00::/8"), None);
+        assert_eq!(parse_network("10.0.0.1"), None);
+    }
+
+    #[test]
+    fn test_script() {
+        let script = proxy_auto_config(&PacConfiguration::default()).script("192.168.1.2");
+
+        assert!(script.contains("\"*.example.com\""));
+        assert!(script.contains("[\"192.168.0.0\", \"255.255.0.0\"]"));
+        assert!(script.contains("var resolvedHosts = [\"*.home.arpa\""));
+        assert!(script.ends_with("  return \"PROXY 192.168.1.2:8100\";\n}\n"));
+    }
+
+    #[test]
+    fn test_script_without_bypass_networks() {
+        let script = proxy_auto_config(&PacConfiguration {
+            bypass_networks: Vec::new(),
+            ..Default::default()
+        })
+        .script("127.0.0.1");
+
+        assert!(!script.contains("dnsResolve"));
+        assert!(!script.contains("isInNet"));
+    }
+
+    #[test]
+    fn test_serve() {
+        let proxy_auto_config = proxy_auto_config(&PacConfiguration::default());
+
+        let req = Request::get("/proxy.pac")
+            .header(http::header::HOST, "192.168.1.2:8100")
+            .body(Body::empty())
+            .unwrap();
+        assert!(ProxyAutoConfig::is_pac_request(&req));
+
+        let response = proxy_auto_config.serve(&req);
+        assert_eq!(
+            response.headers()[http::header::CONTENT_TYPE],
+            PAC_CONTENT_TYPE
+        );
+
+        let req = Request::get("http://example.com/proxy.pac")
+            .body(Body::empty())
+            .unwrap();
+        assert!(!ProxyAutoConfig::is_pac_request(&req));
+    }
+}
@@ -1,28 +1,43 @@
+use super::alt_svc::strip_quic_alternatives;
+use super::charset::encoding_from_content_type;
+use super::csp::{prepare_script_injection, ScriptInjection};
+use super::dynamic_cosmetic_filtering::{self, PageTokens};
 use super::html_rewriter::Rewriter;
 use crate::blocker::AdblockRequester;
+use crate::configuration::HtmlRewriterConfiguration;
 use crate::events::Event;
+use crate::response_rewrite_rules::ResponseRewrite;
 use crate::statistics::Statistics;
+use crate::trust_store::{self, CertificateDetails, TrustStore};
+use crate::upstream_proxies::UpstreamProxies;
 use adblock::blocker::BlockerResult;
 use http::uri::{Authority, Scheme};
 use http::{StatusCode, Uri};
 use hyper::body::Bytes;
-use hyper::client::HttpConnector;
+use hyper::client::connect::HttpInfo;
 use hyper::{http, Body, Request, Response};
 use hyper_rustls::HttpsConnector;
 use std::net::IpAddr;
 use tokio::sync::broadcast;
 
+// Response bodies are buffered before being rewritten, let's not hold onto huge ones.
+const MAX_REWRITTEN_BODY_SIZE: usize = 10 * 1024 * 1024;
+
 #[allow(clippy::too_many_arguments)]
 pub(crate) async fn serve(
     adblock_requester: AdblockRequester,
     request: Request<Body>,
-    hyper_client: hyper::Client<HttpsConnector<HttpConnector>>,
+    hyper_client: hyper::Client<HttpsConnector<UpstreamProxies>>,
     client: reqwest::Client,
     authority: Authority,
     scheme: Scheme,
     broadcast_sender: broadcast::Sender<Event>,
     statistics: Statistics,
     client_ip_address: IpAddr,
+    html_rewriter_configuration: HtmlRewriterConfiguration,
+    page_tokens: PageTokens,
+    strip_alt_svc: bool,
+    trust_store: TrustStore,
 ) -> Result<Response<Body>, hyper::Error> {
     let scheme_string = scheme.to_string();
 
@@ -41,6 +56,18 @@ pub(crate) async fn serve(
         }
     };
 
+    if html_rewriter_configuration.dynamic_cosmetic_filtering
+        && uri.path() == dynamic_cosmetic_filtering::ENDPOINT_PATH
+    {
+        return Ok(dynamic_cosmetic_filtering::serve_endpoint(
+            request,
+            &uri,
+            page_tokens,
+            adblock_requester,
+        )
+        .await);
+    }
+
     if request.headers().contains_key(http::header::UPGRADE) {
         return Ok(perform_two_ends_upgrade(request, uri, hyper_client).await);
     }
@@ -103,22 +130,69 @@ pub(crate) async fn serve(
         .await
     {
         Ok(response) => response,
-        Err(err) => return Ok(get_informative_error_response(&err.to_string())),
+        Err(err) => {
+            if let Some(reason) = trust_store::certificate_error(&err) {
+                let host = uri.host().unwrap_or_default();
+
+                log::warn!("Unable to verify certificate of {}: {}", host, reason);
+
+                return Ok(get_certificate_error_response(
+                    host,
+                    &reason,
+                    &trust_store.rejected_chain(host).unwrap_or_default(),
+                ));
+            }
+
+            return Ok(get_informative_error_response(&err.to_string()));
+        }
     };
 
     statistics.increment_proxied_requests();
+    statistics.record_upstream_response(
+        response
+            .extensions()
+            .get::<HttpInfo>()
+            .map(|http_info| http_info.local_addr()),
+        response.version(),
+    );
 
     *new_response.headers_mut() = response.headers().clone();
 
+    if strip_alt_svc {
+        strip_quic_alternatives(new_response.headers_mut());
+    }
+
     let (mut parts, new_new_body) = new_response.into_parts();
     parts.status = response.status();
 
-    let new_response = Response::from_parts(parts, new_new_body);
+    let mut new_response = Response::from_parts(parts, new_new_body);
 
     if let Some(content_type) = response.headers().get(http::header::CONTENT_TYPE) {
         if let Ok(value) = content_type.to_str() {
             if value.contains("text/html") {
-                let (sender_rewriter, receiver_rewriter) = crossbeam_channel::unbounded::<Bytes>();
+                let (sender_rewriter, receiver_rewriter) =
+                    crossbeam_channel::unbounded::<Result<Bytes, reqwest::Error>>();
+
+                // The rewritten document's length differs, which HTTP/2 clients would not tolerate.
+                new_response
+                    .headers_mut()
+                    .remove(http::header::CONTENT_LENGTH);
+
+                let html_filters = adblock_requester.get_html_filters(uri.to_string()).await;
+                let page_token = if html_rewriter_configuration.dynamic_cosmetic_filtering {
+                    Some(page_tokens.issue(uri.to_string()))
+                } else {
+                    None
+                };
+
+                // Policies are only amended for documents we inject scripts into.
+                let script_injection = if page_token.is_some()
+                    || adblock_requester.injects_script(uri.to_string()).await
+                {
+                    prepare_script_injection(new_response.headers_mut())
+                } else {
+                    ScriptInjection::Unprepared
+                };
 
                 let rewriter = Rewriter::new(
                     uri.to_string(),
@@ -126,18 +200,54 @@ pub(crate) async fn serve(
                     receiver_rewriter,
                     sender,
                     statistics,
+                    html_filters,
+                    script_injection,
+                    page_token,
+                    encoding_from_content_type(value),
+                    response.content_length(),
+                    html_rewriter_configuration,
                 );
 
                 tokio::task::spawn_blocking(|| rewriter.rewrite());
 
-                while let Ok(Some(chunk)) = response.chunk().await {
-                    if let Err(_err) = sender_rewriter.send(chunk) {
-                        break;
+                loop {
+                    match response.chunk().await {
+                        Ok(Some(chunk)) => {
+                            if let Err(_err) = sender_rewriter.send(Ok(chunk)) {
+                                break;
+                            }
+                        }
+                        Ok(None) => break,
+                        // The rewriter aborts the response, so that it doesn't look complete.
+                        Err(err) => {
+                            let _result = sender_rewriter.send(Err(err));
+                            break;
+                        }
                     }
                 }
 
                 return Ok(new_response);
             }
+
+            let response_rewrites = adblock_requester
+                .get_response_rewrites(uri.to_string(), value.to_string())
+                .await;
+
+            if !response_rewrites.is_empty() {
+                // The body's length is likely to change.
+                new_response
+                    .headers_mut()
+                    .remove(http::header::CONTENT_LENGTH);
+
+                tokio::spawn(write_rewritten_body(
+                    response,
+                    sender,
+                    response_rewrites,
+                    statistics,
+                ));
+
+                return Ok(new_response);
+            }
         }
 
         tokio::spawn(write_proxied_body(response, sender));
@@ -161,6 +271,75 @@ fn get_informative_error_response(reason: &str) -> Response<Body> {
     response
 }
 
+// Certificates are picked by whoever runs the upstream server, nothing they hold can be trusted.
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn get_certificate_error_response(
+    host: &str,
+    reason: &str,
+    chain: &[CertificateDetails],
+) -> Response<Body> {
+    let certificate_chain = chain
+        .iter()
+        .map(|certificate| {
+            let fields = [
+                ("Subject", certificate.subject.clone()),
+                ("Issuer", certificate.issuer.clone()),
+                ("Valid from", certificate.not_before.clone()),
+                ("Valid until", certificate.not_after.clone()),
+                (
+                    "Alternative names",
+                    certificate.subject_alternative_names.join(", "),
+                ),
+                ("SHA-256 fingerprint", certificate.sha256_fingerprint.clone()),
+            ];
+
+            let rows = fields
+                .iter()
+                .map(|(name, value)| {
+                    format!(
+                        "<dt class=\"font-medium text-gray-900\">{}</dt><dd class=\"font-mono break-all\">{}</dd>",
+                        name,
+                        escape_html(value)
+                    )
+                })
+                .collect::<String>();
+
+            format!(
+                "<dl class=\"mt-4 p-3 text-sm text-gray-500 bg-gray-100 rounded-md\">{}</dl>",
+                rows
+            )
+        })
+        .collect::<String>();
+
+    // Exceptions are made for the certificate presented by the server itself, which comes first.
+    let certificate_exception = match chain.first() {
+        Some(certificate) => format!("{} {}", host, certificate.sha256_fingerprint),
+        None => format!("{} <SHA-256 fingerprint>", host),
+    };
+
+    let mut response_body = String::from(include_str!("../../resources/head.html"));
+    response_body += &include_str!("../../resources/certificate_error.html")
+        .replace("#{host}#", &escape_html(host))
+        .replace("#{request_error_reson}#", &escape_html(reason))
+        .replace("#{certificate_chain}#", &certificate_chain)
+        .replace(
+            "#{certificate_exception}#",
+            &escape_html(&certificate_exception),
+        );
+
+    let mut response = Response::new(Body::from(response_body));
+    *response.status_mut() = http::StatusCode::BAD_GATEWAY;
+
+    response
+}
+
 fn get_blocked_by_privaxy_response(blocker_result: BlockerResult) -> Response<Body> {
     // We don't redirect to network urls due to security concerns.
     if let Some(resource) = blocker_result.redirect {
@@ -192,20 +371,95 @@ fn get_empty_response(status_code: http::StatusCode) -> Response<Body> {
 }
 
 async fn write_proxied_body(mut response: reqwest::Response, mut sender: hyper::body::Sender) {
-    while let Ok(Some(chunk)) = response.chunk().await {
-        // The other end is broken, let's abort immediately.
-        if let Err(_err) = sender.send_data(chunk).await {
-            break;
+    loop {
+        match response.chunk().await {
+            Ok(Some(chunk)) => {
+                // The other end is broken, let's abort immediately.
+                if let Err(_err) = sender.send_data(chunk).await {
+                    break;
+                }
+            }
+            Ok(None) => break,
+            Err(err) => {
+                abort_body(response.url(), sender, err);
+                break;
+            }
         }
     }
 }
 
+/// Aborts a response whose upstream body couldn't be read entirely, so that clients don't
+/// mistake it for a complete one.
+fn abort_body(url: &url::Url, sender: hyper::body::Sender, err: reqwest::Error) {
+    log::debug!("Unable to read the response body of {}: {}", url, err);
+
+    sender.abort();
+}
+
+/// Buffers the response body to apply rewrites to it as a whole.
+///
+/// Bodies larger than `MAX_REWRITTEN_BODY_SIZE` or which are not valid UTF-8 are passed through untouched.
+async fn write_rewritten_body(
+    mut response: reqwest::Response,
+    mut sender: hyper::body::Sender,
+    response_rewrites: Vec<ResponseRewrite>,
+    statistics: Statistics,
+) {
+    let mut body = Vec::new();
+
+    loop {
+        let chunk = match response.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(err) => {
+                abort_body(response.url(), sender, err);
+                return;
+            }
+        };
+
+        body.extend_from_slice(&chunk);
+
+        if body.len() > MAX_REWRITTEN_BODY_SIZE {
+            log::debug!(
+                "Response body of {} is too large to be rewritten",
+                response.url()
+            );
+
+            if let Err(_err) = sender.send_data(Bytes::from(body)).await {
+                return;
+            }
+
+            write_proxied_body(response, sender).await;
+            return;
+        }
+    }
+
+    let body = match String::from_utf8(body) {
+        Ok(text) => {
+            let rewritten_text = response_rewrites
+                .iter()
+                .fold(text.clone(), |text, response_rewrite| {
+                    response_rewrite.apply(&text)
+                });
+
+            if rewritten_text != text {
+                statistics.increment_modified_responses();
+            }
+
+            Bytes::from(rewritten_text)
+        }
+        Err(err) => Bytes::from(err.into_bytes()),
+    };
+
+    let _result = sender.send_data(body).await;
+}
+
 /// When we receive a request to perform an upgrade, we need to initiate a bidirectional tunnel.
 /// We upgrade the request towards the target server, towards the proxy end and we connect both through a duplex stream.
 async fn perform_two_ends_upgrade(
     request: Request<Body>,
     uri: Uri,
-    hyper_client: hyper::Client<HttpsConnector<HttpConnector>>,
+    hyper_client: hyper::Client<HttpsConnector<UpstreamProxies>>,
 ) -> Response<Body> {
     let (mut duplex_client, mut duplex_server) = tokio::io::duplex(32);
 
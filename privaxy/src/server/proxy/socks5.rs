@@ -0,0 +1,343 @@
+//! Inbound SOCKS5 connections, for clients which don't speak HTTP proxying.
+//!
+//! Connections towards ports 80 and 443 are served like `CONNECT` tunnels are, their plaintext
+//! HTTP requests being filtered and their TLS connections intercepted. Connections towards other
+//! ports are tunneled.
+//!
+//! See: https://www.rfc-editor.org/rfc/rfc1928
+use super::{
+    connect_ports::ConnectPorts,
+    dynamic_cosmetic_filtering::PageTokens,
+    exclusions::LocalExclusionStore,
+    mitm::{is_connection_blocked, serve_intercepted_stream},
+};
+use crate::{
+    blocker::AdblockRequester,
+    cert::CertCache,
+    configuration::HtmlRewriterConfiguration,
+    events::Event,
+    statistics::Statistics,
+    trust_store::TrustStore,
+    upstream_proxies::{
+        UpstreamProxies, SOCKS_CONNECT_COMMAND, SOCKS_DOMAIN_NAME, SOCKS_IPV4_ADDRESS,
+        SOCKS_IPV6_ADDRESS, SOCKS_NO_AUTHENTICATION, SOCKS_VERSION,
+    },
+};
+use http::uri::Authority;
+use hyper_rustls::HttpsConnector;
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::broadcast,
+};
+
+const HTTP_PORT: u16 = 80;
+const INTERCEPTED_PORT: u16 = 443;
+
+const SOCKS_NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const SOCKS_SUCCEEDED: u8 = 0x00;
+const SOCKS_GENERAL_FAILURE: u8 = 0x01;
+const SOCKS_CONNECTION_NOT_ALLOWED: u8 = 0x02;
+const SOCKS_HOST_UNREACHABLE: u8 = 0x04;
+const SOCKS_CONNECTION_REFUSED: u8 = 0x05;
+const SOCKS_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const SOCKS_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+fn invalid_data(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+/// The reply to send when connecting to the destination failed.
+fn connect_error_reply(err: &io::Error) -> u8 {
+    match err.kind() {
+        io::ErrorKind::ConnectionRefused => SOCKS_CONNECTION_REFUSED,
+        // Host names which couldn't be resolved and connections which timed out.
+        io::ErrorKind::NotFound | io::ErrorKind::TimedOut | io::ErrorKind::AddrNotAvailable => {
+            SOCKS_HOST_UNREACHABLE
+        }
+        _ => SOCKS_GENERAL_FAILURE,
+    }
+}
+
+async fn send_reply(stream: &mut TcpStream, reply: u8) -> io::Result<()> {
+    // We don't disclose the address we bound to, clients have no use for it.
+    stream
+        .write_all(&[
+            SOCKS_VERSION,
+            reply,
+            0x00,
+            SOCKS_IPV4_ADDRESS,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ])
+        .await
+}
+
+/// Performs the SOCKS5 handshake, returning the authority the client asks to connect to.
+async fn accept(stream: &mut TcpStream) -> io::Result<Authority> {
+    let mut header = [0; 2];
+    stream.read_exact(&mut header).await?;
+
+    if header[0] != SOCKS_VERSION {
+        return Err(invalid_data("not a socks5 client"));
+    }
+
+    let mut methods = vec![0; header[1] as usize];
+    stream.read_exact(&mut methods).await?;
+
+    // Only local clients are expected to connect, we don't require any authentication.
+    if !methods.contains(&SOCKS_NO_AUTHENTICATION) {
+        stream
+            .write_all(&[SOCKS_VERSION, SOCKS_NO_ACCEPTABLE_METHODS])
+            .await?;
+
+        return Err(invalid_data("no acceptable socks5 authentication method"));
+    }
+
+    stream
+        .write_all(&[SOCKS_VERSION, SOCKS_NO_AUTHENTICATION])
+        .await?;
+
+    let mut request = [0; 4];
+    stream.read_exact(&mut request).await?;
+
+    if request[1] != SOCKS_CONNECT_COMMAND {
+        send_reply(stream, SOCKS_COMMAND_NOT_SUPPORTED).await?;
+
+        return Err(invalid_data("unsupported socks5 command"));
+    }
+
+    let host = match request[3] {
+        SOCKS_IPV4_ADDRESS => {
+            let mut octets = [0; 4];
+            stream.read_exact(&mut octets).await?;
+
+            Ipv4Addr::from(octets).to_string()
+        }
+        SOCKS_IPV6_ADDRESS => {
+            let mut octets = [0; 16];
+            stream.read_exact(&mut octets).await?;
+
+            format!("[{}]", Ipv6Addr::from(octets))
+        }
+        SOCKS_DOMAIN_NAME => {
+            let mut domain_name = vec![0; stream.read_u8().await? as usize];
+            stream.read_exact(&mut domain_name).await?;
+
+            String::from_utf8(domain_name).map_err(|_err| invalid_data("invalid domain name"))?
+        }
+        _ => {
+            send_reply(stream, SOCKS_ADDRESS_TYPE_NOT_SUPPORTED).await?;
+
+            return Err(invalid_data("unsupported socks5 address type"));
+        }
+    };
+
+    let port = stream.read_u16().await?;
+
+    match Authority::from_str(&format!("{}:{}", host, port)) {
+        Ok(authority) => Ok(authority),
+        Err(_err) => {
+            send_reply(stream, SOCKS_GENERAL_FAILURE).await?;
+
+            Err(invalid_data("invalid socks5 destination"))
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn serve_socks5_connection(
+    mut stream: TcpStream,
+    adblock_requester: AdblockRequester,
+    hyper_client: hyper::Client<HttpsConnector<UpstreamProxies>>,
+    client: reqwest::Client,
+    cert_cache: CertCache,
+    broadcast_tx: broadcast::Sender<Event>,
+    statistics: Statistics,
+    client_ip_address: IpAddr,
+    local_exclusion_store: LocalExclusionStore,
+    html_rewriter_configuration: HtmlRewriterConfiguration,
+    page_tokens: PageTokens,
+    strip_alt_svc: bool,
+    upstream_proxies: UpstreamProxies,
+    trust_store: TrustStore,
+    connect_ports: ConnectPorts,
+) {
+    let authority = match accept(&mut stream).await {
+        Ok(authority) => authority,
+        Err(err) => {
+            log::debug!("Unable to accept socks5 connection: {}", err);
+            return;
+        }
+    };
+
+    if !connect_ports.is_allowed(authority.port_u16()) {
+        log::debug!(
+            "Refused socks5 connection towards disallowed port: {}",
+            authority
+        );
+
+        let _result = send_reply(&mut stream, SOCKS_CONNECTION_NOT_ALLOWED).await;
+        return;
+    }
+
+    if is_connection_blocked(
+        &authority,
+        &adblock_requester,
+        &broadcast_tx,
+        &statistics,
+        client_ip_address,
+    )
+    .await
+    {
+        let _result = send_reply(&mut stream, SOCKS_CONNECTION_NOT_ALLOWED).await;
+        return;
+    }
+
+    // Intercepted connections are served like `CONNECT` tunnels are, their requests being sent
+    // through the hyper client's own connections.
+    if matches!(authority.port_u16(), Some(HTTP_PORT | INTERCEPTED_PORT)) {
+        if let Err(err) = send_reply(&mut stream, SOCKS_SUCCEEDED).await {
+            log::debug!("Unable to accept socks5 connection: {}", err);
+            return;
+        }
+
+        serve_intercepted_stream(
+            stream,
+            authority,
+            adblock_requester,
+            hyper_client,
+            client,
+            cert_cache,
+            broadcast_tx,
+            statistics,
+            client_ip_address,
+            local_exclusion_store,
+            html_rewriter_configuration,
+            page_tokens,
+            strip_alt_svc,
+            upstream_proxies,
+            trust_store,
+        )
+        .await;
+
+        return;
+    }
+
+    // Success of tunnels is only reported once the destination is known to be reachable.
+    let mut server = match upstream_proxies
+        .connect(authority.host(), authority.port_u16().unwrap_or_default())
+        .await
+    {
+        Ok(server) => server,
+        Err(err) => {
+            log::debug!("Unable to connect to {}: {}", authority, err);
+
+            let _result = send_reply(&mut stream, connect_error_reply(&err)).await;
+            return;
+        }
+    };
+
+    if let Err(err) = send_reply(&mut stream, SOCKS_SUCCEEDED).await {
+        log::debug!("Unable to accept socks5 connection: {}", err);
+        return;
+    }
+
+    log::debug!("Started tunneling host: {}", authority);
+
+    let _result = tokio::io::copy_bidirectional(&mut stream, &mut server).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Runs `accept` against a client sending `request` after its greeting, returning the
+    /// outcome along with what was sent back to the client.
+    async fn accept_request(request: &[u8]) -> (io::Result<Authority>, Vec<u8>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut stream, _client_address) = listener.accept().await.unwrap();
+
+        client
+            .write_all(&[SOCKS_VERSION, 1, SOCKS_NO_AUTHENTICATION])
+            .await
+            .unwrap();
+        client.write_all(request).await.unwrap();
+
+        let result = accept(&mut stream).await;
+        drop(stream);
+
+        let mut replies = Vec::new();
+        client.read_to_end(&mut replies).await.unwrap();
+
+        (result, replies)
+    }
+
+    #[tokio::test]
+    async fn test_accept_domain_name() {
+        let (result, replies) = accept_request(b"\x05\x01\x00\x03\x0bexample.com\x01\xbb").await;
+
+        assert_eq!(result.unwrap(), "example.com:443");
+        assert_eq!(replies, [SOCKS_VERSION, SOCKS_NO_AUTHENTICATION]);
+    }
+
+    #[tokio::test]
+    async fn test_accept_ip_addresses() {
+        let (result, _replies) = accept_request(b"\x05\x01\x00\x01\x7f\x00\x00\x01\x00\x50").await;
+        assert_eq!(result.unwrap(), "127.0.0.1:80");
+
+        let mut request = vec![
+            SOCKS_VERSION,
+            SOCKS_CONNECT_COMMAND,
+            0x00,
+            SOCKS_IPV6_ADDRESS,
+        ];
+        request.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        request.extend_from_slice(&443u16.to_be_bytes());
+
+        let (result, _replies) = accept_request(&request).await;
+        assert_eq!(result.unwrap(), "[::1]:443");
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_command() {
+        // BIND, the destination isn't read once the command is refused.
+        let (result, replies) = accept_request(b"\x05\x02\x00\x01").await;
+
+        assert!(result.is_err());
+        assert_eq!(replies[2..4], [SOCKS_VERSION, SOCKS_COMMAND_NOT_SUPPORTED]);
+    }
+
+    #[test]
+    fn test_connect_error_reply() {
+        assert_eq!(
+            connect_error_reply(&io::ErrorKind::ConnectionRefused.into()),
+            SOCKS_CONNECTION_REFUSED
+        );
+        assert_eq!(
+            connect_error_reply(&io::ErrorKind::NotFound.into()),
+            SOCKS_HOST_UNREACHABLE
+        );
+        assert_eq!(
+            connect_error_reply(&io::ErrorKind::TimedOut.into()),
+            SOCKS_HOST_UNREACHABLE
+        );
+        assert_eq!(
+            connect_error_reply(&io::ErrorKind::PermissionDenied.into()),
+            SOCKS_GENERAL_FAILURE
+        );
+    }
+}
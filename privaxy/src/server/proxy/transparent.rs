@@ -0,0 +1,320 @@
+//! Transparent proxying of connections redirected to Privaxy by the firewall, so that clients
+//! don't need to be configured to use a proxy.
+//!
+//! Connections are either redirected with NAT, in which case their original destination is
+//! recovered with `SO_ORIGINAL_DST`:
+//! ```sh
+//! iptables -t nat -A OUTPUT -p tcp -m multiport --dports 80,443 -m owner ! --uid-owner privaxy -j REDIRECT --to-ports 8102
+//! ```
+//! or with TPROXY, in which case their destination is preserved. Privaxy's own connections must
+//! not be redirected, which the owner match above takes care of.
+//!
+//! Connections towards port 80 are served as plain HTTP. Connections towards port 443 are
+//! intercepted when they start with a TLS handshake, the certificate being picked from the
+//! ClientHello's server name indication. Other connections are tunneled without waiting for the
+//! client to speak, as clients of protocols where servers speak first wouldn't.
+//!
+//! `scripts/transparent_netns.sh` redirects the connections of a network namespace to the
+//! transparent proxy, to try it out without redirecting the host's own connections.
+use super::{
+    client_hello::peek_client_hello,
+    dynamic_cosmetic_filtering::PageTokens,
+    exclusions::LocalExclusionStore,
+    mitm::{serve_intercepted_stream, tunnel},
+    serve::serve,
+};
+use crate::{
+    blocker::AdblockRequester, cert::CertCache, configuration::HtmlRewriterConfiguration,
+    events::Event, statistics::Statistics, trust_store::TrustStore,
+    upstream_proxies::UpstreamProxies,
+};
+use http::uri::{Authority, Scheme};
+use hyper::{server::conn::Http, service::service_fn};
+use hyper_rustls::HttpsConnector;
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    os::unix::io::AsRawFd,
+    str::FromStr,
+};
+use tokio::{
+    net::{TcpListener, TcpSocket, TcpStream},
+    sync::broadcast,
+};
+
+const HTTP_PORT: u16 = 80;
+const INTERCEPTED_PORT: u16 = 443;
+
+// From `linux/netfilter_ipv4.h` and `linux/netfilter_ipv6/ip6_tables.h`.
+const SO_ORIGINAL_DST: libc::c_int = 80;
+const IP6T_SO_ORIGINAL_DST: libc::c_int = 80;
+
+/// Binds the listener redirected connections are sent to. Listeners receiving connections
+/// redirected with TPROXY must be marked as transparent.
+pub(crate) fn bind(address: SocketAddr, tproxy: bool) -> io::Result<TcpListener> {
+    let socket = match address {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+
+    socket.set_reuseaddr(true)?;
+
+    if tproxy {
+        let (level, option) = match address {
+            SocketAddr::V4(_) => (libc::SOL_IP, libc::IP_TRANSPARENT),
+            SocketAddr::V6(_) => (libc::SOL_IPV6, libc::IPV6_TRANSPARENT),
+        };
+        let enabled: libc::c_int = 1;
+
+        // Safety: the option's value is a valid `c_int` whose size is passed along.
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                option,
+                &enabled as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    socket.bind(address)?;
+    socket.listen(1024)
+}
+
+fn original_destination(stream: &TcpStream, tproxy: bool) -> io::Result<SocketAddr> {
+    let local_address = stream.local_addr()?;
+
+    // TPROXY preserves destinations.
+    if tproxy {
+        return Ok(local_address);
+    }
+
+    let file_descriptor = stream.as_raw_fd();
+
+    // Safety: the buffers handed to `getsockopt` are valid socket addresses whose size is passed
+    // along.
+    unsafe {
+        match local_address {
+            SocketAddr::V4(_) => {
+                let mut address: libc::sockaddr_in = std::mem::zeroed();
+                let mut length = std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+
+                if libc::getsockopt(
+                    file_descriptor,
+                    libc::SOL_IP,
+                    SO_ORIGINAL_DST,
+                    &mut address as *mut libc::sockaddr_in as *mut libc::c_void,
+                    &mut length,
+                ) != 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+
+                Ok(SocketAddr::from((
+                    Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr)),
+                    u16::from_be(address.sin_port),
+                )))
+            }
+            SocketAddr::V6(_) => {
+                let mut address: libc::sockaddr_in6 = std::mem::zeroed();
+                let mut length = std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+
+                if libc::getsockopt(
+                    file_descriptor,
+                    libc::SOL_IPV6,
+                    IP6T_SO_ORIGINAL_DST,
+                    &mut address as *mut libc::sockaddr_in6 as *mut libc::c_void,
+                    &mut length,
+                ) != 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+
+                Ok(SocketAddr::from((
+                    Ipv6Addr::from(address.sin6_addr.s6_addr),
+                    u16::from_be(address.sin6_port),
+                )))
+            }
+        }
+    }
+}
+
+fn authority_from_address(host: Option<String>, address: SocketAddr) -> Option<Authority> {
+    let host = host.unwrap_or_else(|| match address.ip() {
+        IpAddr::V4(ip_address) => ip_address.to_string(),
+        IpAddr::V6(ip_address) => format!("[{}]", ip_address),
+    });
+
+    Authority::from_str(&format!("{}:{}", host, address.port())).ok()
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn serve_transparent_connection(
+    mut stream: TcpStream,
+    tproxy: bool,
+    adblock_requester: AdblockRequester,
+    hyper_client: hyper::Client<HttpsConnector<UpstreamProxies>>,
+    client: reqwest::Client,
+    cert_cache: CertCache,
+    broadcast_tx: broadcast::Sender<Event>,
+    statistics: Statistics,
+    client_ip_address: IpAddr,
+    local_exclusion_store: LocalExclusionStore,
+    html_rewriter_configuration: HtmlRewriterConfiguration,
+    page_tokens: PageTokens,
+    strip_alt_svc: bool,
+    upstream_proxies: UpstreamProxies,
+    trust_store: TrustStore,
+) {
+    let destination = match original_destination(&stream, tproxy) {
+        Ok(destination) => destination,
+        Err(err) => {
+            log::debug!("Unable to get connection's original destination: {}", err);
+            return;
+        }
+    };
+
+    // Connections made directly to the listener would otherwise loop back to it.
+    if !tproxy && Some(destination) == stream.local_addr().ok() {
+        log::warn!(
+            "Received a connection which wasn't redirected to the transparent proxy from {}",
+            client_ip_address
+        );
+        return;
+    }
+
+    if destination.port() == HTTP_PORT {
+        let _result = Http::new()
+            .serve_connection(
+                stream,
+                service_fn(move |req| {
+                    // HTTP/1.1 clients always send the host they intend to reach.
+                    let host = req
+                        .headers()
+                        .get(http::header::HOST)
+                        .and_then(|host| host.to_str().ok())
+                        .and_then(|host| Authority::from_str(host).ok())
+                        .map(|authority| authority.host().to_string());
+
+                    let authority = authority_from_address(host, destination)
+                        .or_else(|| authority_from_address(None, destination))
+                        .unwrap();
+
+                    serve(
+                        adblock_requester.clone(),
+                        req,
+                        hyper_client.clone(),
+                        client.clone(),
+                        authority,
+                        Scheme::HTTP,
+                        broadcast_tx.clone(),
+                        statistics.clone(),
+                        client_ip_address,
+                        html_rewriter_configuration,
+                        page_tokens.clone(),
+                        strip_alt_svc,
+                        trust_store.clone(),
+                    )
+                }),
+            )
+            .with_upgrades()
+            .await;
+
+        return;
+    }
+
+    if destination.port() != INTERCEPTED_PORT {
+        let authority = match authority_from_address(None, destination) {
+            Some(authority) => authority,
+            None => return,
+        };
+
+        let _result = tunnel(&mut stream, &authority, &upstream_proxies).await;
+
+        return;
+    }
+
+    let mut client_hello = match peek_client_hello(stream).await {
+        Ok(client_hello) => client_hello,
+        Err(err) => {
+            log::debug!(
+                "Unable to read from transparently proxied connection: {}",
+                err
+            );
+            return;
+        }
+    };
+
+    let authority = match authority_from_address(
+        client_hello
+            .server_name
+            .take()
+            .filter(|_| client_hello.is_tls),
+        destination,
+    ) {
+        Some(authority) => authority,
+        None => return,
+    };
+
+    if client_hello.is_tls {
+        serve_intercepted_stream(
+            client_hello.stream,
+            authority,
+            adblock_requester,
+            hyper_client,
+            client,
+            cert_cache,
+            broadcast_tx,
+            statistics,
+            client_ip_address,
+            local_exclusion_store,
+            html_rewriter_configuration,
+            page_tokens,
+            strip_alt_svc,
+            upstream_proxies,
+            trust_store,
+        )
+        .await
+    } else {
+        let _result = tunnel(&mut client_hello.stream, &authority, &upstream_proxies).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authority_from_address() {
+        let address = SocketAddr::from((Ipv4Addr::new(93, 184, 216, 34), 443));
+
+        assert_eq!(
+            authority_from_address(Some("example.com".to_string()), address).unwrap(),
+            "example.com:443"
+        );
+        assert_eq!(
+            authority_from_address(None, address).unwrap(),
+            "93.184.216.34:443"
+        );
+        assert_eq!(
+            authority_from_address(None, SocketAddr::from((Ipv6Addr::LOCALHOST, 80))).unwrap(),
+            "[::1]:80"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tproxy_destination() {
+        let listener = bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), false).unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let _client = TcpStream::connect(address).await.unwrap();
+        let (stream, _client_address) = listener.accept().await.unwrap();
+
+        assert_eq!(original_destination(&stream, true).unwrap(), address);
+    }
+}
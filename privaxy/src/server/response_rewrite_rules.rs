@@ -0,0 +1,541 @@
+//! Rules rewriting the bodies of non html responses, such as JSON or javascript ones.
+//!
+//! Rules either come from the configuration or from uBlock Origin's `$replace=` filters, which
+//! `$replace` exceptions cancel. Filters with options other than resource types are ignored.
+//!
+//! See: https://github.com/gorhill/uBlock/wiki/Static-filter-syntax#replace
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use wildmatch::WildMatch;
+
+const REPLACE_OPTION: &str = "replace";
+// Resource types filters may be narrowed down to, which rules already are through the content
+// types of the responses they rewrite. Any other option isn't supported.
+const RESOURCE_TYPE_OPTIONS: [&str; 3] = ["xhr", "xmlhttprequest", "script"];
+// Content types rewritten when a rule doesn't specify any.
+const DEFAULT_CONTENT_TYPES: [&str; 3] = ["json", "javascript", "ecmascript"];
+
+fn default_path() -> String {
+    String::from("*")
+}
+
+/// A user defined rule, as stored in the configuration file.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ResponseRewriteRule {
+    /// Host the rule applies to. `?` and `*` wildcards are supported.
+    pub host: String,
+    /// Path and query the rule applies to. `?` and `*` wildcards are supported.
+    #[serde(default = "default_path")]
+    pub path: String,
+    /// Parts of the `Content-Type` header of responses the rule applies to. Defaults to JSON and
+    /// javascript responses.
+    #[serde(default)]
+    pub content_types: Vec<String>,
+    /// Regular expression matched against the response body.
+    pub pattern: String,
+    /// Replacement for matches of `pattern`, `$1` style references to capture groups are supported.
+    #[serde(default)]
+    pub replacement: String,
+}
+
+/// A replacement to perform on a response body.
+#[derive(Debug, Clone)]
+pub struct ResponseRewrite {
+    pub regex: Regex,
+    pub replacement: String,
+}
+
+impl ResponseRewrite {
+    pub fn apply(&self, body: &str) -> String {
+        self.regex
+            .replace_all(body, self.replacement.as_str())
+            .to_string()
+    }
+}
+
+/// A `$replace` filter, or an exception to such filters.
+struct ReplaceFilter<'a> {
+    is_exception: bool,
+    hosts: Vec<WildMatch>,
+    path: WildMatch,
+    /// Value of the `replace` option along with the rewrite it describes. Exceptions without any
+    /// apply to every filter.
+    replace: Option<(&'a str, ResponseRewrite)>,
+}
+
+impl<'a> ReplaceFilter<'a> {
+    /// Parses filters such as `||example.com/api/*$xhr,replace=/"ads":\[.*?\]/"ads":[]/`, or
+    /// exceptions such as `@@||example.com^$replace`.
+    fn parse(filter: &'a str) -> Option<Self> {
+        let (is_exception, filter) = match filter.strip_prefix("@@") {
+            Some(filter) => (true, filter),
+            None => (false, filter),
+        };
+
+        let replace_option_start =
+            filter
+                .match_indices(REPLACE_OPTION)
+                .find_map(|(index, _)| {
+                    let is_option_start =
+                        matches!(filter[..index].chars().last(), Some('$') | Some(','));
+                    let is_option_end = matches!(
+                        filter[index + REPLACE_OPTION.len()..].chars().next(),
+                        None | Some('=') | Some(',')
+                    );
+
+                    if is_option_start && is_option_end {
+                        Some(index)
+                    } else {
+                        None
+                    }
+                })?;
+
+        let options_start = filter[..replace_option_start].rfind('$')?;
+        let (hosts, path) = parse_pattern(&filter[..options_start])?;
+
+        let value = &filter[replace_option_start + REPLACE_OPTION.len()..];
+        let (replace, remaining_options) = match value.strip_prefix('=') {
+            Some(value) => {
+                let (response_rewrite, remaining_options) = parse_replace_option(value)?;
+                let value = &value[..value.len() - remaining_options.len()];
+
+                (Some((value, response_rewrite)), remaining_options)
+            }
+            None => (None, value),
+        };
+
+        if replace.is_none() && !is_exception {
+            return None;
+        }
+
+        // Options such as `domain=` restrict filters in ways which aren't supported, such filters
+        // are ignored rather than applied too broadly.
+        let are_options_supported = filter[options_start + 1..replace_option_start]
+            .split(',')
+            .chain(remaining_options.split(','))
+            .all(|option| option.is_empty() || RESOURCE_TYPE_OPTIONS.contains(&option));
+
+        if !are_options_supported {
+            return None;
+        }
+
+        Some(Self {
+            is_exception,
+            hosts,
+            path,
+            replace,
+        })
+    }
+}
+
+/// Parses patterns such as `||example.com/api/*` into the hosts and path they match.
+fn parse_pattern(pattern: &str) -> Option<(Vec<WildMatch>, WildMatch)> {
+    let pattern = pattern.strip_prefix("||")?;
+
+    let host_end = pattern.find(['/', '^', '*']).unwrap_or(pattern.len());
+    let (host, path) = pattern.split_at(host_end);
+
+    if host.is_empty() {
+        return None;
+    }
+
+    let mut path = path.replace('^', "*");
+    if !path.starts_with('/') && !path.starts_with('*') {
+        path.insert(0, '/');
+    }
+    if !path.ends_with('*') {
+        path.push('*');
+    }
+
+    let host = host.to_lowercase();
+
+    Some((
+        vec![
+            WildMatch::new(&host),
+            WildMatch::new(&format!("*.{}", host)),
+        ],
+        WildMatch::new(&path),
+    ))
+}
+
+#[derive(Debug)]
+struct CompiledRule {
+    hosts: Vec<WildMatch>,
+    path: WildMatch,
+    content_types: Vec<String>,
+    response_rewrite: ResponseRewrite,
+    // Value of the `replace` option of rules coming from filters, which exceptions refer to.
+    replace_option: Option<String>,
+}
+
+impl CompiledRule {
+    fn from_configuration(rule: &ResponseRewriteRule) -> Option<Self> {
+        let regex = match Regex::new(&rule.pattern) {
+            Ok(regex) => regex,
+            Err(err) => {
+                log::warn!(
+                    "Ignoring response rewrite rule with invalid pattern: {}, {}",
+                    rule.pattern,
+                    err
+                );
+                return None;
+            }
+        };
+
+        Some(Self {
+            hosts: vec![WildMatch::new(&rule.host.to_lowercase())],
+            path: WildMatch::new(&rule.path),
+            content_types: rule
+                .content_types
+                .iter()
+                .map(|content_type| content_type.to_lowercase())
+                .collect(),
+            response_rewrite: ResponseRewrite {
+                regex,
+                replacement: to_regex_replacement(&rule.replacement),
+            },
+            replace_option: None,
+        })
+    }
+
+    fn from_filter(filter: ReplaceFilter) -> Option<Self> {
+        match filter {
+            ReplaceFilter {
+                is_exception: false,
+                hosts,
+                path,
+                replace: Some((replace_option, response_rewrite)),
+            } => Some(Self {
+                hosts,
+                path,
+                content_types: Vec::new(),
+                response_rewrite,
+                replace_option: Some(replace_option.to_string()),
+            }),
+            _ => None,
+        }
+    }
+
+    fn applies_to(&self, host: &str, path_and_query: &str, content_type: &str) -> bool {
+        let content_type_matches = if self.content_types.is_empty() {
+            DEFAULT_CONTENT_TYPES
+                .iter()
+                .any(|default_content_type| content_type.contains(default_content_type))
+        } else {
+            self.content_types
+                .iter()
+                .any(|rule_content_type| content_type.contains(rule_content_type.as_str()))
+        };
+
+        content_type_matches
+            && self.hosts.iter().any(|pattern| pattern.matches(host))
+            && self.path.matches(path_and_query)
+    }
+}
+
+/// Parses the value of a `replace=` option: `/pattern/replacement/flags`. Options following it
+/// are returned along.
+fn parse_replace_option(value: &str) -> Option<(ResponseRewrite, &str)> {
+    let mut parts = Vec::new();
+    let mut current_part = String::new();
+    let mut characters = value.strip_prefix('/')?.chars();
+
+    while parts.len() < 2 {
+        match characters.next()? {
+            '\\' => match characters.next()? {
+                // Escaped separators are part of the pattern or replacement.
+                '/' => current_part.push('/'),
+                ',' => current_part.push(','),
+                // `$$` is a literal dollar sign in replacements, and matches the end of the
+                // line twice in patterns.
+                '$' => current_part.push_str("$$"),
+                character => {
+                    current_part.push('\\');
+                    current_part.push(character);
+                }
+            },
+            '/' => parts.push(std::mem::take(&mut current_part)),
+            character => current_part.push(character),
+        }
+    }
+
+    // Remaining options, if any, follow the flags.
+    let remaining = characters.as_str();
+    let flags_end = remaining.find(',').unwrap_or(remaining.len());
+    let (flags, remaining_options) = remaining.split_at(flags_end);
+
+    let regex = RegexBuilder::new(&parts[0])
+        .case_insensitive(flags.contains('i'))
+        .multi_line(flags.contains('m'))
+        .dot_matches_new_line(flags.contains('s'))
+        .build()
+        .ok()?;
+
+    Some((
+        ResponseRewrite {
+            regex,
+            replacement: to_regex_replacement(&parts[1]),
+        },
+        remaining_options,
+    ))
+}
+
+/// Converts javascript style references to capture groups, such as `$1` or `$&`, to the
+/// syntax of the `regex` crate, which would otherwise read `$1abc` as a group named `1abc`.
+fn to_regex_replacement(replacement: &str) -> String {
+    let mut regex_replacement = String::with_capacity(replacement.len());
+    let mut characters = replacement.chars().peekable();
+
+    while let Some(character) = characters.next() {
+        if character != '$' {
+            regex_replacement.push(character);
+            continue;
+        }
+
+        match characters.peek() {
+            Some('$') => {
+                characters.next();
+                regex_replacement.push_str("$$");
+            }
+            Some('&') => {
+                characters.next();
+                regex_replacement.push_str("${0}");
+            }
+            Some(digit) if digit.is_ascii_digit() => {
+                regex_replacement.push_str("${");
+                while let Some(digit) = characters.next_if(|c| c.is_ascii_digit()) {
+                    regex_replacement.push(digit);
+                }
+                regex_replacement.push('}');
+            }
+            _ => regex_replacement.push('$'),
+        }
+    }
+
+    regex_replacement
+}
+
+/// An exception such as `@@||example.com^$replace`, cancelling filters which apply to the same
+/// requests.
+#[derive(Debug)]
+struct Exception {
+    hosts: Vec<WildMatch>,
+    path: WildMatch,
+    // Exceptions with a `replace=` value only cancel filters with the same one.
+    replace_option: Option<String>,
+}
+
+impl Exception {
+    fn cancels(&self, rule: &CompiledRule, host: &str, path_and_query: &str) -> bool {
+        self.hosts.iter().any(|pattern| pattern.matches(host))
+            && self.path.matches(path_and_query)
+            && (self.replace_option.is_none() || self.replace_option == rule.replace_option)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ResponseRewriteRuleSet {
+    filter_rules: Vec<CompiledRule>,
+    exceptions: Vec<Exception>,
+    configured_rules: Vec<CompiledRule>,
+}
+
+impl ResponseRewriteRuleSet {
+    pub fn replace_filter_rules(&mut self, filter_lists: &[String]) {
+        self.filter_rules.clear();
+        self.exceptions.clear();
+
+        let lines = filter_lists
+            .iter()
+            .flat_map(|filter_list| filter_list.lines())
+            .map(|line| line.trim())
+            .filter(|line| !line.starts_with('!') && line.contains(REPLACE_OPTION));
+
+        for line in lines {
+            match ReplaceFilter::parse(line) {
+                Some(filter) if filter.is_exception => self.exceptions.push(Exception {
+                    hosts: filter.hosts,
+                    path: filter.path,
+                    replace_option: filter
+                        .replace
+                        .map(|(replace_option, _)| replace_option.to_string()),
+                }),
+                Some(filter) => self.filter_rules.extend(CompiledRule::from_filter(filter)),
+                None => log::debug!("Ignoring unsupported replace filter: {}", line),
+            }
+        }
+    }
+
+    pub fn replace_configured_rules(&mut self, rules: &[ResponseRewriteRule]) {
+        self.configured_rules = rules
+            .iter()
+            .filter_map(CompiledRule::from_configuration)
+            .collect();
+    }
+
+    pub fn rewrites_for(&self, url: &url::Url, content_type: &str) -> Vec<ResponseRewrite> {
+        let host = match url.host_str() {
+            Some(host) => host.to_lowercase(),
+            None => return Vec::new(),
+        };
+
+        let path_and_query = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+
+        let content_type = content_type.to_lowercase();
+
+        let filter_rules = self.filter_rules.iter().filter(|rule| {
+            !self
+                .exceptions
+                .iter()
+                .any(|exception| exception.cancels(rule, &host, &path_and_query))
+        });
+
+        filter_rules
+            .chain(self.configured_rules.iter())
+            .filter(|rule| rule.applies_to(&host, &path_and_query, &content_type))
+            .map(|rule| rule.response_rewrite.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_filter(filter: &str) -> Option<CompiledRule> {
+        ReplaceFilter::parse(filter).and_then(CompiledRule::from_filter)
+    }
+
+    fn apply(filter: &str, body: &str) -> String {
+        from_filter(filter).unwrap().response_rewrite.apply(body)
+    }
+
+    #[test]
+    fn test_parse_replace_option() {
+        let (response_rewrite, remaining_options) =
+            parse_replace_option(r#"/"ads":\[.*?\]/"ads":[]/"#).unwrap();
+
+        assert_eq!(response_rewrite.regex.as_str(), r#""ads":\[.*?\]"#);
+        assert_eq!(response_rewrite.replacement, r#""ads":[]"#);
+        assert_eq!(remaining_options, "");
+
+        let (response_rewrite, remaining_options) =
+            parse_replace_option(r"/a\/b\,c/d\/e/gi,xhr").unwrap();
+
+        assert_eq!(response_rewrite.regex.as_str(), "a/b,c");
+        assert_eq!(response_rewrite.replacement, "d/e");
+        assert_eq!(remaining_options, ",xhr");
+
+        assert!(parse_replace_option("missing-slashes").is_none());
+        assert!(parse_replace_option("/unterminated/").is_none());
+        assert!(parse_replace_option("/(/invalid/").is_none());
+    }
+
+    #[test]
+    fn test_flags() {
+        assert_eq!(
+            apply("||example.com^$replace=/ADS/x/gi", "ads, Ads"),
+            "x, x"
+        );
+        assert_eq!(
+            apply("||example.com^$replace=/ads/x/g", "ads, Ads"),
+            "x, Ads"
+        );
+        assert_eq!(apply("||example.com^$replace=/a.b/x/s", "a\nb"), "x");
+    }
+
+    #[test]
+    fn test_capture_group_references() {
+        assert_eq!(
+            apply(r"||example.com^$replace=/(\w+)=(\d+)/$2abc$1/", "ads=1"),
+            "1abcads"
+        );
+        assert_eq!(apply("||example.com^$replace=/ads/[$&]/", "ads"), "[ads]");
+        assert_eq!(apply(r"||example.com^$replace=/ads/\$1/", "ads"), "$1");
+        assert_eq!(apply("||example.com^$replace=/ads/$$1/", "ads"), "$1");
+    }
+
+    #[test]
+    fn test_from_filter() {
+        let rule = from_filter(r#"||example.com/api/*$xhr,replace=/"ads":1/"ads":0/"#).unwrap();
+
+        assert!(rule.applies_to("example.com", "/api/feed?page=1", "application/json"));
+        assert!(rule.applies_to("www.example.com", "/api/feed", "text/javascript"));
+        assert!(!rule.applies_to("example.com", "/feed", "application/json"));
+        assert!(!rule.applies_to("example.com", "/api/feed", "text/html"));
+        assert!(!rule.applies_to("notexample.com", "/api/feed", "application/json"));
+
+        assert!(from_filter("@@||example.com^$replace=/a/b/").is_none());
+        assert!(from_filter("example.com$replace=/a/b/").is_none());
+        assert!(from_filter("||example.com^$noreplace=/a/b/").is_none());
+        assert!(from_filter("||example.com^$replace").is_none());
+    }
+
+    #[test]
+    fn test_filters_with_unsupported_options_are_ignored() {
+        assert!(from_filter("||example.com^$script,replace=/a/b/,xhr").is_some());
+
+        assert!(from_filter("||example.com^$replace=/a/b/,domain=site.com").is_none());
+        assert!(from_filter("||example.com^$domain=site.com,replace=/a/b/").is_none());
+        assert!(from_filter("||example.com^$from=site.com,replace=/a/b/").is_none());
+        assert!(from_filter("||example.com^$replace=/a/b/,third-party").is_none());
+        assert!(from_filter("||example.com^$~xhr,replace=/a/b/").is_none());
+        assert!(from_filter("||example.com^$important,replace=/a/b/").is_none());
+    }
+
+    #[test]
+    fn test_exceptions() {
+        let mut response_rewrite_rule_set = ResponseRewriteRuleSet::default();
+        response_rewrite_rule_set.replace_filter_rules(&[String::from(
+            r#"
+||example.com^$replace=/ads/x/
+||example.com^$replace=/banner/x/
+||site.com^$replace=/ads/x/
+@@||www.example.com^$replace
+@@||example.com/api/$replace=/banner/x/
+@@||site.com^$replace=/unrelated/x/
+"#,
+        )]);
+        response_rewrite_rule_set.replace_configured_rules(&[ResponseRewriteRule {
+            host: String::from("www.example.com"),
+            path: default_path(),
+            content_types: Vec::new(),
+            pattern: String::from("configured"),
+            replacement: String::new(),
+        }]);
+
+        let patterns = |url: &str| {
+            Vec::from_iter(
+                response_rewrite_rule_set
+                    .rewrites_for(&url::Url::parse(url).unwrap(), "application/json")
+                    .into_iter()
+                    .map(|response_rewrite| response_rewrite.regex.as_str().to_string()),
+            )
+        };
+
+        assert_eq!(patterns("https://example.com/"), ["ads", "banner"]);
+        // Exceptions don't apply to configured rules.
+        assert_eq!(patterns("https://www.example.com/"), ["configured"]);
+        assert_eq!(patterns("https://example.com/api/feed"), ["ads"]);
+        assert_eq!(patterns("https://site.com/"), ["ads"]);
+    }
+
+    #[test]
+    fn test_configured_rule() {
+        let rule = CompiledRule::from_configuration(&ResponseRewriteRule {
+            host: String::from("*.Example.com"),
+            path: default_path(),
+            content_types: vec![String::from("Text/Plain")],
+            pattern: String::from("(ads)"),
+            replacement: String::from("no$1!"),
+        })
+        .unwrap();
+
+        assert!(rule.applies_to("www.example.com", "/", "text/plain; charset=utf-8"));
+        assert!(!rule.applies_to("www.example.com", "/", "application/json"));
+        assert_eq!(rule.response_rewrite.apply("ads"), "noads!");
+    }
+}
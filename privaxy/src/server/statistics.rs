@@ -1,7 +1,8 @@
+use hyper::Version;
 use serde::Serialize;
 use std::{
     collections::HashMap,
-    net::IpAddr,
+    net::{IpAddr, SocketAddr},
     sync::{Arc, Mutex},
 };
 use uluru::LRUCache;
@@ -13,6 +14,14 @@ pub struct SerializableStatistics {
     pub proxied_requests: u64,
     pub blocked_requests: u64,
     pub modified_responses: u64,
+    pub removed_html_elements: u64,
+    pub failed_script_injections: u64,
+    pub failed_html_rewrites: u64,
+    pub new_upstream_connections: u64,
+    pub reused_upstream_connections: u64,
+    pub http2_upstream_responses: u64,
+    pub dns_queries: u64,
+    pub blocked_dns_queries: u64,
     #[serde(with = "tuple_vec_map")]
     pub top_blocked_paths: Vec<(String, u64)>,
     #[serde(with = "tuple_vec_map")]
@@ -24,6 +33,16 @@ pub struct Statistics {
     pub proxied_requests: Arc<Mutex<u64>>,
     pub blocked_requests: Arc<Mutex<u64>>,
     pub modified_responses: Arc<Mutex<u64>>,
+    pub removed_html_elements: Arc<Mutex<u64>>,
+    pub failed_script_injections: Arc<Mutex<u64>>,
+    pub failed_html_rewrites: Arc<Mutex<u64>>,
+    pub new_upstream_connections: Arc<Mutex<u64>>,
+    pub reused_upstream_connections: Arc<Mutex<u64>>,
+    pub http2_upstream_responses: Arc<Mutex<u64>>,
+    pub dns_queries: Arc<Mutex<u64>>,
+    pub blocked_dns_queries: Arc<Mutex<u64>>,
+    // Local addresses of recently used upstream connections, each of them identifies a connection.
+    pub upstream_connections: Arc<Mutex<LRUCache<SocketAddr, 1_000>>>,
     pub top_blocked_paths: Arc<Mutex<LRUCache<(String, u64), 1_000>>>,
     pub top_clients: Arc<Mutex<HashMap<IpAddr, u64>>>,
 }
@@ -40,6 +59,15 @@ impl Statistics {
             proxied_requests: Arc::new(Mutex::new(0)),
             blocked_requests: Arc::new(Mutex::new(0)),
             modified_responses: Arc::new(Mutex::new(0)),
+            removed_html_elements: Arc::new(Mutex::new(0)),
+            failed_script_injections: Arc::new(Mutex::new(0)),
+            failed_html_rewrites: Arc::new(Mutex::new(0)),
+            new_upstream_connections: Arc::new(Mutex::new(0)),
+            reused_upstream_connections: Arc::new(Mutex::new(0)),
+            http2_upstream_responses: Arc::new(Mutex::new(0)),
+            dns_queries: Arc::new(Mutex::new(0)),
+            blocked_dns_queries: Arc::new(Mutex::new(0)),
+            upstream_connections: Arc::new(Mutex::new(LRUCache::default())),
             top_blocked_paths: Arc::new(Mutex::new(LRUCache::default())),
             top_clients: Arc::new(Mutex::new(HashMap::new())),
         }
@@ -83,11 +111,76 @@ impl Statistics {
         *modified_responses
     }
 
+    pub fn add_removed_html_elements(&self, count: u64) -> u64 {
+        let mut removed_html_elements = self.removed_html_elements.lock().unwrap();
+
+        *removed_html_elements += count;
+        *removed_html_elements
+    }
+
+    pub fn increment_failed_script_injections(&self) -> u64 {
+        let mut failed_script_injections = self.failed_script_injections.lock().unwrap();
+
+        *failed_script_injections += 1;
+        *failed_script_injections
+    }
+
+    pub fn increment_failed_html_rewrites(&self) -> u64 {
+        let mut failed_html_rewrites = self.failed_html_rewrites.lock().unwrap();
+
+        *failed_html_rewrites += 1;
+        *failed_html_rewrites
+    }
+
+    pub fn increment_dns_queries(&self) -> u64 {
+        let mut dns_queries = self.dns_queries.lock().unwrap();
+
+        *dns_queries += 1;
+        *dns_queries
+    }
+
+    pub fn increment_blocked_dns_queries(&self) -> u64 {
+        let mut blocked_dns_queries = self.blocked_dns_queries.lock().unwrap();
+
+        *blocked_dns_queries += 1;
+        *blocked_dns_queries
+    }
+
+    /// Records whether the connection a response was received on had already been used before.
+    pub fn record_upstream_response(&self, local_address: Option<SocketAddr>, version: Version) {
+        if version == Version::HTTP_2 {
+            *self.http2_upstream_responses.lock().unwrap() += 1;
+        }
+
+        let local_address = match local_address {
+            Some(local_address) => local_address,
+            None => return,
+        };
+
+        let mut upstream_connections = self.upstream_connections.lock().unwrap();
+
+        if upstream_connections.touch(|address| address == &local_address) {
+            *self.reused_upstream_connections.lock().unwrap() += 1;
+        } else {
+            upstream_connections.insert(local_address);
+
+            *self.new_upstream_connections.lock().unwrap() += 1;
+        }
+    }
+
     pub fn get_serialized(&self) -> SerializableStatistics {
         SerializableStatistics {
             proxied_requests: *self.proxied_requests.lock().unwrap(),
             blocked_requests: *self.blocked_requests.lock().unwrap(),
             modified_responses: *self.modified_responses.lock().unwrap(),
+            removed_html_elements: *self.removed_html_elements.lock().unwrap(),
+            failed_script_injections: *self.failed_script_injections.lock().unwrap(),
+            failed_html_rewrites: *self.failed_html_rewrites.lock().unwrap(),
+            new_upstream_connections: *self.new_upstream_connections.lock().unwrap(),
+            reused_upstream_connections: *self.reused_upstream_connections.lock().unwrap(),
+            http2_upstream_responses: *self.http2_upstream_responses.lock().unwrap(),
+            dns_queries: *self.dns_queries.lock().unwrap(),
+            blocked_dns_queries: *self.blocked_dns_queries.lock().unwrap(),
             top_blocked_paths: {
                 let top_blocked_paths = self.top_blocked_paths.lock().unwrap();
                 let mut top_blocked_paths_iterator = top_blocked_paths.iter();
@@ -0,0 +1,502 @@
+//! Certificate authorities upstream servers are verified against. Every upstream client, be it
+//! reqwest's, the one performing upgrades or the ones connecting to upstream proxies and
+//! resolvers, trusts the same authorities.
+//!
+//! Certificates rejected upstream are remembered so that the page telling the user about it can
+//! show what was actually presented, as clients only ever see certificates signed by Privaxy.
+//!
+//! Certificate exceptions are written as a host followed by the SHA-256 fingerprint of the only
+//! certificate accepted for it, such as `router.lan AB:CD:...`, as shown on that page.
+use crate::configuration::UpstreamTlsConfiguration;
+use openssl::sha::sha256;
+use openssl::x509::{X509NameRef, X509};
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::io;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+use uluru::LRUCache;
+
+const REJECTED_CHAINS_CAPACITY: usize = 64;
+
+type RejectedChains = LRUCache<(String, Vec<CertificateDetails>), REJECTED_CHAINS_CAPACITY>;
+
+/// What is shown to the user about a certificate presented by an upstream server.
+#[derive(Debug, Clone)]
+pub(crate) struct CertificateDetails {
+    pub(crate) subject: String,
+    pub(crate) issuer: String,
+    pub(crate) not_before: String,
+    pub(crate) not_after: String,
+    pub(crate) subject_alternative_names: Vec<String>,
+    pub(crate) sha256_fingerprint: String,
+}
+
+impl CertificateDetails {
+    fn from_der(der: &[u8]) -> Option<Self> {
+        let certificate = X509::from_der(der).ok()?;
+
+        let subject_alternative_names = certificate
+            .subject_alt_names()
+            .map(|names| {
+                names
+                    .iter()
+                    .filter_map(|name| {
+                        name.dnsname()
+                            .map(|dns_name| dns_name.to_string())
+                            .or_else(|| name.ipaddress().and_then(format_ip_address))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Some(Self {
+            subject: format_name(certificate.subject_name()),
+            issuer: format_name(certificate.issuer_name()),
+            not_before: certificate.not_before().to_string(),
+            not_after: certificate.not_after().to_string(),
+            subject_alternative_names,
+            sha256_fingerprint: sha256_fingerprint(der),
+        })
+    }
+}
+
+fn sha256_fingerprint(der: &[u8]) -> String {
+    sha256(der)
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Parses a `host fingerprint` certificate exception, the fingerprint being normalized to
+/// uppercase hexadecimal digits separated by colons.
+pub(crate) fn parse_certificate_exception(exception: &str) -> Option<(String, String)> {
+    let (host, fingerprint) = exception.trim().split_once(char::is_whitespace)?;
+
+    let digits = fingerprint
+        .trim()
+        .replace(':', "")
+        .to_uppercase()
+        .chars()
+        .collect::<Vec<_>>();
+
+    if digits.len() != 64 || !digits.iter().all(|digit| digit.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let fingerprint = digits
+        .chunks(2)
+        .map(|byte| byte.iter().collect::<String>())
+        .collect::<Vec<_>>()
+        .join(":");
+
+    Some((host.to_lowercase(), fingerprint))
+}
+
+fn parse_certificate_exceptions(exceptions: &BTreeSet<String>) -> BTreeSet<(String, String)> {
+    exceptions
+        .iter()
+        .filter_map(|exception| {
+            let certificate_exception = parse_certificate_exception(exception);
+
+            if certificate_exception.is_none() {
+                log::warn!(
+                    "Ignoring certificate exception without a valid SHA-256 fingerprint: {}",
+                    exception
+                );
+            }
+
+            certificate_exception
+        })
+        .collect()
+}
+
+pub(crate) fn format_ip_address(address: &[u8]) -> Option<String> {
+    let address = match address.len() {
+        4 => IpAddr::from(<[u8; 4]>::try_from(address).ok()?),
+        16 => IpAddr::from(<[u8; 16]>::try_from(address).ok()?),
+        _ => return None,
+    };
+
+    Some(address.to_string())
+}
+
+fn format_name(name: &X509NameRef) -> String {
+    name.entries()
+        .map(|entry| {
+            format!(
+                "{}={}",
+                entry.object().nid().short_name().unwrap_or("?"),
+                entry
+                    .data()
+                    .as_utf8()
+                    .map(|data| data.to_string())
+                    .unwrap_or_default()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Verifies upstream certificates against the trusted authorities, letting the certificates the
+/// user made an exception for through.
+struct UpstreamCertificateVerifier {
+    web_pki_verifier: WebPkiVerifier,
+    certificate_exceptions: Arc<RwLock<BTreeSet<(String, String)>>>,
+    rejected_chains: Arc<Mutex<RejectedChains>>,
+}
+
+impl ServerCertVerifier for UpstreamCertificateVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let error = match self.web_pki_verifier.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        ) {
+            Ok(verified) => return Ok(verified),
+            Err(error) => error,
+        };
+
+        let host = match server_name {
+            ServerName::DnsName(dns_name) => dns_name.as_ref().to_lowercase(),
+            ServerName::IpAddress(address) => address.to_string(),
+            _ => return Err(error),
+        };
+
+        // Only the very certificate the exception was made for is accepted, not whatever else
+        // would be presented for the host.
+        let certificate_exception = (host, sha256_fingerprint(&end_entity.0));
+
+        if self
+            .certificate_exceptions
+            .read()
+            .unwrap()
+            .contains(&certificate_exception)
+        {
+            log::debug!(
+                "Accepting certificate of {} despite: {}",
+                certificate_exception.0,
+                error
+            );
+
+            return Ok(ServerCertVerified::assertion());
+        }
+
+        let (host, _fingerprint) = certificate_exception;
+
+        let chain = std::iter::once(end_entity)
+            .chain(intermediates)
+            .filter_map(|certificate| CertificateDetails::from_der(&certificate.0))
+            .collect::<Vec<_>>();
+
+        let mut rejected_chains = self.rejected_chains.lock().unwrap();
+
+        match rejected_chains.find(|(rejected_host, _chain)| *rejected_host == host) {
+            Some((_host, rejected_chain)) => *rejected_chain = chain,
+            None => {
+                rejected_chains.insert((host, chain));
+            }
+        }
+
+        Err(error)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TrustStore {
+    // Mozilla's authorities, as bundled with webpki-roots.
+    bundled_roots: bool,
+    // DER encoded certificates of the native and extra authorities.
+    certificates: Arc<Vec<Vec<u8>>>,
+    // Hosts and fingerprints of certificates accepted even though they can't be verified.
+    certificate_exceptions: Arc<RwLock<BTreeSet<(String, String)>>>,
+    rejected_chains: Arc<Mutex<RejectedChains>>,
+}
+
+impl Default for TrustStore {
+    fn default() -> Self {
+        Self::new(&UpstreamTlsConfiguration::default())
+    }
+}
+
+impl TrustStore {
+    pub fn new(configuration: &UpstreamTlsConfiguration) -> Self {
+        let mut certificates = Vec::new();
+
+        if configuration.native_roots {
+            match rustls_native_certs::load_native_certs() {
+                Ok(native_certificates) => certificates.extend(
+                    native_certificates
+                        .into_iter()
+                        .map(|certificate| certificate.0),
+                ),
+                Err(err) => log::error!("Unable to load native root certificates: {}", err),
+            }
+        }
+
+        for path in &configuration.extra_root_certificates {
+            let pem = match std::fs::read(path) {
+                Ok(pem) => pem,
+                Err(err) => {
+                    log::error!(
+                        "Unable to read root certificates from {}: {}",
+                        path.display(),
+                        err
+                    );
+                    continue;
+                }
+            };
+
+            match X509::stack_from_pem(&pem) {
+                Ok(stack) => certificates.extend(
+                    stack
+                        .into_iter()
+                        .filter_map(|certificate| certificate.to_der().ok()),
+                ),
+                Err(err) => log::error!(
+                    "Unable to decode root certificates from {}: {}",
+                    path.display(),
+                    err
+                ),
+            }
+        }
+
+        let certificate_exceptions =
+            parse_certificate_exceptions(&configuration.certificate_exceptions);
+
+        Self {
+            bundled_roots: configuration.bundled_roots,
+            certificates: Arc::new(certificates),
+            certificate_exceptions: Arc::new(RwLock::new(certificate_exceptions)),
+            rejected_chains: Arc::new(Mutex::new(LRUCache::default())),
+        }
+    }
+
+    pub fn replace_certificate_exceptions(&self, certificate_exceptions: &BTreeSet<String>) {
+        *self.certificate_exceptions.write().unwrap() =
+            parse_certificate_exceptions(certificate_exceptions);
+    }
+
+    /// The certificate chain `host` presented when its certificate was last rejected.
+    pub(crate) fn rejected_chain(&self, host: &str) -> Option<Vec<CertificateDetails>> {
+        let host = host.to_lowercase();
+
+        self.rejected_chains
+            .lock()
+            .unwrap()
+            .find(|(rejected_host, _chain)| *rejected_host == host)
+            .map(|(_host, chain)| chain.clone())
+    }
+
+    fn root_cert_store(&self) -> RootCertStore {
+        let mut root_store = RootCertStore::empty();
+
+        if self.bundled_roots {
+            root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(
+                |trust_anchor| {
+                    OwnedTrustAnchor::from_subject_spki_name_constraints(
+                        trust_anchor.subject,
+                        trust_anchor.spki,
+                        trust_anchor.name_constraints,
+                    )
+                },
+            ));
+        }
+
+        // Some native certificates can't be parsed by webpki, they are ignored.
+        let (_added, _ignored) = root_store.add_parsable_certificates(&self.certificates);
+
+        root_store
+    }
+
+    /// A client configuration without any ALPN protocol.
+    pub fn client_configuration(&self) -> ClientConfig {
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(UpstreamCertificateVerifier {
+                web_pki_verifier: WebPkiVerifier::new(self.root_cert_store(), None),
+                certificate_exceptions: self.certificate_exceptions.clone(),
+                rejected_chains: self.rejected_chains.clone(),
+            }))
+            .with_no_client_auth()
+    }
+
+    /// Makes reqwest's client verify certificates the same way, offering both HTTP/2 and
+    /// HTTP/1.1 as reqwest would.
+    pub fn configure_reqwest(
+        &self,
+        client_builder: reqwest::ClientBuilder,
+    ) -> reqwest::ClientBuilder {
+        let mut tls_configuration = self.client_configuration();
+        tls_configuration.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        client_builder.use_preconfigured_tls(tls_configuration)
+    }
+}
+
+fn find_tls_error<'a>(error: &'a (dyn Error + 'static)) -> Option<&'a rustls::Error> {
+    if let Some(tls_error) = error.downcast_ref::<rustls::Error>() {
+        return Some(tls_error);
+    }
+
+    // IO errors don't expose the error they wrap as their source.
+    if let Some(inner_error) = error
+        .downcast_ref::<io::Error>()
+        .and_then(|error| error.get_ref())
+    {
+        if let Some(tls_error) = find_tls_error(inner_error) {
+            return Some(tls_error);
+        }
+    }
+
+    error.source().and_then(find_tls_error)
+}
+
+/// Returns why an upstream server's certificate was rejected, if that's what `error` was caused by.
+pub(crate) fn certificate_error(error: &(dyn Error + 'static)) -> Option<String> {
+    match find_tls_error(error)? {
+        tls_error @ (rustls::Error::InvalidCertificateEncoding
+        | rustls::Error::InvalidCertificateSignatureType
+        | rustls::Error::InvalidCertificateSignature
+        | rustls::Error::InvalidCertificateData(_)
+        | rustls::Error::UnsupportedNameType
+        | rustls::Error::NoCertificatesPresented) => Some(tls_error.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ca::make_ca_certificate;
+
+    fn verifier(trust_store: &TrustStore) -> UpstreamCertificateVerifier {
+        UpstreamCertificateVerifier {
+            web_pki_verifier: WebPkiVerifier::new(trust_store.root_cert_store(), None),
+            certificate_exceptions: trust_store.certificate_exceptions.clone(),
+            rejected_chains: trust_store.rejected_chains.clone(),
+        }
+    }
+
+    fn verify(certificate_exceptions: &[String], certificate: &Certificate) -> bool {
+        let trust_store = TrustStore::new(&UpstreamTlsConfiguration {
+            native_roots: false,
+            bundled_roots: false,
+            certificate_exceptions: BTreeSet::from_iter(certificate_exceptions.iter().cloned()),
+            ..Default::default()
+        });
+
+        verifier(&trust_store)
+            .verify_server_cert(
+                certificate,
+                &[],
+                &ServerName::try_from("router.lan").unwrap(),
+                &mut std::iter::empty(),
+                &[],
+                SystemTime::now(),
+            )
+            .is_ok()
+    }
+
+    fn self_signed_certificate() -> Certificate {
+        let (certificate, _private_key) = make_ca_certificate();
+
+        Certificate(certificate.to_der().unwrap())
+    }
+
+    #[test]
+    fn test_parse_certificate_exception() {
+        let fingerprint = "AB:".repeat(31) + "AB";
+
+        assert_eq!(
+            parse_certificate_exception(&format!("Router.LAN {}", fingerprint)),
+            Some((String::from("router.lan"), fingerprint.clone()))
+        );
+        assert_eq!(
+            parse_certificate_exception(&format!(" router.lan\t{} ", "ab".repeat(32))),
+            Some((String::from("router.lan"), fingerprint))
+        );
+        assert_eq!(parse_certificate_exception("router.lan"), None);
+        assert_eq!(parse_certificate_exception("router.lan AB:CD"), None);
+        assert_eq!(
+            parse_certificate_exception(&format!("router.lan {}", "XY".repeat(32))),
+            None
+        );
+    }
+
+    #[test]
+    fn test_certificate_exception() {
+        let certificate = self_signed_certificate();
+        let fingerprint = sha256_fingerprint(&certificate.0);
+
+        assert!(!verify(&[], &certificate));
+        assert!(verify(
+            &[format!("router.lan {}", fingerprint)],
+            &certificate
+        ));
+        assert!(verify(
+            &[format!("ROUTER.lan {}", fingerprint.to_lowercase())],
+            &certificate
+        ));
+    }
+
+    #[test]
+    fn test_certificate_exception_of_other_certificate() {
+        let certificate = self_signed_certificate();
+        let other_certificate = self_signed_certificate();
+
+        let certificate_exceptions = [format!(
+            "router.lan {}",
+            sha256_fingerprint(&other_certificate.0)
+        )];
+
+        assert!(!verify(&certificate_exceptions, &certificate));
+        assert!(!verify(&[String::from("router.lan")], &certificate));
+        assert!(!verify(
+            &[format!("other.lan {}", sha256_fingerprint(&certificate.0))],
+            &certificate
+        ));
+    }
+
+    #[test]
+    fn test_rejected_chain() {
+        let trust_store = TrustStore::new(&UpstreamTlsConfiguration {
+            native_roots: false,
+            bundled_roots: false,
+            ..Default::default()
+        });
+        let certificate = self_signed_certificate();
+
+        let _result = verifier(&trust_store).verify_server_cert(
+            &certificate,
+            &[],
+            &ServerName::try_from("Router.lan").unwrap(),
+            &mut std::iter::empty(),
+            &[],
+            SystemTime::now(),
+        );
+
+        let chain = trust_store.rejected_chain("router.LAN").unwrap();
+
+        assert_eq!(chain.len(), 1);
+        assert_eq!(
+            chain[0].sha256_fingerprint,
+            sha256_fingerprint(&certificate.0)
+        );
+    }
+}
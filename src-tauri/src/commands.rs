@@ -104,6 +104,55 @@ pub(crate) async fn set_exclusions(
     Ok(())
 }
 
+#[tauri::command]
+pub(crate) async fn get_certificate_exceptions(
+    http_client: tauri::State<'_, reqwest::Client>,
+) -> Result<String, ()> {
+    let configuration = match Configuration::read_from_home(http_client.inner().clone()).await {
+        Ok(configuration) => configuration,
+        Err(_) => return Err(()),
+    };
+    let certificate_exceptions = Vec::from_iter(
+        configuration
+            .upstream_tls
+            .certificate_exceptions
+            .into_iter(),
+    )
+    .join("\n");
+
+    Ok(certificate_exceptions)
+}
+
+#[tauri::command]
+pub(crate) async fn set_certificate_exceptions(
+    input: String,
+    privaxy_server: tauri::State<'_, PrivaxyServer>,
+    http_client: tauri::State<'_, reqwest::Client>,
+) -> Result<(), ()> {
+    let _guard = privaxy_server.configuration_save_lock.lock().await;
+
+    let mut configuration = match Configuration::read_from_home(http_client.inner().clone()).await {
+        Ok(configuration) => configuration,
+        Err(_) => return Err(()),
+    };
+
+    if configuration
+        .set_certificate_exceptions(&input, privaxy_server.trust_store.clone())
+        .await
+        .is_err()
+    {
+        return Err(());
+    }
+
+    privaxy_server
+        .configuration_updater_sender
+        .send(configuration.clone())
+        .await
+        .unwrap();
+
+    Ok(())
+}
+
 #[tauri::command]
 pub(crate) async fn get_filters_configuration(
     http_client: tauri::State<'_, reqwest::Client>,
@@ -147,3 +196,71 @@ pub(crate) async fn change_filter_status(
 
     Ok(configuration.filters)
 }
+
+#[tauri::command]
+pub(crate) fn get_auto_exclusions(privaxy_server: tauri::State<'_, PrivaxyServer>) -> Vec<String> {
+    privaxy_server.local_exclusion_store.auto_exclusions()
+}
+
+#[tauri::command]
+pub(crate) async fn remove_auto_exclusion(
+    host: String,
+    privaxy_server: tauri::State<'_, PrivaxyServer>,
+) -> Result<Vec<String>, ()> {
+    if privaxy_server
+        .local_exclusion_store
+        .remove_auto_exclusion(&host)
+        .await
+        .is_err()
+    {
+        return Err(());
+    }
+
+    Ok(privaxy_server.local_exclusion_store.auto_exclusions())
+}
+
+#[tauri::command]
+pub(crate) async fn clear_auto_exclusions(
+    privaxy_server: tauri::State<'_, PrivaxyServer>,
+) -> Result<Vec<String>, ()> {
+    if privaxy_server
+        .local_exclusion_store
+        .clear_auto_exclusions()
+        .await
+        .is_err()
+    {
+        return Err(());
+    }
+
+    Ok(privaxy_server.local_exclusion_store.auto_exclusions())
+}
+
+#[tauri::command]
+pub(crate) async fn promote_auto_exclusion(
+    host: String,
+    privaxy_server: tauri::State<'_, PrivaxyServer>,
+    http_client: tauri::State<'_, reqwest::Client>,
+) -> Result<Vec<String>, ()> {
+    let _guard = privaxy_server.configuration_save_lock.lock().await;
+
+    let mut configuration = match Configuration::read_from_home(http_client.inner().clone()).await {
+        Ok(configuration) => configuration,
+        Err(_) => return Err(()),
+    };
+
+    if configuration
+        .promote_auto_exclusion(&host, privaxy_server.local_exclusion_store.clone())
+        .await
+        .is_err()
+    {
+        return Err(());
+    }
+
+    privaxy_server
+        .configuration_updater_sender
+        .send(configuration.clone())
+        .await
+        .unwrap();
+
+    Ok(privaxy_server.local_exclusion_store.auto_exclusions())
+}
@@ -82,8 +82,14 @@ fn main() {
             commands::set_custom_filters,
             commands::get_exclusions,
             commands::set_exclusions,
+            commands::get_certificate_exceptions,
+            commands::set_certificate_exceptions,
             commands::get_filters_configuration,
-            commands::change_filter_status
+            commands::change_filter_status,
+            commands::get_auto_exclusions,
+            commands::remove_auto_exclusion,
+            commands::clear_auto_exclusions,
+            commands::promote_auto_exclusion
         ])
         .setup(move |app| {
             let main_window = app.get_window("main").unwrap();
@@ -0,0 +1,156 @@
+use serde::Serialize;
+use tauri_sys::tauri;
+use wasm_bindgen_futures::spawn_local;
+use yew::{html, Callback, Component, Context, Html, Properties};
+
+#[derive(Serialize)]
+struct AutoExclusionPayload {
+    host: String,
+}
+
+#[derive(Properties, PartialEq)]
+pub struct Props {
+    /// Called once hosts have been moved to the exclusions.
+    #[prop_or_default]
+    pub on_promoted: Callback<()>,
+}
+
+pub enum Message {
+    Load,
+    Display(Vec<String>),
+    Promote(String),
+    Remove(String),
+    Clear,
+}
+
+pub struct AutoExclusions {
+    hosts: Vec<String>,
+}
+
+impl Component for AutoExclusions {
+    type Message = Message;
+    type Properties = Props;
+
+    fn create(ctx: &Context<Self>) -> Self {
+        ctx.link().send_message(Message::Load);
+
+        Self { hosts: Vec::new() }
+    }
+
+    fn update(&mut self, ctx: &Context<Self>, msg: Self::Message) -> bool {
+        let message_callback = ctx.link().callback(|message: Message| message);
+
+        match msg {
+            Message::Load => {
+                spawn_local(async move {
+                    let hosts = tauri::invoke::<_, Vec<String>>("get_auto_exclusions", &())
+                        .await
+                        .unwrap();
+
+                    message_callback.emit(Message::Display(hosts))
+                });
+
+                false
+            }
+            Message::Display(hosts) => {
+                self.hosts = hosts;
+
+                true
+            }
+            Message::Promote(host) => {
+                let on_promoted = ctx.props().on_promoted.clone();
+
+                spawn_local(async move {
+                    let hosts = tauri::invoke::<_, Vec<String>>(
+                        "promote_auto_exclusion",
+                        &AutoExclusionPayload { host },
+                    )
+                    .await
+                    .unwrap();
+
+                    message_callback.emit(Message::Display(hosts));
+                    on_promoted.emit(())
+                });
+
+                false
+            }
+            Message::Remove(host) => {
+                spawn_local(async move {
+                    let hosts = tauri::invoke::<_, Vec<String>>(
+                        "remove_auto_exclusion",
+                        &AutoExclusionPayload { host },
+                    )
+                    .await
+                    .unwrap();
+
+                    message_callback.emit(Message::Display(hosts))
+                });
+
+                false
+            }
+            Message::Clear => {
+                spawn_local(async move {
+                    let hosts = tauri::invoke::<_, Vec<String>>("clear_auto_exclusions", &())
+                        .await
+                        .unwrap();
+
+                    message_callback.emit(Message::Display(hosts))
+                });
+
+                false
+            }
+        }
+    }
+
+    fn view(&self, ctx: &Context<Self>) -> Html {
+        let button_classes = "inline-flex items-center px-2.5 py-1.5 border border-gray-300 shadow-sm text-xs font-medium rounded text-gray-700 bg-white hover:bg-gray-50 focus:outline-none focus:ring-2 focus:ring-offset-2 focus:ring-blue-500";
+
+        let render_host = |host: &String| {
+            let promote_host = host.clone();
+            let remove_host = host.clone();
+
+            let promote_callback = ctx
+                .link()
+                .callback(move |_| Message::Promote(promote_host.clone()));
+            let remove_callback = ctx
+                .link()
+                .callback(move |_| Message::Remove(remove_host.clone()));
+
+            html! {
+            <li class="flex items-center justify-between py-3">
+                <span class="text-sm font-mono text-gray-900">{host}</span>
+                <div class="ml-3 flex space-x-2">
+                    <button onclick={promote_callback} type="button" class={button_classes}>{"Keep excluded"}</button>
+                    <button onclick={remove_callback} type="button" class={button_classes}>{"Remove"}</button>
+                </div>
+            </li>
+            }
+        };
+
+        let content = if self.hosts.is_empty() {
+            html! {<p class="mt-4 text-sm text-gray-500">{"No host has been excluded automatically."}</p>}
+        } else {
+            let clear_callback = ctx.link().callback(|_| Message::Clear);
+
+            html! {
+            <>
+                <ul class="mt-4 border-t border-b border-gray-200 divide-y divide-gray-200">
+                    { for self.hosts.iter().map(render_host) }
+                </ul>
+                <button onclick={clear_callback} type="button" class={format!("mt-4 {}", button_classes)}>{"Clear all"}</button>
+            </>
+            }
+        };
+
+        html! {
+        <div class="mt-10">
+            <h2 class="text-lg font-medium text-gray-900">{"Automatic exclusions"}</h2>
+            <p class="text-gray-600">
+                {"Hosts whose clients repeatedly refused intercepted TLS connections, such as certificate pinning applications, are tunneled automatically. "}
+                {"Keep them excluded to move them to your exclusions, or remove them to intercept them again."}
+            </p>
+            {content}
+        </div>
+        }
+    }
+}
@@ -2,6 +2,7 @@ use yew::functional::*;
 use yew::prelude::*;
 use yew_router::prelude::*;
 
+mod auto_exclusions;
 mod blocking_enabled;
 mod dashboard;
 mod filters;
@@ -1,3 +1,4 @@
+use crate::auto_exclusions::AutoExclusions;
 use crate::filters::Filters;
 use crate::set_title;
 use crate::settings_textarea::SettingsTextarea;
@@ -15,6 +16,54 @@ pub enum SettingsRoute {
     CustomFilters,
 }
 
+#[function_component(ExclusionsSettings)]
+fn exclusions_settings() -> Html {
+    // Kept automatic exclusions are moved to the exclusions, which must then be loaded again.
+    let reload = use_state(|| 0u32);
+    let on_promoted = {
+        let reload = reload.clone();
+        Callback::from(move |_| reload.set(*reload + 1))
+    };
+
+    let description = html! {<div class="text-gray-600">
+            <p>
+                {"Exclusions are hosts or domains that are not passed through the MITM pipeline. "}
+                {"Excluded entries will be transparently tunneled."}
+            </p>
+            <br/>
+            <p><span class="bg-gray-100 rounded">{"?"}</span>{" matches exactly one occurrence of any character."}</p>
+            <p><span class="bg-gray-100 rounded">{"*"}</span>{" matches arbitrary many (including zero) occurrences of any character."}</p>
+        </div>
+    };
+    let textarea_description = "Insert one entry per line";
+    let set_resource_name = "set_exclusions";
+    let get_resource_name = "get_exclusions";
+
+    let certificate_exceptions_description = html! {<div class="text-gray-600">
+            <p>
+                {"Certificates accepted even when they can't be verified, such as self-signed certificates of devices. "}
+                {"Privaxy otherwise refuses to serve their hosts and shows the certificates they presented, along with the line to add here."}
+            </p>
+            <br/>
+            <p>
+                {"Each line holds a host followed by the SHA-256 fingerprint of its certificate, such as "}
+                <span class="font-mono bg-gray-100 rounded">{"router.lan 3A:1F:...:C4"}</span>
+                {". Any other certificate presented by the host is still rejected."}
+            </p>
+        </div>
+    };
+
+    html! {
+        <>
+            <SettingsTextarea h1="Exclusions" {description} input_name="exclusions" {textarea_description} {set_resource_name} {get_resource_name} reload={*reload} />
+            <AutoExclusions {on_promoted} />
+            <div class="mt-10">
+                <SettingsTextarea h1="Certificate exceptions" description={certificate_exceptions_description} input_name="certificate_exceptions" textarea_description="Insert one host and certificate fingerprint per line" set_resource_name="set_certificate_exceptions" get_resource_name="get_certificate_exceptions" />
+            </div>
+        </>
+    }
+}
+
 pub fn switch_settings(route: &SettingsRoute) -> Html {
     fn get_classes(current_route: SettingsRoute, for_route_link: SettingsRoute) -> Classes {
         if current_route == for_route_link {
@@ -54,21 +103,7 @@ pub fn switch_settings(route: &SettingsRoute) -> Html {
         SettingsRoute::Exclusions => {
             set_title("Settings - Exclusions");
 
-            let description = html! {<div class="text-gray-600">
-                    <p>
-                        {"Exclusions are hosts or domains that are not passed through the MITM pipeline. "}
-                        {"Excluded entries will be transparently tunneled."}
-                    </p>
-                    <br/>
-                    <p><span class="bg-gray-100 rounded">{"?"}</span>{" matches exactly one occurrence of any character."}</p>
-                    <p><span class="bg-gray-100 rounded">{"*"}</span>{" matches arbitrary many (including zero) occurrences of any character."}</p>
-                </div>
-            };
-            let textarea_description = "Insert one entry per line";
-            let set_resource_name = "set_exclusions";
-            let get_resource_name = "get_exclusions";
-
-            html! {<SettingsTextarea h1="Exclusions" {description} input_name="exclusions" {textarea_description} {set_resource_name} {get_resource_name} />}
+            html! { <ExclusionsSettings /> }
         }
         SettingsRoute::CustomFilters => {
             set_title("Settings - Custom Filters");
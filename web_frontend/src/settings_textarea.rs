@@ -21,6 +21,9 @@ pub struct Props {
     pub textarea_description: String,
     pub set_resource_name: String,
     pub get_resource_name: String,
+    /// Changing it loads the current state again, when it was modified elsewhere.
+    #[prop_or_default]
+    pub reload: u32,
 }
 
 pub struct SettingsTextarea {